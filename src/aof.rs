@@ -0,0 +1,323 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::AppendFsync,
+    db::Snapshot,
+    encoding::to_list_of_bulk_strings,
+    parser::{RDBParser, Rdb},
+    persistence::dump_rdb,
+    response::{Frame, RespParser, Value},
+};
+
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The append-only file: every write command is logged in RESP form so the
+/// keyspace can be rebuilt by replaying it on startup.
+pub struct Aof {
+    path: PathBuf,
+    fsync: AppendFsync,
+    /// `None` while `appendonly` is off.
+    file: Option<File>,
+    last_fsync: Instant,
+    /// Database the logged commands currently apply to; a `SELECT` is logged
+    /// before any command for another one.
+    selected_db: Option<usize>,
+    rewrite_in_progress: bool,
+    /// Commands logged while a rewrite runs, appended to the rewritten file
+    /// before it replaces the current one.
+    rewrite_buffer: Vec<u8>,
+}
+
+impl Aof {
+    pub fn disabled(path: PathBuf, fsync: AppendFsync) -> Self {
+        Self {
+            path,
+            fsync,
+            file: None,
+            last_fsync: Instant::now(),
+            selected_db: None,
+            rewrite_in_progress: false,
+            rewrite_buffer: Vec::new(),
+        }
+    }
+
+    pub fn open(path: PathBuf, fsync: AppendFsync) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            file: Some(file),
+            ..Self::disabled(path, fsync)
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite_in_progress
+    }
+
+    /// Logs one write command executed against database `db`. With
+    /// `appendfsync always` the data is on disk before this returns.
+    pub fn append(&mut self, db: usize, args: &[Bytes]) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let mut command = Vec::new();
+        if self.selected_db != Some(db) {
+            command.extend_from_slice(&to_list_of_bulk_strings(&[
+                Bytes::from_static(b"SELECT"),
+                Bytes::from(db.to_string()),
+            ]));
+            self.selected_db = Some(db);
+        }
+        command.extend_from_slice(&to_list_of_bulk_strings(args));
+        file.write_all(&command)?;
+        if self.rewrite_in_progress {
+            self.rewrite_buffer.extend_from_slice(&command);
+        }
+        if self.fsync == AppendFsync::Always {
+            file.sync_data()?;
+            self.last_fsync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Returns a handle to `fsync` outside of the lock when `appendfsync
+    /// everysec` is due.
+    pub fn fsync_due(&mut self) -> Option<File> {
+        if self.fsync != AppendFsync::EverySec || self.last_fsync.elapsed() < FSYNC_INTERVAL {
+            return None;
+        }
+        self.last_fsync = Instant::now();
+        self.file.as_ref().and_then(|file| file.try_clone().ok())
+    }
+
+    pub fn fsync(&mut self) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+            self.last_fsync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Marks a rewrite as running and returns the temporary file it should
+    /// write the compacted log to.
+    pub fn start_rewrite(&mut self) -> PathBuf {
+        self.rewrite_in_progress = true;
+        self.rewrite_buffer.clear();
+        // The rewritten file starts without a selected database.
+        self.selected_db = None;
+        self.path
+            .with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()))
+    }
+
+    /// Completes a rewrite: the commands logged meanwhile are appended to the
+    /// rewritten file, which then atomically replaces the current log.
+    pub fn finish_rewrite(&mut self, temp_path: &Path, rewritten: Result<()>) -> Result<()> {
+        self.rewrite_in_progress = false;
+        let buffer = std::mem::take(&mut self.rewrite_buffer);
+        if let Err(e) = rewritten {
+            let _ = fs::remove_file(temp_path);
+            return Err(e);
+        }
+
+        let mut file = OpenOptions::new().append(true).open(temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_data()?;
+        fs::rename(temp_path, &self.path)?;
+        if self.is_enabled() {
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+}
+
+/// The contents of an append-only file.
+pub struct AofFile {
+    /// The keyspace at the time of the last rewrite, stored in the RDB format.
+    pub preamble: Option<Rdb>,
+    /// Commands logged after the preamble.
+    pub commands: Vec<Value>,
+}
+
+/// Writes `snapshot` as an RDB preamble, like Redis does with
+/// `aof-use-rdb-preamble yes`, so values of every type survive a rewrite.
+pub fn rewrite_aof(snapshot: &Snapshot, path: &Path) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&dump_rdb(snapshot))?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Reads the RDB preamble, if any, and every command logged in the
+/// append-only file. A truncated final command, e.g. from a crash mid-write,
+/// is dropped with a warning like Redis does with `aof-load-truncated yes`.
+pub fn read_aof_file(path: &Path, verify_checksum: bool) -> Result<AofFile> {
+    let buffer = fs::read(path)?;
+    let mut preamble = None;
+    let mut pos = 0;
+    if buffer.starts_with(b"REDIS") {
+        let mut parser = RDBParser::new(&buffer).verify_checksum(verify_checksum);
+        preamble = Some(parser.parse()?);
+        pos = parser.position();
+    }
+
+    let mut commands = Vec::new();
+    let mut parser = RespParser::new();
+    let mut rest = &buffer[pos..];
+    while !rest.is_empty() {
+        let unparsed = rest.len();
+        match parser.parse(&mut rest) {
+            Frame::Complete(value) => commands.push(value),
+            Frame::Invalid(reason) => {
+                bail!(
+                    "Bad file format reading the append only file {}: {}",
+                    path.display(),
+                    reason
+                );
+            }
+            Frame::Incomplete => {
+                eprintln!(
+                    "!!! Warning: short read while loading the AOF file {}; ignoring the last {} bytes",
+                    path.display(),
+                    unparsed
+                );
+                break;
+            }
+        }
+    }
+    Ok(AofFile { preamble, commands })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{Database, Databases, GetValue, RedisDatabase},
+        persistence::insert_rdb,
+    };
+
+    /// A fresh directory for one test's files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("redis-starter-aof-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn args(command: &[&str]) -> Vec<Bytes> {
+        command
+            .iter()
+            .map(|arg| Bytes::from(arg.to_string()))
+            .collect()
+    }
+
+    /// The arguments of a logged command, as strings.
+    fn logged(value: &Value) -> Vec<String> {
+        let Value::Array(args) = value else {
+            panic!("expected an array, got {:?}", value);
+        };
+        args.iter()
+            .map(|arg| match arg {
+                Value::String(arg) => String::from_utf8_lossy(arg).into_owned(),
+                Value::Array(_) => panic!("nested array in {:?}", value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_is_logged_when_the_database_changes() {
+        let dir = temp_dir("select");
+        let path = dir.join("appendonly.aof");
+        let mut aof = Aof::open(path.clone(), AppendFsync::No).unwrap();
+        aof.append(0, &args(&["SET", "a", "1"])).unwrap();
+        aof.append(0, &args(&["SET", "b", "2"])).unwrap();
+        aof.append(2, &args(&["DEL", "a"])).unwrap();
+        aof.append(0, &args(&["DEL", "b"])).unwrap();
+        let file = read_aof_file(&path, true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(file.preamble.is_none());
+        let commands = file.commands.iter().map(logged).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            [
+                vec!["SELECT", "0"],
+                vec!["SET", "a", "1"],
+                vec!["SET", "b", "2"],
+                vec!["SELECT", "2"],
+                vec!["DEL", "a"],
+                vec!["SELECT", "0"],
+                vec!["DEL", "b"],
+            ]
+        );
+    }
+
+    #[test]
+    fn a_truncated_last_command_is_dropped() {
+        let dir = temp_dir("truncated");
+        let path = dir.join("appendonly.aof");
+        let mut aof = Aof::open(path.clone(), AppendFsync::No).unwrap();
+        aof.append(0, &args(&["SET", "a", "1"])).unwrap();
+        aof.append(0, &args(&["SET", "b", "2"])).unwrap();
+        drop(aof);
+        let length = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(length - 4).unwrap();
+        drop(file);
+        let file = read_aof_file(&path, true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let commands = file.commands.iter().map(logged).collect::<Vec<_>>();
+        assert_eq!(commands, [vec!["SELECT", "0"], vec!["SET", "a", "1"]]);
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        let dir = temp_dir("malformed");
+        let path = dir.join("appendonly.aof");
+        fs::write(&path, b"*1\r\n$4\r\nPING\r\n*1\r\n:1\r\n").unwrap();
+        let result = read_aof_file(&path, true);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn a_rewrite_starts_with_the_keyspace_followed_by_later_commands() {
+        let dir = temp_dir("rewrite");
+        let path = dir.join("appendonly.aof");
+        let mut dbs = Databases::new(4, RedisDatabase::new);
+        dbs[0].set(Bytes::from("a"), Bytes::from("1"), None);
+        dbs[3].set(Bytes::from("b"), Bytes::from("2"), Some(u64::MAX));
+
+        let mut aof = Aof::open(path.clone(), AppendFsync::No).unwrap();
+        aof.append(0, &args(&["SET", "a", "1"])).unwrap();
+        let temp_path = aof.start_rewrite();
+        // Logged while the rewrite runs: kept for the rewritten file.
+        aof.append(3, &args(&["SET", "c", "3"])).unwrap();
+        let rewritten = rewrite_aof(&dbs.snapshot(), &temp_path);
+        aof.finish_rewrite(&temp_path, rewritten).unwrap();
+        aof.append(3, &args(&["DEL", "b"])).unwrap();
+        let file = read_aof_file(&path, true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut loaded = Databases::new(4, RedisDatabase::new);
+        insert_rdb(&mut loaded, file.preamble.expect("an RDB preamble")).unwrap();
+        assert!(matches!(loaded[0].get(b"a"), GetValue::Ok(value) if value == "1"));
+        assert!(matches!(loaded[3].get(b"b"), GetValue::Ok(value) if value == "2"));
+        let commands = file.commands.iter().map(logged).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            [vec!["SELECT", "3"], vec!["SET", "c", "3"], vec!["DEL", "b"]]
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Bit-level operations on string values behind `SETBIT`, `GETBIT`,
+//! `BITCOUNT`, `BITPOS`, `BITOP` and `BITFIELD`. Bits are numbered from the
+//! most significant bit of the first byte, like in Redis.
+
+use std::fmt::Display;
+
+use bytes::Bytes;
+
+use crate::strings::MAX_STRING_LEN;
+
+/// Number of addressable bits in a string of the maximum size.
+pub const MAX_BIT_OFFSET: u64 = MAX_STRING_LEN as u64 * 8;
+
+/// Whether the offsets of `BITCOUNT` and `BITPOS` count bytes or bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeUnit {
+    Byte,
+    Bit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// A `BITFIELD` type such as `i16` or `u8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitfieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl BitfieldType {
+    /// Parses `i1` to `i64` or `u1` to `u63`; Redis has no `u64` since
+    /// replies are signed 64-bit integers.
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        let (&sign, bits) = name.split_first()?;
+        let signed = match sign {
+            b'i' => true,
+            b'u' => false,
+            _ => return None,
+        };
+        let bits = std::str::from_utf8(bits).ok()?.parse::<u32>().ok()?;
+        let max_bits = if signed { 64 } else { 63 };
+        (1..=max_bits)
+            .contains(&bits)
+            .then_some(BitfieldType { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+}
+
+impl Display for BitfieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", if self.signed { 'i' } else { 'u' }, self.bits)
+    }
+}
+
+/// What `BITFIELD` does when a `SET` or `INCRBY` does not fit its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Overflow::Wrap => write!(f, "WRAP"),
+            Overflow::Sat => write!(f, "SAT"),
+            Overflow::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+/// The bit at `offset`; bits past the end of the value are 0.
+pub fn get_bit(value: &[u8], offset: u64) -> bool {
+    let byte = (offset / 8) as usize;
+    value
+        .get(byte)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Sets the bit at `offset`, growing `value` with zero bytes if needed.
+/// Returns the previous bit.
+pub fn set_bit(value: &mut Vec<u8>, offset: u64, bit: bool) -> bool {
+    let byte = (offset / 8) as usize;
+    if value.len() <= byte {
+        value.resize(byte + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    let previous = value[byte] & mask != 0;
+    if bit {
+        value[byte] |= mask;
+    } else {
+        value[byte] &= !mask;
+    }
+    previous
+}
+
+/// A `BITCOUNT`/`BITPOS` range resolved to bytes, with masks selecting the
+/// bits of the first and last byte that are inside it.
+struct ByteRange {
+    start: usize,
+    end: usize,
+    first_mask: u8,
+    last_mask: u8,
+}
+
+/// Resolves `start` and `end`, both inclusive and counting from the end
+/// when negative, against a value of `len` bytes. Returns `None` when the
+/// range is empty.
+fn resolve_range(len: usize, start: i64, end: i64, unit: RangeUnit) -> Option<ByteRange> {
+    let total = match unit {
+        RangeUnit::Byte => len as i64,
+        RangeUnit::Bit => len as i64 * 8,
+    };
+    let start = if start < 0 { total + start } else { start }.max(0);
+    let end = if end < 0 { total + end } else { end }
+        .max(0)
+        .min(total - 1);
+    if start > end {
+        return None;
+    }
+    Some(match unit {
+        RangeUnit::Byte => ByteRange {
+            start: start as usize,
+            end: end as usize,
+            first_mask: 0xFF,
+            last_mask: 0xFF,
+        },
+        RangeUnit::Bit => ByteRange {
+            start: (start / 8) as usize,
+            end: (end / 8) as usize,
+            first_mask: 0xFF >> (start % 8),
+            last_mask: 0xFF << (7 - end % 8),
+        },
+    })
+}
+
+/// Number of set bits in the whole value or in the given range, like
+/// `BITCOUNT`.
+pub fn count_bits(value: &[u8], range: Option<(i64, i64, RangeUnit)>) -> u64 {
+    let Some((start, end, unit)) = range else {
+        return value.iter().map(|byte| byte.count_ones() as u64).sum();
+    };
+    let Some(range) = resolve_range(value.len(), start, end, unit) else {
+        return 0;
+    };
+    (range.start..=range.end)
+        .map(|index| {
+            let mut byte = value[index];
+            if index == range.start {
+                byte &= range.first_mask;
+            }
+            if index == range.end {
+                byte &= range.last_mask;
+            }
+            byte.count_ones() as u64
+        })
+        .sum()
+}
+
+/// Position of the first bit equal to `bit`, like `BITPOS`. Without an
+/// explicit `end`, the value counts as padded with zeros to the right, so a
+/// clear bit is always found.
+pub fn bit_position(value: &[u8], bit: bool, start: i64, end: Option<i64>, unit: RangeUnit) -> i64 {
+    let Some(range) = resolve_range(value.len(), start, end.unwrap_or(-1), unit) else {
+        return -1;
+    };
+    for (index, &byte) in value
+        .iter()
+        .enumerate()
+        .take(range.end + 1)
+        .skip(range.start)
+    {
+        // Bits outside the range are made to never match.
+        let mut byte = if bit { byte } else { !byte };
+        if index == range.start {
+            byte &= range.first_mask;
+        }
+        if index == range.end {
+            byte &= range.last_mask;
+        }
+        if byte != 0 {
+            return index as i64 * 8 + byte.leading_zeros() as i64;
+        }
+    }
+    if !bit && end.is_none() {
+        (range.end as i64 + 1) * 8
+    } else {
+        -1
+    }
+}
+
+/// Combines `sources` bytewise like `BITOP`; shorter sources count as
+/// padded with zero bytes.
+pub fn bit_op(operation: BitOperation, sources: &[Bytes]) -> Vec<u8> {
+    let len = sources.iter().map(Bytes::len).max().unwrap_or(0);
+    (0..len)
+        .map(|index| {
+            let mut bytes = sources
+                .iter()
+                .map(|source| source.get(index).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match operation {
+                BitOperation::And => bytes.fold(first, |result, byte| result & byte),
+                BitOperation::Or => bytes.fold(first, |result, byte| result | byte),
+                BitOperation::Xor => bytes.fold(first, |result, byte| result ^ byte),
+                BitOperation::Not => !first,
+            }
+        })
+        .collect()
+}
+
+/// Reads a `BITFIELD` integer of type `field` at bit `offset`.
+pub fn read_field(value: &[u8], field: BitfieldType, offset: u64) -> i64 {
+    let mut result = 0u64;
+    for bit in 0..field.bits as u64 {
+        result = (result << 1) | get_bit(value, offset + bit) as u64;
+    }
+    if field.signed && field.bits < 64 && result & (1 << (field.bits - 1)) != 0 {
+        // Sign extend.
+        result |= u64::MAX << field.bits;
+    }
+    result as i64
+}
+
+/// Writes the low bits of `integer` as a field of type `field` at bit
+/// `offset`, growing `value` if needed.
+pub fn write_field(value: &mut Vec<u8>, field: BitfieldType, offset: u64, integer: i64) {
+    for bit in 0..field.bits as u64 {
+        let set = (integer as u64 >> (field.bits as u64 - 1 - bit)) & 1 != 0;
+        set_bit(value, offset + bit, set);
+    }
+}
+
+/// Fits `integer` into the range of `field` following `overflow`, or
+/// returns `None` if it does not fit and `overflow` is `FAIL`.
+pub fn fit_field(field: BitfieldType, integer: i128, overflow: Overflow) -> Option<i64> {
+    let (min, max) = (field.min(), field.max());
+    if (min..=max).contains(&integer) {
+        return Some(integer as i64);
+    }
+    match overflow {
+        Overflow::Wrap => Some(((integer - min).rem_euclid(1 << field.bits) + min) as i64),
+        Overflow::Sat => Some(if integer > max { max } else { min } as i64),
+        Overflow::Fail => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str) -> BitfieldType {
+        BitfieldType::parse(name.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn set_bit_grows_the_value_and_returns_the_previous_bit() {
+        let mut value = Vec::new();
+        assert!(!set_bit(&mut value, 7, true));
+        assert_eq!(value, [0x01]);
+        assert!(set_bit(&mut value, 7, true));
+        assert!(!set_bit(&mut value, 17, true));
+        assert_eq!(value, [0x01, 0x00, 0x40]);
+        assert!(set_bit(&mut value, 17, false));
+        assert_eq!(value, [0x01, 0x00, 0x00]);
+        assert!(get_bit(&value, 7));
+        assert!(!get_bit(&value, 1000));
+    }
+
+    #[test]
+    fn count_bits_in_ranges() {
+        assert_eq!(count_bits(b"foobar", None), 26);
+        assert_eq!(count_bits(b"foobar", Some((0, 0, RangeUnit::Byte))), 4);
+        assert_eq!(count_bits(b"foobar", Some((1, 1, RangeUnit::Byte))), 6);
+        assert_eq!(count_bits(b"foobar", Some((-2, -1, RangeUnit::Byte))), 7);
+        assert_eq!(count_bits(b"foobar", Some((5, 30, RangeUnit::Bit))), 17);
+        assert_eq!(count_bits(b"foobar", Some((3, 1, RangeUnit::Byte))), 0);
+        assert_eq!(count_bits(b"", Some((0, -1, RangeUnit::Byte))), 0);
+    }
+
+    #[test]
+    fn bit_position_in_ranges() {
+        assert_eq!(
+            bit_position(b"\xff\xf0\x00", false, 0, None, RangeUnit::Byte),
+            12
+        );
+        assert_eq!(
+            bit_position(b"\x00\xff\xf0", true, 0, None, RangeUnit::Byte),
+            8
+        );
+        assert_eq!(
+            bit_position(b"\x00\xff\xf0", true, 2, None, RangeUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_position(b"\x00\xff\xf0", true, 7, Some(15), RangeUnit::Bit),
+            8
+        );
+        assert_eq!(
+            bit_position(b"\x00\x00\x00", true, 0, None, RangeUnit::Byte),
+            -1
+        );
+        // Without an end the value counts as padded with clear bits.
+        assert_eq!(
+            bit_position(b"\xff\xff", false, 0, None, RangeUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_position(b"\xff\xff", false, 0, Some(-1), RangeUnit::Byte),
+            -1
+        );
+        assert_eq!(bit_position(b"", true, 0, None, RangeUnit::Byte), -1);
+    }
+
+    #[test]
+    fn bit_op_pads_shorter_sources() {
+        let sources = [Bytes::from("foobar"), Bytes::from("abcdef")];
+        assert_eq!(bit_op(BitOperation::And, &sources), b"`bc`ab");
+        let sources = [Bytes::from_static(b"\x01"), Bytes::from_static(b"\x00\x02")];
+        assert_eq!(bit_op(BitOperation::Or, &sources), [0x01, 0x02]);
+        assert_eq!(bit_op(BitOperation::Xor, &sources), [0x01, 0x02]);
+        assert_eq!(bit_op(BitOperation::And, &sources), [0x00, 0x00]);
+        assert_eq!(bit_op(BitOperation::Not, &sources[..1]), [0xfe]);
+        assert!(bit_op(BitOperation::Or, &[]).is_empty());
+    }
+
+    #[test]
+    fn bitfield_types() {
+        assert_eq!(
+            field("i64"),
+            BitfieldType {
+                signed: true,
+                bits: 64
+            }
+        );
+        assert_eq!(
+            field("u63"),
+            BitfieldType {
+                signed: false,
+                bits: 63
+            }
+        );
+        for invalid in ["u64", "i65", "i0", "x8", "i", "u-1"] {
+            assert!(
+                BitfieldType::parse(invalid.as_bytes()).is_none(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn fields_round_trip_at_unaligned_offsets() {
+        let mut value = Vec::new();
+        write_field(&mut value, field("i8"), 100, -1);
+        assert_eq!(value.len(), 14);
+        assert_eq!(read_field(&value, field("i8"), 100), -1);
+        assert_eq!(read_field(&value, field("u8"), 100), 255);
+        write_field(&mut value, field("i64"), 3, i64::MIN);
+        assert_eq!(read_field(&value, field("i64"), 3), i64::MIN);
+        assert_eq!(read_field(&[], field("u16"), 5000), 0);
+    }
+
+    #[test]
+    fn overflow_modes() {
+        assert_eq!(fit_field(field("u8"), 265, Overflow::Wrap), Some(9));
+        assert_eq!(fit_field(field("i8"), 128, Overflow::Wrap), Some(-128));
+        assert_eq!(fit_field(field("i8"), -129, Overflow::Wrap), Some(127));
+        assert_eq!(fit_field(field("i8"), 200, Overflow::Sat), Some(127));
+        assert_eq!(fit_field(field("i8"), -200, Overflow::Sat), Some(-128));
+        assert_eq!(fit_field(field("u2"), -1, Overflow::Sat), Some(0));
+        assert_eq!(fit_field(field("u2"), 4, Overflow::Fail), None);
+        assert_eq!(fit_field(field("u2"), 3, Overflow::Fail), Some(3));
+    }
+}
@@ -0,0 +1,268 @@
+//! Clients blocked by `BLPOP` and friends until one of their keys holds a
+//! list.
+//!
+//! Commands that may create a list signal its key as ready. Once the
+//! command is done, the clients blocked on each ready key are served in the
+//! order they blocked, for as long as the list lasts, before any other
+//! command runs. A client blocked on several keys is served from one of
+//! them and then forgotten for all of them.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::command::BlockingOperation;
+
+/// What a blocked client gets once its timeout passes without a list to
+/// pop from: a null array, whatever the command.
+pub const TIMEOUT_REPLY: &[u8] = b"*-1\r\n";
+
+/// A client waiting for one of `keys` in database `db` to hold a list.
+pub struct Waiter {
+    pub db: usize,
+    pub keys: Vec<Bytes>,
+    pub operation: BlockingOperation,
+    sender: oneshot::Sender<Vec<u8>>,
+}
+
+impl Waiter {
+    /// Whether the connection stopped waiting without unblocking, e.g.
+    /// because it failed; such a client must not be served.
+    pub fn is_gone(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// The connection's half of a blocked command: where the reply arrives
+/// once the client is served.
+#[derive(Debug)]
+pub struct BlockedWait {
+    pub id: u64,
+    pub receiver: oneshot::Receiver<Vec<u8>>,
+    /// How long to wait at most, forever if `None`.
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct BlockedClients {
+    /// Ids are handed out in increasing order, so a lower id waited longer.
+    next_id: u64,
+    waiters: HashMap<u64, Waiter>,
+    /// Ids of the clients blocked on each key of each database.
+    keys: HashMap<(usize, Bytes), BTreeSet<u64>>,
+    /// Keys signaled since blocked clients were last served, in order.
+    ready: Vec<(usize, Bytes)>,
+    ready_set: HashSet<(usize, Bytes)>,
+}
+
+impl BlockedClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of clients currently blocked.
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Blocks a client on `keys` until it is served or gives up.
+    pub fn block(
+        &mut self,
+        db: usize,
+        keys: Vec<Bytes>,
+        operation: BlockingOperation,
+        timeout: Option<Duration>,
+    ) -> BlockedWait {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in &keys {
+            self.keys.entry((db, key.clone())).or_default().insert(id);
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.insert(
+            id,
+            Waiter {
+                db,
+                keys,
+                operation,
+                sender,
+            },
+        );
+        BlockedWait {
+            id,
+            receiver,
+            timeout,
+        }
+    }
+
+    /// Stops waiting for the client, e.g. once its timeout passed. Returns
+    /// `false` if it was served in the meantime, in which case its reply is
+    /// already in its channel.
+    pub fn unblock(&mut self, id: u64) -> bool {
+        self.remove(id).is_some()
+    }
+
+    /// Marks `key` as ready if any client is blocked on it, e.g. after a
+    /// push.
+    pub fn signal(&mut self, db: usize, key: &Bytes) {
+        let ready = (db, key.clone());
+        if self.keys.contains_key(&ready) && self.ready_set.insert(ready.clone()) {
+            self.ready.push(ready);
+        }
+    }
+
+    /// Marks every key clients are blocked on in `db` as ready, e.g. after
+    /// `SWAPDB` replaced its whole content.
+    pub fn signal_db(&mut self, db: usize) {
+        let mut keys = self
+            .keys
+            .keys()
+            .filter(|(index, _)| *index == db)
+            .map(|(_, key)| key.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            self.signal(db, &key);
+        }
+    }
+
+    pub fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Takes the keys signaled so far, in the order they were signaled.
+    pub fn take_ready(&mut self) -> Vec<(usize, Bytes)> {
+        self.ready_set.clear();
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Ids of the clients blocked on `key`, the longest-waiting first.
+    pub fn blocked_on(&self, db: usize, key: &Bytes) -> Vec<u64> {
+        self.keys
+            .get(&(db, key.clone()))
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn waiter(&self, id: u64) -> Option<&Waiter> {
+        self.waiters.get(&id)
+    }
+
+    /// Unblocks the client with `reply`. A client that disconnected in the
+    /// meantime is simply dropped.
+    pub fn serve(&mut self, id: u64, reply: Vec<u8>) {
+        if let Some(waiter) = self.remove(id) {
+            let _ = waiter.sender.send(reply);
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            let entry = (waiter.db, key.clone());
+            if let Some(ids) = self.keys.get_mut(&entry) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.keys.remove(&entry);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quicklist::ListEnd;
+
+    fn key(name: &str) -> Bytes {
+        Bytes::from(name.to_string())
+    }
+
+    fn block(blocked: &mut BlockedClients, keys: &[&str]) -> BlockedWait {
+        let keys = keys.iter().map(|name| key(name)).collect();
+        blocked.block(0, keys, BlockingOperation::Pop(ListEnd::Left), None)
+    }
+
+    #[test]
+    fn clients_are_listed_in_the_order_they_blocked() {
+        let mut blocked = BlockedClients::new();
+        let first = block(&mut blocked, &["list"]);
+        let other = block(&mut blocked, &["other"]);
+        let second = block(&mut blocked, &["other", "list"]);
+        assert_eq!(blocked.blocked_on(0, &key("list")), [first.id, second.id]);
+        assert_eq!(blocked.blocked_on(0, &key("other")), [other.id, second.id]);
+        assert!(blocked.blocked_on(1, &key("list")).is_empty());
+    }
+
+    #[test]
+    fn only_keys_with_waiters_are_signaled_once_in_order() {
+        let mut blocked = BlockedClients::new();
+        block(&mut blocked, &["a", "b"]);
+        blocked.signal(0, &key("nobody"));
+        blocked.signal(1, &key("a"));
+        assert!(!blocked.has_ready());
+
+        blocked.signal(0, &key("b"));
+        blocked.signal(0, &key("a"));
+        blocked.signal(0, &key("b"));
+        assert_eq!(blocked.take_ready(), [(0, key("b")), (0, key("a"))]);
+        assert!(!blocked.has_ready());
+
+        // Taken keys can be signaled again.
+        blocked.signal(0, &key("a"));
+        assert_eq!(blocked.take_ready(), [(0, key("a"))]);
+    }
+
+    #[test]
+    fn signaling_a_database_readies_all_of_its_keys() {
+        let mut blocked = BlockedClients::new();
+        block(&mut blocked, &["b", "a"]);
+        blocked.block(
+            1,
+            vec![key("c")],
+            BlockingOperation::Pop(ListEnd::Left),
+            None,
+        );
+        blocked.signal_db(0);
+        assert_eq!(blocked.take_ready(), [(0, key("a")), (0, key("b"))]);
+    }
+
+    #[test]
+    fn serving_a_client_forgets_it_for_every_key() {
+        let mut blocked = BlockedClients::new();
+        let mut wait = block(&mut blocked, &["a", "b"]);
+        blocked.serve(wait.id, b"reply".to_vec());
+        assert_eq!(wait.receiver.try_recv().unwrap(), b"reply");
+        assert_eq!(blocked.len(), 0);
+        assert!(blocked.blocked_on(0, &key("a")).is_empty());
+        assert!(blocked.blocked_on(0, &key("b")).is_empty());
+        // Too late to time out, the reply is already in the channel.
+        assert!(!blocked.unblock(wait.id));
+    }
+
+    #[test]
+    fn unblocking_a_client_forgets_it() {
+        let mut blocked = BlockedClients::new();
+        let wait = block(&mut blocked, &["a"]);
+        assert!(blocked.unblock(wait.id));
+        assert_eq!(blocked.len(), 0);
+        assert!(blocked.blocked_on(0, &key("a")).is_empty());
+        blocked.signal(0, &key("a"));
+        assert!(!blocked.has_ready());
+    }
+
+    #[test]
+    fn a_client_that_stopped_waiting_is_gone() {
+        let mut blocked = BlockedClients::new();
+        let wait = block(&mut blocked, &["a"]);
+        assert!(!blocked.waiter(wait.id).unwrap().is_gone());
+        drop(wait.receiver);
+        assert!(blocked.waiter(wait.id).unwrap().is_gone());
+    }
+}
@@ -1,164 +1,1838 @@
-use crate::response::Value;
+use std::time::Duration;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::{
+    bitmap::{BitOperation, BitfieldType, Overflow, RangeUnit, MAX_BIT_OFFSET},
+    db::{parse_f64, parse_i64, unix_time_millis},
+    quicklist::ListEnd,
+    response::Value,
+};
+
+/// Why a request could not be turned into a `Command`; the message is sent
+/// to the client after `-ERR `.
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("unknown command '{0}'")]
+    UnknownCommand(String),
+    #[error("unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("syntax error")]
+    Syntax,
+    #[error("Unsupported option {0}")]
+    UnsupportedOption(String),
+    #[error("{0}")]
+    Message(&'static str),
+    #[error("value is not an integer or out of range")]
+    NotInteger,
+    #[error("invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+    #[error("DB index is out of range")]
+    DbIndexOutOfRange,
+    #[error("value is not a valid float")]
+    NotFloat,
+    #[error("decrement would overflow")]
+    DecrementOverflow,
+    #[error("offset is out of range")]
+    OffsetOutOfRange,
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("unknown type name '{0}'")]
+    UnknownType(String),
+    #[error("bit offset is not an integer or out of range")]
+    BitOffset,
+    #[error("bit is not an integer or out of range")]
+    BitValue,
+    #[error("The bit argument must be 1 or 0.")]
+    BitArgument,
+    #[error("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.")]
+    BitfieldType,
+}
+
+/// Only set the key if it does not exist yet (`NX`) or if it already
+/// exists (`XX`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    Nx,
+    Xx,
+}
 
 pub struct SetCommand {
-    pub key: String,
-    pub value: String,
-    pub px: Option<u64>,
+    pub key: Bytes,
+    pub value: Bytes,
+    /// Absolute unix time in milliseconds.
+    pub expires_at: Option<u64>,
+    /// Keep the current expiry of the key instead of clearing it.
+    pub keep_ttl: bool,
+    pub condition: Option<SetCondition>,
+    /// Reply with the previous value instead of `OK`.
+    pub get: bool,
 }
 
 impl SetCommand {
-    pub fn new(key: String, value: String, px: Option<u64>) -> Self {
-        Self { key, value, px }
+    pub fn new(key: Bytes, value: Bytes, expires_at: Option<u64>) -> Self {
+        Self {
+            key,
+            value,
+            expires_at,
+            keep_ttl: false,
+            condition: None,
+            get: false,
+        }
+    }
+}
+
+/// `LCS key1 key2` and its options.
+pub struct LcsCommand {
+    pub keys: (Bytes, Bytes),
+    /// Reply with the length of the subsequence only.
+    pub len: bool,
+    /// Reply with the ranges of the matches.
+    pub idx: bool,
+    /// Leave out matches shorter than this from the `IDX` reply.
+    pub min_match_len: usize,
+    pub with_match_len: bool,
+}
+
+/// What `SCAN`, `HSCAN`, `SSCAN` and `ZSCAN` have in common.
+pub struct ScanOptions {
+    pub cursor: u64,
+    /// Only return names matching this glob pattern.
+    pub pattern: Option<Bytes>,
+    /// How many elements to visit, which can be more than are returned.
+    pub count: usize,
+}
+
+/// `COPY source destination [DB destination-db] [REPLACE]`.
+pub struct CopyCommand {
+    pub source: Bytes,
+    pub destination: Bytes,
+    /// Database to copy to, the selected one if not given.
+    pub db: Option<usize>,
+    pub replace: bool,
+}
+
+/// `BITPOS key bit [start [end [BYTE|BIT]]]`.
+pub struct BitPosCommand {
+    pub key: Bytes,
+    pub bit: bool,
+    pub start: i64,
+    pub end: Option<i64>,
+    pub unit: RangeUnit,
+}
+
+/// `LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]`.
+pub struct LPosCommand {
+    pub key: Bytes,
+    pub element: Bytes,
+    /// Which match to start from, counting from the tail when negative.
+    pub rank: i64,
+    /// How many positions to reply with, all of them for 0. Without it the
+    /// reply is a single position rather than an array.
+    pub count: Option<usize>,
+    /// How many elements to compare at most, all of them for 0.
+    pub max_len: usize,
+}
+
+/// What a blocking list command does once one of its keys holds a list.
+#[derive(Debug, Clone)]
+pub enum BlockingOperation {
+    /// `BLPOP` and `BRPOP`: pop one element, replying with the key and the
+    /// element.
+    Pop(ListEnd),
+    /// `BLMPOP`: pop up to `count` elements, replying with the key and an
+    /// array of them.
+    MPop(ListEnd, usize),
+    /// `BLMOVE` and `BRPOPLPUSH`: move an element to `destination`,
+    /// replying with it.
+    Move {
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    },
+}
+
+/// `BLPOP`, `BRPOP`, `BLMPOP`, `BLMOVE` and `BRPOPLPUSH`.
+pub struct BlockingCommand {
+    /// The keys to pop from, in the order they are tried.
+    pub keys: Vec<Bytes>,
+    pub operation: BlockingOperation,
+    /// How long to block at most, forever if `None`.
+    pub timeout: Option<Duration>,
+}
+
+/// One subcommand of `BITFIELD`; offsets are in bits.
+pub enum BitfieldOperation {
+    Get(BitfieldType, u64),
+    Set(BitfieldType, u64, i64),
+    IncrBy(BitfieldType, u64, i64),
+    Overflow(Overflow),
+}
+
+impl BitfieldOperation {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            BitfieldOperation::Set(..) | BitfieldOperation::IncrBy(..)
+        )
+    }
+}
+
+/// How `GETEX` changes the expiry of the key it reads.
+pub enum GetExExpiry {
+    /// Absolute unix time in milliseconds.
+    At(u64),
+    Persist,
+}
+
+/// Conditions of `EXPIRE` and friends; a key without an expiry counts as
+/// having an infinite one for `GT` and `LT`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExpireFlags {
+    /// Only if the key has no expiry.
+    pub nx: bool,
+    /// Only if the key already has an expiry.
+    pub xx: bool,
+    /// Only if the new expiry is later than the current one.
+    pub gt: bool,
+    /// Only if the new expiry is earlier than the current one.
+    pub lt: bool,
+}
+
+impl ExpireFlags {
+    /// Whether a key whose expiry is `current` may get `expires_at`.
+    pub fn allow(&self, current: Option<u64>, expires_at: u64) -> bool {
+        (!self.nx || current.is_none())
+            && (!self.xx || current.is_some())
+            && (!self.gt || current.is_some_and(|current| expires_at > current))
+            && (!self.lt || current.is_none_or(|current| expires_at < current))
+    }
+
+    fn to_args(self) -> Vec<Bytes> {
+        [
+            (self.nx, "NX"),
+            (self.xx, "XX"),
+            (self.gt, "GT"),
+            (self.lt, "LT"),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| Bytes::from_static(flag.as_bytes()))
+        .collect()
     }
 }
 
+pub struct ExpireCommand {
+    pub key: Bytes,
+    /// Absolute unix time in milliseconds; at or before the current time
+    /// the key is deleted instead.
+    pub expires_at: i64,
+    pub flags: ExpireFlags,
+}
+
+/// Unit of the times reported by `TTL`/`PTTL` and `EXPIRETIME`/`PEXPIRETIME`.
+#[derive(Debug, Clone, Copy)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+/// Whether `SHUTDOWN` should write a final snapshot. `Default` saves only
+/// when `save` rules are configured.
+pub enum ShutdownMode {
+    Default,
+    Save,
+    NoSave,
+}
+
 pub enum Command {
     Ping(String),
-    Echo(String),
+    Echo(Bytes),
     Set(SetCommand),
-    Get(String),
-    Config(String),
-    Keys(String),
+    /// `SETNX`, which replies with an integer rather than like `SET NX`.
+    SetNx(SetCommand),
+    Get(Bytes),
+    GetDel(Bytes),
+    GetEx(Bytes, Option<GetExExpiry>),
+    /// `INCR`, `DECR`, `INCRBY` and `DECRBY`, with the sign of `DECR*`
+    /// folded into the delta.
+    IncrBy(Bytes, i64),
+    IncrByFloat(Bytes, f64),
+    Append(Bytes, Bytes),
+    StrLen(Bytes),
+    GetRange(Bytes, i64, i64),
+    SetRange(Bytes, usize, Bytes),
+    MGet(Vec<Bytes>),
+    MSet(Vec<(Bytes, Bytes)>),
+    MSetNx(Vec<(Bytes, Bytes)>),
+    Lcs(LcsCommand),
+    SetBit(Bytes, u64, bool),
+    GetBit(Bytes, u64),
+    /// `BITCOUNT key [start end [BYTE|BIT]]`.
+    BitCount(Bytes, Option<(i64, i64, RangeUnit)>),
+    BitPos(BitPosCommand),
+    /// `BITOP operation destkey key [key ...]`.
+    BitOp(BitOperation, Bytes, Vec<Bytes>),
+    /// `BITFIELD` and `BITFIELD_RO`, which only accepts `GET`.
+    BitField(Bytes, Vec<BitfieldOperation>),
+    /// `LPUSH` and `RPUSH`.
+    Push(ListEnd, Bytes, Vec<Bytes>),
+    /// `LPUSHX` and `RPUSHX`, which only push onto an existing list.
+    PushX(ListEnd, Bytes, Vec<Bytes>),
+    /// `LPOP` and `RPOP`, with the count if one was given.
+    Pop(ListEnd, Bytes, Option<usize>),
+    /// `LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]`.
+    LMPop(Vec<Bytes>, ListEnd, usize),
+    LLen(Bytes),
+    LRange(Bytes, i64, i64),
+    LIndex(Bytes, i64),
+    LSet(Bytes, i64, Bytes),
+    /// `LREM key count element`.
+    LRem(Bytes, i64, Bytes),
+    LTrim(Bytes, i64, i64),
+    /// `LINSERT key BEFORE|AFTER pivot element`, with `true` for `BEFORE`.
+    LInsert(Bytes, bool, Bytes, Bytes),
+    LPos(LPosCommand),
+    /// `LMOVE source destination LEFT|RIGHT LEFT|RIGHT`, which `RPOPLPUSH`
+    /// is a special case of.
+    LMove(Bytes, Bytes, ListEnd, ListEnd),
+    /// Any of the blocking list commands, which never go to the append-only
+    /// file themselves: what they pop is logged as `LPOP`, `RPOP` or
+    /// `LMOVE` once they are served.
+    Blocking(BlockingCommand),
+    /// `CONFIG GET pattern [pattern ...]`.
+    Config(Vec<String>),
+    Keys(Bytes),
+    Save,
+    BgSave,
+    LastSave,
+    Shutdown(ShutdownMode),
+    BgRewriteAof,
+    Select(usize),
+    Move(Bytes, usize),
+    SwapDb(usize, usize),
+    FlushDb,
+    FlushAll,
+    DbSize,
+    Del(Vec<Bytes>),
+    /// Like `DEL`, but large values are freed in the background.
+    Unlink(Vec<Bytes>),
+    Exists(Vec<Bytes>),
+    Touch(Vec<Bytes>),
+    Type(Bytes),
+    Rename(Bytes, Bytes),
+    RenameNx(Bytes, Bytes),
+    Copy(CopyCommand),
+    RandomKey,
+    /// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`.
+    Scan(ScanOptions, Option<String>),
+    HScan(Bytes, ScanOptions),
+    SScan(Bytes, ScanOptions),
+    ZScan(Bytes, ScanOptions),
+    /// `INFO` with the requested sections, lowercased.
+    Info(Vec<String>),
+    Expire(ExpireCommand),
+    Ttl(Bytes, TimeUnit),
+    ExpireTime(Bytes, TimeUnit),
+    Persist(Bytes),
 }
 
 impl Command {
-    pub fn process(name: &str, args: &[Value]) -> Option<Command> {
-        match name.to_uppercase().as_str() {
-            "PING" => Some(Command::Ping("PONG".to_string())),
-            "ECHO" => Some(Command::Echo(
+    pub fn process(name: &str, args: &[Value]) -> Result<Command, CommandError> {
+        let upper = name.to_uppercase();
+        match upper.as_str() {
+            "PING" => Ok(Command::Ping("PONG".to_string())),
+            "ECHO" => Ok(Command::Echo(Bytes::from(
                 args.iter()
                     .map(|arg| match arg {
-                        Value::String(string) => string,
-                        _ => "",
+                        Value::String(string) => string.as_ref(),
+                        _ => b"",
                     })
-                    .collect::<Vec<&str>>()
-                    .join(" "),
-            )),
-            "SET" => {
-                if args.len() > 4 || args.len() < 2 {
-                    eprintln!("SET command requires 2 or 4 arguments; got {}", args.len());
-                    return None;
-                }
-
-                if args.len() == 2 {
-                    match (&args[0], &args[1]) {
-                        (Value::String(key), Value::String(value)) => {
-                            return Some(Command::Set(SetCommand::new(
-                                key.to_string(),
-                                value.to_string(),
-                                None,
-                            )))
+                    .collect::<Vec<&[u8]>>()
+                    .join(&b' '),
+            ))),
+            "SET" => match args {
+                [Value::String(key), Value::String(value), options @ ..] => {
+                    parse_set(key, value, options).map(Command::Set)
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "SETNX" => match args {
+                [Value::String(key), Value::String(value)] => Ok(Command::SetNx(SetCommand {
+                    condition: Some(SetCondition::Nx),
+                    ..SetCommand::new(key.clone(), value.clone(), None)
+                })),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "SETEX" | "PSETEX" => match args {
+                [Value::String(key), Value::String(time), Value::String(value)] => {
+                    let expires_at =
+                        parse_relative_expiry(time, upper == "SETEX", &name.to_lowercase())?;
+                    Ok(Command::Set(SetCommand::new(
+                        key.clone(),
+                        value.clone(),
+                        Some(expires_at),
+                    )))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "GETSET" => match args {
+                [Value::String(key), Value::String(value)] => Ok(Command::Set(SetCommand {
+                    get: true,
+                    ..SetCommand::new(key.clone(), value.clone(), None)
+                })),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "GET" | "GETDEL" => match args {
+                [Value::String(key)] if upper == "GET" => Ok(Command::Get(key.clone())),
+                [Value::String(key)] => Ok(Command::GetDel(key.clone())),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "INCR" | "DECR" => match args {
+                [Value::String(key)] => Ok(Command::IncrBy(
+                    key.clone(),
+                    if upper == "INCR" { 1 } else { -1 },
+                )),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "INCRBY" | "DECRBY" => match args {
+                [Value::String(key), Value::String(delta)] => {
+                    let delta = parse_i64(delta).ok_or(CommandError::NotInteger)?;
+                    let delta = if upper == "INCRBY" {
+                        delta
+                    } else {
+                        delta.checked_neg().ok_or(CommandError::DecrementOverflow)?
+                    };
+                    Ok(Command::IncrBy(key.clone(), delta))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "INCRBYFLOAT" => match args {
+                [Value::String(key), Value::String(delta)] => {
+                    let delta = parse_f64(delta).ok_or(CommandError::NotFloat)?;
+                    Ok(Command::IncrByFloat(key.clone(), delta))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "APPEND" => match args {
+                [Value::String(key), Value::String(value)] => {
+                    Ok(Command::Append(key.clone(), value.clone()))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "STRLEN" => match args {
+                [Value::String(key)] => Ok(Command::StrLen(key.clone())),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "GETRANGE" | "SUBSTR" => match args {
+                [Value::String(key), Value::String(start), Value::String(end)] => {
+                    Ok(Command::GetRange(
+                        key.clone(),
+                        parse_integer(start).ok_or(CommandError::NotInteger)?,
+                        parse_integer(end).ok_or(CommandError::NotInteger)?,
+                    ))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "SETRANGE" => match args {
+                [Value::String(key), Value::String(offset), Value::String(value)] => {
+                    let offset = parse_integer::<i64>(offset).ok_or(CommandError::NotInteger)?;
+                    let offset =
+                        usize::try_from(offset).map_err(|_| CommandError::OffsetOutOfRange)?;
+                    Ok(Command::SetRange(key.clone(), offset, value.clone()))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "MGET" => {
+                if args.is_empty() {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                Ok(Command::MGet(args.iter().map(bytes_argument).collect()))
+            }
+            "MSET" | "MSETNX" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| (bytes_argument(&pair[0]), bytes_argument(&pair[1])))
+                    .collect();
+                Ok(if upper == "MSET" {
+                    Command::MSet(pairs)
+                } else {
+                    Command::MSetNx(pairs)
+                })
+            }
+            "SETBIT" => match args {
+                [Value::String(key), Value::String(offset), Value::String(bit)] => {
+                    let bit = match bit.as_ref() {
+                        b"0" => false,
+                        b"1" => true,
+                        _ => return Err(CommandError::BitValue),
+                    };
+                    Ok(Command::SetBit(key.clone(), parse_bit_offset(offset)?, bit))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "GETBIT" => match args {
+                [Value::String(key), Value::String(offset)] => {
+                    Ok(Command::GetBit(key.clone(), parse_bit_offset(offset)?))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "BITCOUNT" => match args {
+                [Value::String(key)] => Ok(Command::BitCount(key.clone(), None)),
+                [Value::String(key), Value::String(start), Value::String(end), unit @ ..]
+                    if unit.len() <= 1 =>
+                {
+                    Ok(Command::BitCount(
+                        key.clone(),
+                        Some((
+                            parse_integer(start).ok_or(CommandError::NotInteger)?,
+                            parse_integer(end).ok_or(CommandError::NotInteger)?,
+                            parse_range_unit(unit.first())?,
+                        )),
+                    ))
+                }
+                [_, ..] => Err(CommandError::Syntax),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "BITPOS" => match args {
+                [Value::String(key), Value::String(bit), range @ ..] if range.len() <= 3 => {
+                    let bit = match bit.as_ref() {
+                        b"0" => false,
+                        b"1" => true,
+                        _ => return Err(CommandError::BitArgument),
+                    };
+                    let start = match range.first() {
+                        Some(Value::String(start)) => {
+                            parse_integer(start).ok_or(CommandError::NotInteger)?
                         }
-                        _ => {
-                            eprintln!("Wrong type of arguments for 'SET' command; got {:?}", args);
-                            return None;
+                        _ => 0,
+                    };
+                    let end = match range.get(1) {
+                        Some(Value::String(end)) => {
+                            Some(parse_integer(end).ok_or(CommandError::NotInteger)?)
                         }
+                        _ => None,
+                    };
+                    Ok(Command::BitPos(BitPosCommand {
+                        key: key.clone(),
+                        bit,
+                        start,
+                        end,
+                        unit: parse_range_unit(range.get(2))?,
+                    }))
+                }
+                [_, _, ..] => Err(CommandError::Syntax),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "BITOP" => match args {
+                [Value::String(operation), Value::String(destination), sources @ ..]
+                    if !sources.is_empty() =>
+                {
+                    let operation = match operation.to_ascii_uppercase().as_slice() {
+                        b"AND" => BitOperation::And,
+                        b"OR" => BitOperation::Or,
+                        b"XOR" => BitOperation::Xor,
+                        b"NOT" => BitOperation::Not,
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    if operation == BitOperation::Not && sources.len() != 1 {
+                        return Err(CommandError::Message(
+                            "BITOP NOT must be called with a single source key.",
+                        ));
                     }
+                    Ok(Command::BitOp(
+                        operation,
+                        destination.clone(),
+                        sources.iter().map(bytes_argument).collect(),
+                    ))
                 }
-                if args.len() == 4 {
-                    match (&args[0], &args[1], &args[2], &args[3]) {
-                        (
-                            Value::String(key),
-                            Value::String(value),
-                            Value::String(px),
-                            Value::String(expiry_in_ms),
-                        ) => {
-                            if px.to_uppercase() != "PX" {
-                                eprintln!(
-                                    "Wrong type of arguments for 'SET' command; expecting PX, got {}", px
-                                );
-                                return None;
-                            }
-                            let px = match expiry_in_ms.parse::<u64>() {
-                                Ok(px) => px,
-                                Err(_) => {
-                                    eprintln!("Unable to parse expiry_in_ms; got {}", expiry_in_ms);
-                                    return None;
-                                }
-                            };
-                            return Some(Command::Set(SetCommand::new(
-                                key.to_string(),
-                                value.to_string(),
-                                Some(px),
-                            )));
-                        }
-                        _ => {
-                            eprintln!("Wrong type of arguments for 'SET' command; got {:?}", args);
-                            return None;
-                        }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "BITFIELD" | "BITFIELD_RO" => match args {
+                [Value::String(key), operations @ ..] => {
+                    let operations = parse_bitfield(operations)?;
+                    if upper == "BITFIELD_RO"
+                        && operations
+                            .iter()
+                            .any(|operation| !matches!(operation, BitfieldOperation::Get(..)))
+                    {
+                        return Err(CommandError::Message(
+                            "BITFIELD_RO only supports the GET subcommand",
+                        ));
+                    }
+                    Ok(Command::BitField(key.clone(), operations))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "LCS" => match args {
+                [Value::String(a), Value::String(b), options @ ..] => {
+                    parse_lcs(a, b, options).map(Command::Lcs)
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "GETEX" => match args {
+                [Value::String(key), options @ ..] => {
+                    parse_getex_options(options).map(|expiry| Command::GetEx(key.clone(), expiry))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+            "CONFIG" => match args {
+                [Value::String(subcommand), patterns @ ..]
+                    if subcommand.eq_ignore_ascii_case(b"GET") =>
+                {
+                    if patterns.is_empty() {
+                        return Err(CommandError::WrongArity("config|get".to_string()));
                     }
+                    Ok(Command::Config(
+                        patterns.iter().map(|pattern| pattern.to_string()).collect(),
+                    ))
                 }
-                None
-            }
-            "GET" => {
-                if args.len() != 1 {
-                    eprintln!(
-                        "Wrong number of arguments for 'GET' command; got {}",
-                        args.len()
-                    );
-                    return None;
-                }
-                match &args[0] {
-                    Value::String(key) => Some(Command::Get(key.clone())),
-                    _ => {
-                        eprintln!("Wrong type of arguments for 'GET' command; got {:?}", args);
-                        None
+                [Value::String(subcommand), ..] => Err(CommandError::UnknownSubcommand(
+                    String::from_utf8_lossy(subcommand).into_owned(),
+                    "CONFIG".to_string(),
+                )),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" => match args {
+                [Value::String(key), elements @ ..] if !elements.is_empty() => {
+                    let end = if upper.starts_with('L') {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    };
+                    let elements = elements.iter().map(bytes_argument).collect();
+                    Ok(if upper.ends_with('X') {
+                        Command::PushX(end, key.clone(), elements)
+                    } else {
+                        Command::Push(end, key.clone(), elements)
+                    })
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LPOP" | "RPOP" => {
+                let end = if upper == "LPOP" {
+                    ListEnd::Left
+                } else {
+                    ListEnd::Right
+                };
+                match args {
+                    [Value::String(key)] => Ok(Command::Pop(end, key.clone(), None)),
+                    [Value::String(key), Value::String(count)] => {
+                        let count = parse_positive_count(count)?;
+                        Ok(Command::Pop(end, key.clone(), Some(count)))
                     }
+                    _ => Err(CommandError::WrongArity(name.to_lowercase())),
                 }
             }
-            "CONFIG" => match args[0].to_string().to_uppercase().as_str() {
-                "GET" => match &args[1] {
-                    Value::String(config_value) => Some(Command::Config(config_value.clone())),
-                    _ => {
-                        eprintln!(
-                            "Wrong type of arguments for 'CONFIG' command; got {:?}",
-                            args
-                        );
-                        None
-                    }
-                },
-                _ => {
-                    eprintln!("Unknown command '{}'; expecting CONFIG GET", name);
-                    None
-                }
-            },
-
-            "KEYS" => {
-                if args.len() != 1 {
-                    eprintln!(
-                        "Wrong number of arguments for 'KEYS' command; got {}",
-                        args.len()
-                    );
-                    return None;
-                }
-                match &args[0] {
-                    Value::String(key) => Some(Command::Keys(key.clone())),
-                    _ => {
-                        eprintln!("Wrong type of arguments for 'KEYS' command; got {:?}", args);
-                        None
+
+            "LMPOP" => match args {
+                [Value::String(numkeys), rest @ ..] if rest.len() >= 2 => {
+                    parse_lmpop(numkeys, rest)
+                        .map(|(keys, end, count)| Command::LMPop(keys, end, count))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LLEN" => match args {
+                [Value::String(key)] => Ok(Command::LLen(key.clone())),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LRANGE" | "LTRIM" => match args {
+                [Value::String(key), Value::String(start), Value::String(end)] => {
+                    let start = parse_integer(start).ok_or(CommandError::NotInteger)?;
+                    let end = parse_integer(end).ok_or(CommandError::NotInteger)?;
+                    Ok(if upper == "LRANGE" {
+                        Command::LRange(key.clone(), start, end)
+                    } else {
+                        Command::LTrim(key.clone(), start, end)
+                    })
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LINDEX" => match args {
+                [Value::String(key), Value::String(index)] => Ok(Command::LIndex(
+                    key.clone(),
+                    parse_integer(index).ok_or(CommandError::NotInteger)?,
+                )),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LSET" => match args {
+                [Value::String(key), Value::String(index), Value::String(element)] => {
+                    Ok(Command::LSet(
+                        key.clone(),
+                        parse_integer(index).ok_or(CommandError::NotInteger)?,
+                        element.clone(),
+                    ))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LREM" => match args {
+                [Value::String(key), Value::String(count), Value::String(element)] => {
+                    Ok(Command::LRem(
+                        key.clone(),
+                        parse_integer(count).ok_or(CommandError::NotInteger)?,
+                        element.clone(),
+                    ))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LINSERT" => match args {
+                [Value::String(key), Value::String(position), Value::String(pivot), Value::String(element)] =>
+                {
+                    let before = if position.eq_ignore_ascii_case(b"BEFORE") {
+                        true
+                    } else if position.eq_ignore_ascii_case(b"AFTER") {
+                        false
+                    } else {
+                        return Err(CommandError::Syntax);
+                    };
+                    Ok(Command::LInsert(
+                        key.clone(),
+                        before,
+                        pivot.clone(),
+                        element.clone(),
+                    ))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LPOS" => match args {
+                [Value::String(key), Value::String(element), options @ ..] => {
+                    parse_lpos(key, element, options).map(Command::LPos)
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "LMOVE" => match args {
+                [Value::String(source), Value::String(destination), Value::String(from), Value::String(to)] =>
+                {
+                    let (Some(from), Some(to)) = (ListEnd::parse(from), ListEnd::parse(to)) else {
+                        return Err(CommandError::Syntax);
+                    };
+                    Ok(Command::LMove(
+                        source.clone(),
+                        destination.clone(),
+                        from,
+                        to,
+                    ))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "RPOPLPUSH" => match args {
+                [Value::String(source), Value::String(destination)] => Ok(Command::LMove(
+                    source.clone(),
+                    destination.clone(),
+                    ListEnd::Right,
+                    ListEnd::Left,
+                )),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "BLPOP" | "BRPOP" => match args {
+                [keys @ .., Value::String(timeout)] if !keys.is_empty() => {
+                    let end = if upper == "BLPOP" {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    };
+                    Ok(Command::Blocking(BlockingCommand {
+                        keys: keys.iter().map(bytes_argument).collect(),
+                        operation: BlockingOperation::Pop(end),
+                        timeout: parse_timeout(timeout)?,
+                    }))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "BLMPOP" => match args {
+                [Value::String(timeout), Value::String(numkeys), rest @ ..] if rest.len() >= 2 => {
+                    let timeout = parse_timeout(timeout)?;
+                    let (keys, end, count) = parse_lmpop(numkeys, rest)?;
+                    Ok(Command::Blocking(BlockingCommand {
+                        keys,
+                        operation: BlockingOperation::MPop(end, count),
+                        timeout,
+                    }))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "BLMOVE" => match args {
+                [Value::String(source), Value::String(destination), Value::String(from), Value::String(to), Value::String(timeout)] =>
+                {
+                    let (Some(from), Some(to)) = (ListEnd::parse(from), ListEnd::parse(to)) else {
+                        return Err(CommandError::Syntax);
+                    };
+                    Ok(Command::Blocking(BlockingCommand {
+                        keys: vec![source.clone()],
+                        operation: BlockingOperation::Move {
+                            destination: destination.clone(),
+                            from,
+                            to,
+                        },
+                        timeout: parse_timeout(timeout)?,
+                    }))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "BRPOPLPUSH" => match args {
+                [Value::String(source), Value::String(destination), Value::String(timeout)] => {
+                    Ok(Command::Blocking(BlockingCommand {
+                        keys: vec![source.clone()],
+                        operation: BlockingOperation::Move {
+                            destination: destination.clone(),
+                            from: ListEnd::Right,
+                            to: ListEnd::Left,
+                        },
+                        timeout: parse_timeout(timeout)?,
+                    }))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "KEYS" => match args {
+                [Value::String(pattern)] => Ok(Command::Keys(pattern.clone())),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "SAVE" | "BGSAVE" | "LASTSAVE" | "BGREWRITEAOF" => {
+                if !args.is_empty() {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                Ok(match upper.as_str() {
+                    "SAVE" => Command::Save,
+                    "BGSAVE" => Command::BgSave,
+                    "BGREWRITEAOF" => Command::BgRewriteAof,
+                    _ => Command::LastSave,
+                })
+            }
+
+            "SHUTDOWN" => match args {
+                [] => Ok(Command::Shutdown(ShutdownMode::Default)),
+                [Value::String(mode)] if mode.eq_ignore_ascii_case(b"SAVE") => {
+                    Ok(Command::Shutdown(ShutdownMode::Save))
+                }
+                [Value::String(mode)] if mode.eq_ignore_ascii_case(b"NOSAVE") => {
+                    Ok(Command::Shutdown(ShutdownMode::NoSave))
+                }
+                _ => Err(CommandError::Syntax),
+            },
+
+            "SELECT" => match args {
+                [Value::String(index)] => parse_db_index(index).map(Command::Select),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "MOVE" => match args {
+                [Value::String(key), Value::String(index)] => {
+                    parse_db_index(index).map(|index| Command::Move(key.clone(), index))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "SWAPDB" => match args {
+                [Value::String(first), Value::String(second)] => Ok(Command::SwapDb(
+                    parse_db_index(first)?,
+                    parse_db_index(second)?,
+                )),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            // Freeing memory happens inline either way, so ASYNC and SYNC are
+            // accepted and behave the same.
+            "FLUSHDB" | "FLUSHALL" => match args {
+                [] => Ok(flush_command(name)),
+                [Value::String(mode)]
+                    if mode.eq_ignore_ascii_case(b"ASYNC")
+                        || mode.eq_ignore_ascii_case(b"SYNC") =>
+                {
+                    Ok(flush_command(name))
+                }
+                _ => Err(CommandError::Syntax),
+            },
+
+            "DEL" | "UNLINK" | "EXISTS" | "TOUCH" => {
+                if args.is_empty() {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                let keys = args.iter().map(bytes_argument).collect();
+                Ok(match upper.as_str() {
+                    "DEL" => Command::Del(keys),
+                    "UNLINK" => Command::Unlink(keys),
+                    "EXISTS" => Command::Exists(keys),
+                    _ => Command::Touch(keys),
+                })
+            }
+
+            "TYPE" => match args {
+                [Value::String(key)] => Ok(Command::Type(key.clone())),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "RENAME" | "RENAMENX" => match args {
+                [Value::String(source), Value::String(destination)] => {
+                    let (source, destination) = (source.clone(), destination.clone());
+                    Ok(if upper == "RENAME" {
+                        Command::Rename(source, destination)
+                    } else {
+                        Command::RenameNx(source, destination)
+                    })
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "COPY" => match args {
+                [Value::String(source), Value::String(destination), options @ ..] => {
+                    let mut command = CopyCommand {
+                        source: source.clone(),
+                        destination: destination.clone(),
+                        db: None,
+                        replace: false,
+                    };
+                    let mut options = options.iter();
+                    while let Some(option) = options.next() {
+                        match option.to_string().to_uppercase().as_str() {
+                            "REPLACE" => command.replace = true,
+                            "DB" => match options.next() {
+                                Some(Value::String(index)) => {
+                                    command.db = Some(parse_db_index(index)?)
+                                }
+                                _ => return Err(CommandError::Syntax),
+                            },
+                            _ => return Err(CommandError::Syntax),
+                        }
                     }
+                    Ok(Command::Copy(command))
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "SCAN" => match args {
+                [Value::String(cursor), options @ ..] => parse_scan(cursor, options, true)
+                    .map(|(scan, type_name)| Command::Scan(scan, type_name)),
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "HSCAN" | "SSCAN" | "ZSCAN" => match args {
+                [Value::String(key), Value::String(cursor), options @ ..] => {
+                    let (scan, _) = parse_scan(cursor, options, false)?;
+                    let key = key.clone();
+                    Ok(match upper.as_str() {
+                        "HSCAN" => Command::HScan(key, scan),
+                        "SSCAN" => Command::SScan(key, scan),
+                        _ => Command::ZScan(key, scan),
+                    })
                 }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "RANDOMKEY" => {
+                if !args.is_empty() {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                Ok(Command::RandomKey)
             }
 
-            _ => {
-                eprintln!(
-                    "Unknown command '{}'; expecting PING, ECHO, SET, GET, CONFIG, KEYS",
-                    name
-                );
-                None
+            "DBSIZE" => {
+                if !args.is_empty() {
+                    return Err(CommandError::WrongArity(name.to_lowercase()));
+                }
+                Ok(Command::DbSize)
             }
+
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                parse_expire(&upper, args).map(Command::Expire)
+            }
+
+            "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" | "PERSIST" => match args {
+                [Value::String(key)] => {
+                    let key = key.clone();
+                    Ok(match upper.as_str() {
+                        "TTL" => Command::Ttl(key, TimeUnit::Seconds),
+                        "PTTL" => Command::Ttl(key, TimeUnit::Milliseconds),
+                        "EXPIRETIME" => Command::ExpireTime(key, TimeUnit::Seconds),
+                        "PEXPIRETIME" => Command::ExpireTime(key, TimeUnit::Milliseconds),
+                        _ => Command::Persist(key),
+                    })
+                }
+                _ => Err(CommandError::WrongArity(name.to_lowercase())),
+            },
+
+            "INFO" => Ok(Command::Info(
+                args.iter()
+                    .map(|arg| arg.to_string().to_lowercase())
+                    .collect(),
+            )),
+
+            _ => Err(CommandError::UnknownCommand(name.to_string())),
         }
     }
 
-    pub fn handle_command(value: &[Value]) -> Option<Command> {
+    /// Returns the arguments to log in the append-only file for commands that
+    /// modify the keyspace, or `None` for read-only commands. Relative
+    /// expiries are rewritten as absolute ones so a replay does not extend them.
+    pub fn to_aof_args(&self) -> Option<Vec<Bytes>> {
+        match self {
+            Command::Set(set) | Command::SetNx(set) => {
+                let mut args = vec![
+                    Bytes::from_static(b"SET"),
+                    set.key.clone(),
+                    set.value.clone(),
+                ];
+                if let Some(expires_at) = set.expires_at {
+                    args.push(Bytes::from_static(b"PXAT"));
+                    args.push(Bytes::from(expires_at.to_string()));
+                }
+                if set.keep_ttl {
+                    args.push(Bytes::from_static(b"KEEPTTL"));
+                }
+                match set.condition {
+                    Some(SetCondition::Nx) => args.push(Bytes::from_static(b"NX")),
+                    Some(SetCondition::Xx) => args.push(Bytes::from_static(b"XX")),
+                    None => {}
+                }
+                Some(args)
+            }
+            Command::GetDel(key) => Some(vec![Bytes::from_static(b"GETDEL"), key.clone()]),
+            Command::SetBit(key, offset, bit) => Some(vec![
+                Bytes::from_static(b"SETBIT"),
+                key.clone(),
+                Bytes::from(offset.to_string()),
+                Bytes::from_static(if *bit { b"1" } else { b"0" }),
+            ]),
+            Command::BitOp(operation, destination, sources) => {
+                let operation: &'static [u8] = match operation {
+                    BitOperation::And => b"AND",
+                    BitOperation::Or => b"OR",
+                    BitOperation::Xor => b"XOR",
+                    BitOperation::Not => b"NOT",
+                };
+                let mut args = vec![
+                    Bytes::from_static(b"BITOP"),
+                    Bytes::from_static(operation),
+                    destination.clone(),
+                ];
+                args.extend(sources.iter().cloned());
+                Some(args)
+            }
+            // Only the writes matter for replay, but an `OVERFLOW` changes
+            // the ones after it, so those are kept too.
+            Command::BitField(key, operations)
+                if operations.iter().any(BitfieldOperation::is_write) =>
+            {
+                let mut args = vec![Bytes::from_static(b"BITFIELD"), key.clone()];
+                for operation in operations {
+                    let operation_args = match operation {
+                        BitfieldOperation::Get(..) => continue,
+                        BitfieldOperation::Set(field, offset, value) => [
+                            "SET",
+                            &field.to_string(),
+                            &offset.to_string(),
+                            &value.to_string(),
+                        ]
+                        .map(str::to_string)
+                        .to_vec(),
+                        BitfieldOperation::IncrBy(field, offset, increment) => [
+                            "INCRBY",
+                            &field.to_string(),
+                            &offset.to_string(),
+                            &increment.to_string(),
+                        ]
+                        .map(str::to_string)
+                        .to_vec(),
+                        BitfieldOperation::Overflow(overflow) => {
+                            vec!["OVERFLOW".to_string(), overflow.to_string()]
+                        }
+                    };
+                    args.extend(operation_args.into_iter().map(Bytes::from));
+                }
+                Some(args)
+            }
+            Command::Append(key, value) => Some(vec![
+                Bytes::from_static(b"APPEND"),
+                key.clone(),
+                value.clone(),
+            ]),
+            Command::SetRange(key, offset, value) => Some(vec![
+                Bytes::from_static(b"SETRANGE"),
+                key.clone(),
+                Bytes::from(offset.to_string()),
+                value.clone(),
+            ]),
+            Command::MSet(pairs) | Command::MSetNx(pairs) => {
+                let name: &'static [u8] = if matches!(self, Command::MSet(_)) {
+                    b"MSET"
+                } else {
+                    b"MSETNX"
+                };
+                let mut args = vec![Bytes::from_static(name)];
+                for (key, value) in pairs {
+                    args.push(key.clone());
+                    args.push(value.clone());
+                }
+                Some(args)
+            }
+            Command::IncrBy(key, delta) => Some(vec![
+                Bytes::from_static(b"INCRBY"),
+                key.clone(),
+                Bytes::from(delta.to_string()),
+            ]),
+            // Replaying the addition is deterministic here, so unlike Redis
+            // the increment is logged rather than the resulting value.
+            Command::IncrByFloat(key, delta) => Some(vec![
+                Bytes::from_static(b"INCRBYFLOAT"),
+                key.clone(),
+                Bytes::from(delta.to_string()),
+            ]),
+            Command::GetEx(key, Some(GetExExpiry::At(expires_at))) => Some(vec![
+                Bytes::from_static(b"PEXPIREAT"),
+                key.clone(),
+                Bytes::from(expires_at.to_string()),
+            ]),
+            Command::GetEx(key, Some(GetExExpiry::Persist)) => {
+                Some(vec![Bytes::from_static(b"PERSIST"), key.clone()])
+            }
+            Command::Move(key, index) => Some(vec![
+                Bytes::from_static(b"MOVE"),
+                key.clone(),
+                Bytes::from(index.to_string()),
+            ]),
+            Command::SwapDb(first, second) => Some(vec![
+                Bytes::from_static(b"SWAPDB"),
+                Bytes::from(first.to_string()),
+                Bytes::from(second.to_string()),
+            ]),
+            Command::Expire(ExpireCommand {
+                key,
+                expires_at,
+                flags,
+            }) => {
+                let mut args = vec![
+                    Bytes::from_static(b"PEXPIREAT"),
+                    key.clone(),
+                    Bytes::from(expires_at.to_string()),
+                ];
+                args.extend(flags.to_args());
+                Some(args)
+            }
+            Command::Persist(key) => Some(vec![Bytes::from_static(b"PERSIST"), key.clone()]),
+            Command::Del(keys) | Command::Unlink(keys) => {
+                let name: &'static [u8] = if matches!(self, Command::Del(_)) {
+                    b"DEL"
+                } else {
+                    b"UNLINK"
+                };
+                let mut args = vec![Bytes::from_static(name)];
+                args.extend(keys.iter().cloned());
+                Some(args)
+            }
+            Command::Rename(source, destination) => Some(vec![
+                Bytes::from_static(b"RENAME"),
+                source.clone(),
+                destination.clone(),
+            ]),
+            Command::RenameNx(source, destination) => Some(vec![
+                Bytes::from_static(b"RENAMENX"),
+                source.clone(),
+                destination.clone(),
+            ]),
+            Command::Copy(copy) => {
+                let mut args = vec![
+                    Bytes::from_static(b"COPY"),
+                    copy.source.clone(),
+                    copy.destination.clone(),
+                ];
+                if let Some(db) = copy.db {
+                    args.push(Bytes::from_static(b"DB"));
+                    args.push(Bytes::from(db.to_string()));
+                }
+                if copy.replace {
+                    args.push(Bytes::from_static(b"REPLACE"));
+                }
+                Some(args)
+            }
+            Command::Push(end, key, elements) | Command::PushX(end, key, elements) => {
+                let name = match (end, matches!(self, Command::PushX(..))) {
+                    (ListEnd::Left, false) => "LPUSH",
+                    (ListEnd::Right, false) => "RPUSH",
+                    (ListEnd::Left, true) => "LPUSHX",
+                    (ListEnd::Right, true) => "RPUSHX",
+                };
+                let mut args = vec![Bytes::from_static(name.as_bytes()), key.clone()];
+                args.extend(elements.iter().cloned());
+                Some(args)
+            }
+            Command::Pop(end, key, count) => {
+                let name: &'static [u8] = match end {
+                    ListEnd::Left => b"LPOP",
+                    ListEnd::Right => b"RPOP",
+                };
+                let mut args = vec![Bytes::from_static(name), key.clone()];
+                if let Some(count) = count {
+                    args.push(Bytes::from(count.to_string()));
+                }
+                Some(args)
+            }
+            Command::LMPop(keys, end, count) => {
+                let mut args = vec![
+                    Bytes::from_static(b"LMPOP"),
+                    Bytes::from(keys.len().to_string()),
+                ];
+                args.extend(keys.iter().cloned());
+                args.push(Bytes::from_static(end.name().as_bytes()));
+                args.push(Bytes::from_static(b"COUNT"));
+                args.push(Bytes::from(count.to_string()));
+                Some(args)
+            }
+            Command::LSet(key, index, element) => Some(vec![
+                Bytes::from_static(b"LSET"),
+                key.clone(),
+                Bytes::from(index.to_string()),
+                element.clone(),
+            ]),
+            Command::LRem(key, count, element) => Some(vec![
+                Bytes::from_static(b"LREM"),
+                key.clone(),
+                Bytes::from(count.to_string()),
+                element.clone(),
+            ]),
+            Command::LTrim(key, start, end) => Some(vec![
+                Bytes::from_static(b"LTRIM"),
+                key.clone(),
+                Bytes::from(start.to_string()),
+                Bytes::from(end.to_string()),
+            ]),
+            Command::LInsert(key, before, pivot, element) => Some(vec![
+                Bytes::from_static(b"LINSERT"),
+                key.clone(),
+                Bytes::from_static(if *before { b"BEFORE" } else { b"AFTER" }),
+                pivot.clone(),
+                element.clone(),
+            ]),
+            Command::LMove(source, destination, from, to) => Some(vec![
+                Bytes::from_static(b"LMOVE"),
+                source.clone(),
+                destination.clone(),
+                Bytes::from_static(from.name().as_bytes()),
+                Bytes::from_static(to.name().as_bytes()),
+            ]),
+            Command::FlushDb => Some(vec![Bytes::from_static(b"FLUSHDB")]),
+            Command::FlushAll => Some(vec![Bytes::from_static(b"FLUSHALL")]),
+            _ => None,
+        }
+    }
+
+    pub fn handle_command(value: &[Value]) -> Result<Command, CommandError> {
         let command_name = &value[0];
         let command_args = &value[1..];
         match command_name {
-            Value::String(name) => Command::process(name, command_args),
+            Value::String(name) => Command::process(&String::from_utf8_lossy(name), command_args),
+            _ => Err(CommandError::Syntax),
+        }
+    }
+}
+
+/// Parses `EXPIRE`, `PEXPIRE`, `EXPIREAT` and `PEXPIREAT`, turning the time
+/// into absolute unix milliseconds.
+fn parse_expire(name: &str, args: &[Value]) -> Result<ExpireCommand, CommandError> {
+    let [Value::String(key), Value::String(time), options @ ..] = args else {
+        return Err(CommandError::WrongArity(name.to_lowercase()));
+    };
+    let mut flags = ExpireFlags::default();
+    for option in options {
+        match option.to_string().to_uppercase().as_str() {
+            "NX" => flags.nx = true,
+            "XX" => flags.xx = true,
+            "GT" => flags.gt = true,
+            "LT" => flags.lt = true,
+            _ => return Err(CommandError::UnsupportedOption(option.to_string())),
+        }
+    }
+    if flags.nx && (flags.xx || flags.gt || flags.lt) {
+        return Err(CommandError::Message(
+            "NX and XX, GT or LT options at the same time are not compatible",
+        ));
+    }
+    if flags.gt && flags.lt {
+        return Err(CommandError::Message(
+            "GT and LT options at the same time are not compatible",
+        ));
+    }
+
+    let time = parse_integer::<i64>(time).ok_or(CommandError::NotInteger)?;
+    let millis = if name.starts_with('P') {
+        Some(time)
+    } else {
+        time.checked_mul(1000)
+    };
+    let expires_at = if name.ends_with("AT") {
+        millis
+    } else {
+        millis.and_then(|millis| millis.checked_add(unix_time_millis() as i64))
+    };
+    let expires_at =
+        expires_at.ok_or_else(|| CommandError::InvalidExpireTime(name.to_lowercase()))?;
+    Ok(ExpireCommand {
+        key: key.clone(),
+        expires_at,
+        flags,
+    })
+}
+
+/// Parses the options of `SET key value`: `NX|XX`, `GET` and one of
+/// `EX|PX|EXAT|PXAT|KEEPTTL`, in any order.
+fn parse_set(key: &Bytes, value: &Bytes, options: &[Value]) -> Result<SetCommand, CommandError> {
+    let mut command = SetCommand::new(key.clone(), value.clone(), None);
+    let mut expiry_given = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_string().to_uppercase().as_str() {
+            "NX" | "XX" if command.condition.is_some() => return Err(CommandError::Syntax),
+            "NX" => command.condition = Some(SetCondition::Nx),
+            "XX" => command.condition = Some(SetCondition::Xx),
+            "GET" => command.get = true,
+            "KEEPTTL" if !expiry_given => {
+                expiry_given = true;
+                command.keep_ttl = true;
+            }
+            unit @ ("EX" | "PX" | "EXAT" | "PXAT") if !expiry_given => {
+                expiry_given = true;
+                let Some(Value::String(time)) = options.next() else {
+                    return Err(CommandError::Syntax);
+                };
+                command.expires_at = Some(parse_expiry_option(unit, time, "set")?);
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(command)
+}
+
+/// Default `COUNT` of the scan commands, as in Redis.
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+/// Parses the cursor and options of a scan command; `TYPE` is only
+/// accepted by `SCAN` itself.
+fn parse_scan(
+    cursor: &[u8],
+    options: &[Value],
+    allow_type: bool,
+) -> Result<(ScanOptions, Option<String>), CommandError> {
+    let mut scan = ScanOptions {
+        cursor: parse_integer(cursor).ok_or(CommandError::InvalidCursor)?,
+        pattern: None,
+        count: SCAN_DEFAULT_COUNT,
+    };
+    let mut type_name = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = option.to_string().to_uppercase();
+        let Some(Value::String(argument)) = options.next() else {
+            return Err(CommandError::Syntax);
+        };
+        match option.as_str() {
+            "MATCH" => scan.pattern = Some(argument.clone()),
+            "COUNT" => {
+                let count = parse_integer::<i64>(argument).ok_or(CommandError::NotInteger)?;
+                if count < 1 {
+                    return Err(CommandError::Syntax);
+                }
+                scan.count = count as usize;
+            }
+            "TYPE" if allow_type => {
+                let name = String::from_utf8_lossy(argument).to_lowercase();
+                if !matches!(
+                    name.as_str(),
+                    "string" | "list" | "set" | "zset" | "hash" | "stream"
+                ) {
+                    return Err(CommandError::UnknownType(name));
+                }
+                type_name = Some(name);
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok((scan, type_name))
+}
+
+/// Parses a bit offset of `SETBIT`, `GETBIT` or `BITFIELD`, which must
+/// address a bit within a string of the maximum size.
+fn parse_bit_offset(bytes: &[u8]) -> Result<u64, CommandError> {
+    parse_integer::<u64>(bytes)
+        .filter(|&offset| offset < MAX_BIT_OFFSET)
+        .ok_or(CommandError::BitOffset)
+}
+
+/// Parses the optional `BYTE|BIT` unit of `BITCOUNT` and `BITPOS`.
+fn parse_range_unit(unit: Option<&Value>) -> Result<RangeUnit, CommandError> {
+    match unit {
+        None => Ok(RangeUnit::Byte),
+        Some(unit) => match unit.to_string().to_uppercase().as_str() {
+            "BYTE" => Ok(RangeUnit::Byte),
+            "BIT" => Ok(RangeUnit::Bit),
+            _ => Err(CommandError::Syntax),
+        },
+    }
+}
+
+/// Parses the subcommands of `BITFIELD`. Offsets prefixed with `#` are
+/// multiplied by the width of the type.
+fn parse_bitfield(args: &[Value]) -> Result<Vec<BitfieldOperation>, CommandError> {
+    let mut operations = Vec::new();
+    let mut args = args.iter().map(bytes_argument);
+    while let Some(subcommand) = args.next() {
+        let subcommand = subcommand.to_ascii_uppercase();
+        if subcommand == b"OVERFLOW" {
+            let overflow = args.next().ok_or(CommandError::Syntax)?;
+            let overflow = match overflow.to_ascii_uppercase().as_slice() {
+                b"WRAP" => Overflow::Wrap,
+                b"SAT" => Overflow::Sat,
+                b"FAIL" => Overflow::Fail,
+                _ => return Err(CommandError::Message("Invalid OVERFLOW type specified")),
+            };
+            operations.push(BitfieldOperation::Overflow(overflow));
+            continue;
+        }
+        let arity = match subcommand.as_slice() {
+            b"GET" => 2,
+            b"SET" | b"INCRBY" => 3,
+            _ => return Err(CommandError::Syntax),
+        };
+        let operands = args.by_ref().take(arity).collect::<Vec<_>>();
+        if operands.len() < arity {
+            return Err(CommandError::Syntax);
+        }
+        let field = BitfieldType::parse(&operands[0]).ok_or(CommandError::BitfieldType)?;
+        let offset = match operands[1].strip_prefix(b"#") {
+            Some(index) => parse_integer::<u64>(index)
+                .and_then(|index| index.checked_mul(field.bits as u64))
+                .ok_or(CommandError::BitOffset)?,
+            None => parse_integer::<u64>(&operands[1]).ok_or(CommandError::BitOffset)?,
+        };
+        if offset.saturating_add(field.bits as u64) > MAX_BIT_OFFSET {
+            return Err(CommandError::BitOffset);
+        }
+        operations.push(match subcommand.as_slice() {
+            b"GET" => BitfieldOperation::Get(field, offset),
             _ => {
-                eprintln!("Unexpected command: {:?}", command_name);
-                None
+                let integer = parse_integer(&operands[2]).ok_or(CommandError::NotInteger)?;
+                if subcommand == b"SET" {
+                    BitfieldOperation::Set(field, offset, integer)
+                } else {
+                    BitfieldOperation::IncrBy(field, offset, integer)
+                }
+            }
+        });
+    }
+    Ok(operations)
+}
+
+/// Parses the options of `LCS key1 key2`: `LEN`, `IDX`, `MINMATCHLEN len`
+/// and `WITHMATCHLEN`.
+fn parse_lcs(a: &Bytes, b: &Bytes, options: &[Value]) -> Result<LcsCommand, CommandError> {
+    let mut command = LcsCommand {
+        keys: (a.clone(), b.clone()),
+        len: false,
+        idx: false,
+        min_match_len: 0,
+        with_match_len: false,
+    };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_string().to_uppercase().as_str() {
+            "LEN" => command.len = true,
+            "IDX" => command.idx = true,
+            "WITHMATCHLEN" => command.with_match_len = true,
+            "MINMATCHLEN" => {
+                let Some(Value::String(len)) = options.next() else {
+                    return Err(CommandError::Syntax);
+                };
+                let len = parse_integer::<i64>(len).ok_or(CommandError::NotInteger)?;
+                command.min_match_len = len.max(0) as usize;
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    if command.len && command.idx {
+        return Err(CommandError::Message(
+            "If you want both the length and indexes, please just use IDX.",
+        ));
+    }
+    Ok(command)
+}
+
+/// Parses the options of `GETEX key`: at most one of
+/// `EX|PX|EXAT|PXAT|PERSIST`.
+fn parse_getex_options(options: &[Value]) -> Result<Option<GetExExpiry>, CommandError> {
+    match options {
+        [] => Ok(None),
+        [option] if option.to_string().eq_ignore_ascii_case("PERSIST") => {
+            Ok(Some(GetExExpiry::Persist))
+        }
+        [option, Value::String(time)] => {
+            let unit = option.to_string().to_uppercase();
+            if !matches!(unit.as_str(), "EX" | "PX" | "EXAT" | "PXAT") {
+                return Err(CommandError::Syntax);
+            }
+            parse_expiry_option(&unit, time, "getex").map(|at| Some(GetExExpiry::At(at)))
+        }
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+/// Converts the argument of an `EX`, `PX`, `EXAT` or `PXAT` option into
+/// absolute unix milliseconds. Like Redis, the time must be positive.
+fn parse_expiry_option(unit: &str, time: &[u8], command: &str) -> Result<u64, CommandError> {
+    if unit == "EX" || unit == "PX" {
+        return parse_relative_expiry(time, unit == "EX", command);
+    }
+    let time = parse_integer::<i64>(time).ok_or(CommandError::NotInteger)?;
+    let millis = if unit == "EXAT" {
+        time.checked_mul(1000)
+    } else {
+        Some(time)
+    };
+    millis
+        .filter(|&millis| time > 0 && millis > 0)
+        .map(|millis| millis as u64)
+        .ok_or_else(|| CommandError::InvalidExpireTime(command.to_string()))
+}
+
+/// Converts a positive time to live into absolute unix milliseconds.
+fn parse_relative_expiry(time: &[u8], seconds: bool, command: &str) -> Result<u64, CommandError> {
+    let time = parse_integer::<i64>(time).ok_or(CommandError::NotInteger)?;
+    let millis = if seconds {
+        time.checked_mul(1000)
+    } else {
+        Some(time)
+    };
+    millis
+        .filter(|&millis| millis > 0)
+        .and_then(|millis| (millis as u64).checked_add(unix_time_millis()))
+        .ok_or_else(|| CommandError::InvalidExpireTime(command.to_string()))
+}
+
+/// Parses the count of `LPOP` and `RPOP`.
+fn parse_positive_count(bytes: &[u8]) -> Result<usize, CommandError> {
+    let count = parse_integer::<i64>(bytes).ok_or(CommandError::NotInteger)?;
+    usize::try_from(count)
+        .map_err(|_| CommandError::Message("value is out of range, must be positive"))
+}
+
+/// Parses what follows `numkeys` in `LMPOP`: the keys, the end to pop from
+/// and the optional `COUNT`, which defaults to 1.
+fn parse_lmpop(
+    numkeys: &[u8],
+    rest: &[Value],
+) -> Result<(Vec<Bytes>, ListEnd, usize), CommandError> {
+    let numkeys = parse_integer::<i64>(numkeys).ok_or(CommandError::NotInteger)?;
+    if numkeys <= 0 {
+        return Err(CommandError::Message("numkeys should be greater than 0"));
+    }
+    let numkeys = usize::try_from(numkeys).unwrap_or(usize::MAX);
+    if numkeys >= rest.len() {
+        return Err(CommandError::Syntax);
+    }
+    let (keys, options) = rest.split_at(numkeys);
+    let keys = keys.iter().map(bytes_argument).collect();
+    let (end, count) = match options {
+        [Value::String(end)] => (ListEnd::parse(end), 1),
+        [Value::String(end), Value::String(option), Value::String(count)]
+            if option.eq_ignore_ascii_case(b"COUNT") =>
+        {
+            let count = parse_integer::<i64>(count).ok_or(CommandError::NotInteger)?;
+            if count <= 0 {
+                return Err(CommandError::Message("count should be greater than 0"));
+            }
+            (ListEnd::parse(end), count as usize)
+        }
+        _ => return Err(CommandError::Syntax),
+    };
+    Ok((keys, end.ok_or(CommandError::Syntax)?, count))
+}
+
+/// Parses the timeout of a blocking command, in seconds with decimals; 0
+/// blocks forever.
+fn parse_timeout(bytes: &[u8]) -> Result<Option<Duration>, CommandError> {
+    let seconds = parse_f64(bytes)
+        .filter(|seconds| seconds.is_finite())
+        .ok_or(CommandError::Message(
+            "timeout is not a float or out of range",
+        ))?;
+    if seconds < 0.0 {
+        return Err(CommandError::Message("timeout is negative"));
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| CommandError::Message("timeout is out of range"))
+}
+
+/// Parses the `RANK`, `COUNT` and `MAXLEN` options of `LPOS`.
+fn parse_lpos(
+    key: &Bytes,
+    element: &Bytes,
+    options: &[Value],
+) -> Result<LPosCommand, CommandError> {
+    let mut command = LPosCommand {
+        key: key.clone(),
+        element: element.clone(),
+        rank: 1,
+        count: None,
+        max_len: 0,
+    };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let Some(Value::String(value)) = options.next() else {
+            return Err(CommandError::Syntax);
+        };
+        let value = parse_integer::<i64>(value).ok_or(CommandError::NotInteger)?;
+        match option.to_string().to_uppercase().as_str() {
+            "RANK" if value == 0 => {
+                return Err(CommandError::Message(
+                    "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list",
+                ))
+            }
+            "RANK" if value == i64::MIN => return Err(CommandError::NotInteger),
+            "RANK" => command.rank = value,
+            "COUNT" => {
+                let count = usize::try_from(value)
+                    .map_err(|_| CommandError::Message("COUNT can't be negative"))?;
+                command.count = Some(count);
+            }
+            "MAXLEN" => {
+                command.max_len = usize::try_from(value)
+                    .map_err(|_| CommandError::Message("MAXLEN can't be negative"))?;
             }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(command)
+}
+
+fn flush_command(name: &str) -> Command {
+    if name.eq_ignore_ascii_case("FLUSHALL") {
+        Command::FlushAll
+    } else {
+        Command::FlushDb
+    }
+}
+
+fn parse_db_index(bytes: &[u8]) -> Result<usize, CommandError> {
+    match parse_integer::<i64>(bytes) {
+        Some(index) => usize::try_from(index).map_err(|_| CommandError::DbIndexOutOfRange),
+        None => Err(CommandError::NotInteger),
+    }
+}
+
+/// The bytes of an argument; requests only ever carry bulk strings.
+fn bytes_argument(value: &Value) -> Bytes {
+    match value {
+        Value::String(bytes) => bytes.clone(),
+        other => Bytes::from(other.to_string()),
+    }
+}
+
+fn parse_integer<T: std::str::FromStr>(bytes: &[u8]) -> Option<T> {
+    std::str::from_utf8(bytes).ok()?.parse::<T>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(command: &str) -> Result<Command, CommandError> {
+        let mut words = command.split(' ');
+        let name = words.next().unwrap();
+        let args = words
+            .map(|word| Value::String(Bytes::copy_from_slice(word.as_bytes())))
+            .collect::<Vec<_>>();
+        Command::process(name, &args)
+    }
+
+    fn set(command: &str) -> SetCommand {
+        match process(command) {
+            Ok(Command::Set(set)) => set,
+            Ok(_) => panic!("{} is not a SET", command),
+            Err(e) => panic!("{}: {}", command, e),
+        }
+    }
+
+    fn aof_args(command: &str) -> Vec<String> {
+        let Ok(command) = process(command) else {
+            panic!("{} does not parse", command);
+        };
+        command
+            .to_aof_args()
+            .unwrap()
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn set_options() {
+        let plain = set("SET key value");
+        assert_eq!(
+            (plain.key.as_ref(), plain.value.as_ref()),
+            (&b"key"[..], &b"value"[..])
+        );
+        assert_eq!(plain.condition, None);
+        assert!(!plain.get && !plain.keep_ttl && plain.expires_at.is_none());
+
+        assert_eq!(set("SET key value NX").condition, Some(SetCondition::Nx));
+        assert_eq!(set("SET key value xx").condition, Some(SetCondition::Xx));
+        let all = set("SET key value get KEEPTTL XX");
+        assert!(all.get && all.keep_ttl);
+        assert_eq!(all.condition, Some(SetCondition::Xx));
+
+        let before = unix_time_millis();
+        let expires_at = set("SET key value EX 10").expires_at.unwrap();
+        assert!((before + 10_000..=unix_time_millis() + 10_000).contains(&expires_at));
+        let expires_at = set("SET key value px 1500 NX").expires_at.unwrap();
+        assert!((before + 1500..=unix_time_millis() + 1500).contains(&expires_at));
+        assert_eq!(
+            set("SET key value EXAT 1700000000").expires_at,
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(
+            set("SET key value GET PXAT 1700000000123").expires_at,
+            Some(1_700_000_000_123)
+        );
+    }
+
+    #[test]
+    fn conflicting_set_options_are_rejected() {
+        for command in [
+            "SET key value NX XX",
+            "SET key value XX NX",
+            "SET key value EX 10 PX 10000",
+            "SET key value PX 10 EXAT 1700000000",
+            "SET key value EX 10 KEEPTTL",
+            "SET key value KEEPTTL PXAT 1700000000000",
+            "SET key value KEEPTTL KEEPTTL",
+            "SET key value EX",
+            "SET key value NX EX",
+            "SET key value PERSIST",
+        ] {
+            assert!(
+                matches!(process(command), Err(CommandError::Syntax)),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn set_expiries_must_be_positive() {
+        for command in [
+            "SET key value EX 0",
+            "SET key value EX -1",
+            "SET key value PX 0",
+            "SET key value PX -100",
+            "SET key value EXAT 0",
+            "SET key value PXAT -1",
+            "SET key value EX 9223372036854775807",
+            "SET key value EXAT 9223372036854775807",
+        ] {
+            match process(command) {
+                Err(CommandError::InvalidExpireTime(name)) => assert_eq!(name, "set"),
+                _ => panic!("{} is accepted", command),
+            }
+        }
+        for command in ["SET key value EX ten", "SET key value PX 1.5"] {
+            assert!(
+                matches!(process(command), Err(CommandError::NotInteger)),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn set_is_logged_with_an_absolute_expiry() {
+        assert_eq!(aof_args("SET key value"), ["SET", "key", "value"]);
+        let logged = aof_args("SET key value EX 10 NX GET");
+        assert_eq!(logged[..4], ["SET", "key", "value", "PXAT"]);
+        let expires_at = logged[4].parse::<u64>().unwrap();
+        assert!(expires_at > unix_time_millis() + 9_000);
+        assert_eq!(logged[5..], ["NX"]);
+        assert_eq!(
+            aof_args("SET key value EXAT 1700000000 XX"),
+            ["SET", "key", "value", "PXAT", "1700000000000", "XX"]
+        );
+        assert_eq!(
+            aof_args("SET key value KEEPTTL GET"),
+            ["SET", "key", "value", "KEEPTTL"]
+        );
+    }
+
+    #[test]
+    fn increments_take_integer_and_float_arguments() {
+        assert!(matches!(process("INCR key"), Ok(Command::IncrBy(_, 1))));
+        assert!(matches!(process("DECR key"), Ok(Command::IncrBy(_, -1))));
+        assert!(matches!(
+            process("INCRBY key -5"),
+            Ok(Command::IncrBy(_, -5))
+        ));
+        assert!(matches!(
+            process("DECRBY key -5"),
+            Ok(Command::IncrBy(_, 5))
+        ));
+        assert!(matches!(
+            process("DECRBY key 9223372036854775807"),
+            Ok(Command::IncrBy(_, -9223372036854775807))
+        ));
+        // Its negation does not fit.
+        assert!(matches!(
+            process("DECRBY key -9223372036854775808"),
+            Err(CommandError::DecrementOverflow)
+        ));
+        for command in [
+            "INCRBY key 1.5",
+            "INCRBY key abc",
+            "INCRBY key 9223372036854775808",
+            "DECRBY key +1",
+            "INCRBY key 01",
+        ] {
+            assert!(
+                matches!(process(command), Err(CommandError::NotInteger)),
+                "{}",
+                command
+            );
+        }
+
+        assert!(matches!(
+            process("INCRBYFLOAT key 2.5e3"),
+            Ok(Command::IncrByFloat(_, delta)) if delta == 2500.0
+        ));
+        for command in [
+            "INCRBYFLOAT key abc",
+            "INCRBYFLOAT key nan",
+            "INCRBYFLOAT key 1,5",
+        ] {
+            assert!(
+                matches!(process(command), Err(CommandError::NotFloat)),
+                "{}",
+                command
+            );
         }
     }
 }
@@ -0,0 +1,320 @@
+//! Decoders for the compact encodings Redis uses to store small collections
+//! in a single RDB string: ziplists, listpacks, intsets and zipmaps. Integer
+//! entries are returned in their decimal string form, as Redis does.
+
+use bytes::Bytes;
+
+use crate::parser::RDBError;
+
+const ZIPLIST_HEADER_SIZE: usize = 10;
+const ZIPLIST_END: u8 = 0xFF;
+const LISTPACK_HEADER_SIZE: usize = 6;
+const LISTPACK_END: u8 = 0xFF;
+const ZIPMAP_BIGLEN: u8 = 0xFE;
+const ZIPMAP_END: u8 = 0xFF;
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    encoding: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8], pos: usize, encoding: &'static str) -> Self {
+        Self { buf, pos, encoding }
+    }
+
+    fn error(&self) -> RDBError {
+        RDBError::InvalidCompactEncoding(self.encoding)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], RDBError> {
+        let end = self.pos.checked_add(length).ok_or_else(|| self.error())?;
+        let bytes = self.buf.get(self.pos..end).ok_or_else(|| self.error())?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RDBError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, RDBError> {
+        self.buf.get(self.pos).copied().ok_or_else(|| self.error())
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RDBError> {
+        let mut buf = [0u8; N];
+        buf.copy_from_slice(self.take(N)?);
+        Ok(buf)
+    }
+}
+
+fn integer(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+/// Sign-extends the low `bits` bits of `value`.
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+/// Decodes a ziplist: a header of total bytes, tail offset and entry count,
+/// then entries made of the previous entry's length, an encoding byte and the
+/// data, terminated by `0xFF`.
+pub fn decode_ziplist(buf: &[u8]) -> Result<Vec<Bytes>, RDBError> {
+    let mut cursor = Cursor::new(buf, ZIPLIST_HEADER_SIZE, "ziplist");
+    let mut entries = Vec::new();
+    loop {
+        if cursor.peek()? == ZIPLIST_END {
+            return Ok(entries);
+        }
+        if cursor.byte()? == 0xFE {
+            cursor.take(4)?;
+        }
+
+        let encoding = cursor.byte()?;
+        let entry = match encoding >> 6 {
+            0b00 => {
+                let length = (encoding & 0x3F) as usize;
+                Bytes::copy_from_slice(cursor.take(length)?)
+            }
+            0b01 => {
+                let length = (((encoding & 0x3F) as usize) << 8) | cursor.byte()? as usize;
+                Bytes::copy_from_slice(cursor.take(length)?)
+            }
+            0b10 => {
+                let length = u32::from_be_bytes(cursor.array::<4>()?) as usize;
+                Bytes::copy_from_slice(cursor.take(length)?)
+            }
+            _ => match encoding {
+                0xC0 => integer(i16::from_le_bytes(cursor.array::<2>()?) as i64),
+                0xD0 => integer(i32::from_le_bytes(cursor.array::<4>()?) as i64),
+                0xE0 => integer(i64::from_le_bytes(cursor.array::<8>()?)),
+                0xF0 => {
+                    let [a, b, c] = cursor.array::<3>()?;
+                    integer(sign_extend(u32::from_le_bytes([a, b, c, 0]) as u64, 24))
+                }
+                0xFE => integer(cursor.byte()? as i8 as i64),
+                0xF1..=0xFD => integer((encoding & 0x0F) as i64 - 1),
+                _ => return Err(cursor.error()),
+            },
+        };
+        entries.push(entry);
+    }
+}
+
+/// Decodes a listpack: a header of total bytes and element count, then
+/// entries made of an encoding byte, the data and a variable length
+/// back-pointer, terminated by `0xFF`.
+pub fn decode_listpack(buf: &[u8]) -> Result<Vec<Bytes>, RDBError> {
+    let mut cursor = Cursor::new(buf, LISTPACK_HEADER_SIZE, "listpack");
+    let mut entries = Vec::new();
+    loop {
+        let start = cursor.pos;
+        let encoding = cursor.byte()?;
+        let entry = if encoding == LISTPACK_END {
+            return Ok(entries);
+        } else if encoding & 0x80 == 0 {
+            integer((encoding & 0x7F) as i64)
+        } else if encoding & 0xC0 == 0x80 {
+            let length = (encoding & 0x3F) as usize;
+            Bytes::copy_from_slice(cursor.take(length)?)
+        } else if encoding & 0xE0 == 0xC0 {
+            let value = (((encoding & 0x1F) as u64) << 8) | cursor.byte()? as u64;
+            integer(sign_extend(value, 13))
+        } else if encoding & 0xF0 == 0xE0 {
+            let length = (((encoding & 0x0F) as usize) << 8) | cursor.byte()? as usize;
+            Bytes::copy_from_slice(cursor.take(length)?)
+        } else {
+            match encoding {
+                0xF0 => {
+                    let length = u32::from_le_bytes(cursor.array::<4>()?) as usize;
+                    Bytes::copy_from_slice(cursor.take(length)?)
+                }
+                0xF1 => integer(i16::from_le_bytes(cursor.array::<2>()?) as i64),
+                0xF2 => {
+                    let [a, b, c] = cursor.array::<3>()?;
+                    integer(sign_extend(u32::from_le_bytes([a, b, c, 0]) as u64, 24))
+                }
+                0xF3 => integer(i32::from_le_bytes(cursor.array::<4>()?) as i64),
+                0xF4 => integer(i64::from_le_bytes(cursor.array::<8>()?)),
+                _ => return Err(cursor.error()),
+            }
+        };
+
+        // Skip the back-pointer, whose size depends on the entry's length.
+        let entry_length = cursor.pos - start;
+        let backlen_size = match entry_length {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        cursor.take(backlen_size)?;
+        entries.push(entry);
+    }
+}
+
+/// Decodes an intset: the byte width of its integers, their count and the
+/// sorted little-endian integers themselves.
+pub fn decode_intset(buf: &[u8]) -> Result<Vec<Bytes>, RDBError> {
+    let mut cursor = Cursor::new(buf, 0, "intset");
+    let width = u32::from_le_bytes(cursor.array::<4>()?);
+    let length = u32::from_le_bytes(cursor.array::<4>()?);
+    (0..length)
+        .map(|_| match width {
+            2 => Ok(integer(i16::from_le_bytes(cursor.array::<2>()?) as i64)),
+            4 => Ok(integer(i32::from_le_bytes(cursor.array::<4>()?) as i64)),
+            8 => Ok(integer(i64::from_le_bytes(cursor.array::<8>()?))),
+            _ => Err(cursor.error()),
+        })
+        .collect()
+}
+
+/// Decodes a zipmap, the pre-ziplist hash encoding: an entry count byte,
+/// then key length, key, value length, free byte count, value and padding
+/// for every pair, terminated by `0xFF`. Returns keys and values alternately.
+pub fn decode_zipmap(buf: &[u8]) -> Result<Vec<Bytes>, RDBError> {
+    let mut cursor = Cursor::new(buf, 1, "zipmap");
+    let mut entries = Vec::new();
+    loop {
+        if cursor.peek()? == ZIPMAP_END {
+            return Ok(entries);
+        }
+        let key_length = zipmap_length(&mut cursor)?;
+        entries.push(Bytes::copy_from_slice(cursor.take(key_length)?));
+        let value_length = zipmap_length(&mut cursor)?;
+        let free = cursor.byte()? as usize;
+        entries.push(Bytes::copy_from_slice(cursor.take(value_length)?));
+        cursor.take(free)?;
+    }
+}
+
+/// Reads a zipmap length: a single byte up to 253, or `ZIPMAP_BIGLEN`
+/// followed by a little-endian 4 byte length.
+fn zipmap_length(cursor: &mut Cursor) -> Result<usize, RDBError> {
+    match cursor.byte()? {
+        length @ 0..=253 => Ok(length as usize),
+        ZIPMAP_BIGLEN => Ok(u32::from_le_bytes(cursor.array::<4>()?) as usize),
+        _ => Err(cursor.error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(entries: &[&str]) -> Vec<Bytes> {
+        entries
+            .iter()
+            .map(|entry| Bytes::copy_from_slice(entry.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn zipmap_single_byte_lengths() {
+        let key = "k".repeat(253);
+        let mut buf = vec![1, 253];
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&[1, 2, b'v', 0, 0]);
+        buf.push(ZIPMAP_END);
+        assert_eq!(decode_zipmap(&buf).unwrap(), strings(&[&key, "v"]));
+    }
+
+    #[test]
+    fn zipmap_big_lengths() {
+        let value = "v".repeat(300);
+        let mut buf = vec![1, 1, b'k', ZIPMAP_BIGLEN];
+        buf.extend_from_slice(&300u32.to_le_bytes());
+        buf.push(0);
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(ZIPMAP_END);
+        assert_eq!(decode_zipmap(&buf).unwrap(), strings(&["k", &value]));
+    }
+
+    #[test]
+    fn zipmap_end_is_not_a_length() {
+        // The key is followed by END where its value's length belongs.
+        let buf = [1, 1, b'k', ZIPMAP_END];
+        assert!(decode_zipmap(&buf).is_err());
+    }
+
+    #[test]
+    fn zipmap_truncated() {
+        assert!(decode_zipmap(&[1, ZIPMAP_BIGLEN, 1, 0]).is_err());
+        assert!(decode_zipmap(&[1, 5, b'k']).is_err());
+    }
+
+    #[test]
+    fn ziplist_integers() {
+        let mut buf = vec![0; ZIPLIST_HEADER_SIZE];
+        buf.extend_from_slice(&[0, 0xF1]);
+        buf.extend_from_slice(&[2, 0xFD]);
+        buf.extend_from_slice(&[2, 0xFE, 0x80]);
+        buf.extend_from_slice(&[2, 0xC0, 0x00, 0x80]);
+        buf.extend_from_slice(&[3, 0xF0, 0xFF, 0xFF, 0xFF]);
+        buf.extend_from_slice(&[4, 0x02, b'h', b'i']);
+        buf.push(ZIPLIST_END);
+        assert_eq!(
+            decode_ziplist(&buf).unwrap(),
+            strings(&["0", "12", "-128", "-32768", "-1", "hi"])
+        );
+    }
+
+    #[test]
+    fn ziplist_large_prevlen() {
+        let mut buf = vec![0; ZIPLIST_HEADER_SIZE];
+        buf.extend_from_slice(&[0xFE, 0, 1, 0, 0, 0x01, b'x']);
+        buf.push(ZIPLIST_END);
+        assert_eq!(decode_ziplist(&buf).unwrap(), strings(&["x"]));
+    }
+
+    #[test]
+    fn ziplist_without_end() {
+        let mut buf = vec![0; ZIPLIST_HEADER_SIZE];
+        buf.extend_from_slice(&[0, 0x01, b'x']);
+        assert!(decode_ziplist(&buf).is_err());
+    }
+
+    #[test]
+    fn listpack_entries() {
+        let mut buf = vec![0; LISTPACK_HEADER_SIZE];
+        buf.extend_from_slice(&[0x7F, 1]);
+        buf.extend_from_slice(&[0x82, b'h', b'i', 3]);
+        // A 13 bit integer with its sign bit set.
+        buf.extend_from_slice(&[0xDF, 0xFF, 2]);
+        buf.extend_from_slice(&[0xF1, 0x00, 0x80, 3]);
+        buf.extend_from_slice(&[0xF4]);
+        buf.extend_from_slice(&i64::MIN.to_le_bytes());
+        buf.push(9);
+        buf.push(LISTPACK_END);
+        assert_eq!(
+            decode_listpack(&buf).unwrap(),
+            strings(&["127", "hi", "-1", "-32768", &i64::MIN.to_string()])
+        );
+    }
+
+    #[test]
+    fn listpack_unknown_encoding() {
+        let mut buf = vec![0; LISTPACK_HEADER_SIZE];
+        buf.extend_from_slice(&[0xF5, 1, LISTPACK_END]);
+        assert!(decode_listpack(&buf).is_err());
+    }
+
+    #[test]
+    fn intset_widths() {
+        let mut buf = 2u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&(-5i16).to_le_bytes());
+        buf.extend_from_slice(&7i16.to_le_bytes());
+        assert_eq!(decode_intset(&buf).unwrap(), strings(&["-5", "7"]));
+
+        let mut buf = 3u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0]);
+        assert!(decode_intset(&buf).is_err());
+    }
+}
@@ -1,26 +1,235 @@
-use std::path::PathBuf;
+use anyhow::{bail, Result};
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+use crate::glob::glob_match;
+
+/// Redis' default snapshot rules: after 3600 seconds if at least one key
+/// changed, after 300 seconds if 100 keys changed and after 60 seconds if
+/// 10000 keys changed.
+const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+/// Number of logical databases, like Redis' `databases 16`.
+const DEFAULT_DATABASES: usize = 16;
+
+/// Parameters reported by `CONFIG GET`, in the order they are listed.
+const PARAMETERS: [&str; 8] = [
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "rdbchecksum",
+    "databases",
+];
+
+/// A `save <seconds> <changes>` rule: snapshot once at least `changes`
+/// writes happened and `seconds` have passed since the last save.
+#[derive(Debug, Clone, Copy)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// When the append-only file is flushed to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write command, before replying to the client.
+    Always,
+    /// At most once per second from the server cron.
+    EverySec,
+    /// Never explicitly; the operating system decides.
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => bail!("appendfsync must be always, everysec or no; got '{}'", s),
+        }
+    }
+}
+
+impl Display for AppendFsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppendFsync::Always => write!(f, "always"),
+            AppendFsync::EverySec => write!(f, "everysec"),
+            AppendFsync::No => write!(f, "no"),
+        }
+    }
+}
+
 pub struct Config {
     pub dir: Option<PathBuf>,
     pub dbfilename: Option<String>,
+    pub save: Vec<SaveRule>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// Whether the CRC64 checksum of RDB files (and AOF preambles) is
+    /// verified when loading them.
+    pub rdbchecksum: bool,
+    /// Number of logical databases.
+    pub databases: usize,
 }
 
 impl Config {
-    pub fn new(dir: Option<PathBuf>, dbfilename: Option<String>) -> Self {
-        Config { dir, dbfilename }
+    pub fn new(dir: Option<PathBuf>, dbfilename: Option<String>, save: Vec<SaveRule>) -> Self {
+        Config {
+            dir,
+            dbfilename,
+            save,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            rdbchecksum: true,
+            databases: DEFAULT_DATABASES,
+        }
     }
 
-    pub fn to_file_path(&self) -> Option<PathBuf> {
-        match (&self.dir, &self.dbfilename) {
-            (Some(dir), Some(dbfilename)) => Some(dir.join(dbfilename)),
-            _ => None,
+    /// Parses a Redis style `yes`/`no` flag.
+    pub fn parse_yes_no(value: &str) -> Result<bool> {
+        match value.to_lowercase().as_str() {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => bail!("expected yes or no; got '{}'", value),
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<&str> {
+    /// Parses rules given as `"<seconds> <changes> [<seconds> <changes> ...]"`.
+    /// `None` yields the Redis defaults and an empty string disables saving.
+    pub fn parse_save_rules(rules: Option<&str>) -> Result<Vec<SaveRule>> {
+        let rules = rules.unwrap_or(DEFAULT_SAVE_RULES);
+        let numbers = rules
+            .split_whitespace()
+            .map(|number| number.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if numbers.len() % 2 != 0 {
+            bail!(
+                "save rules must be pairs of <seconds> <changes>; got '{}'",
+                rules
+            );
+        }
+        Ok(numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect())
+    }
+
+    /// Path of the RDB file, falling back to Redis' defaults of the working
+    /// directory and `dump.rdb` for whichever part is not configured.
+    pub fn to_file_path(&self) -> PathBuf {
+        let dir = self.dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        dir.join(self.dbfilename.as_deref().unwrap_or("dump.rdb"))
+    }
+
+    /// Path of the append-only file, which lives next to the RDB file.
+    pub fn to_aof_path(&self) -> PathBuf {
+        let dir = self.dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        dir.join(&self.appendfilename)
+    }
+
+    /// Every parameter with a value whose name matches one of the glob
+    /// `patterns`, ignoring case like `CONFIG GET`.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|name| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes(), true))
+            })
+            .filter_map(|&name| self.get(name).map(|value| (name, value)))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
         match key {
-            "dir" => self.dir.as_ref().and_then(|path| path.to_str()),
-            "dbfilename" => self.dbfilename.as_deref(),
+            "dir" => self
+                .dir
+                .as_ref()
+                .and_then(|path| path.to_str())
+                .map(str::to_string),
+            "dbfilename" => self.dbfilename.clone(),
+            "save" => Some(
+                self.save
+                    .iter()
+                    .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            "appendonly" => Some(if self.appendonly { "yes" } else { "no" }.to_string()),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appendfsync" => Some(self.appendfsync.to_string()),
+            "rdbchecksum" => Some(if self.rdbchecksum { "yes" } else { "no" }.to_string()),
+            "databases" => Some(self.databases.to_string()),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[SaveRule]) -> Vec<(u64, u64)> {
+        rules
+            .iter()
+            .map(|rule| (rule.seconds, rule.changes))
+            .collect()
+    }
+
+    #[test]
+    fn save_rules_default_to_redis_ones() {
+        let defaults = Config::parse_save_rules(None).unwrap();
+        assert_eq!(rules(&defaults), [(3600, 1), (300, 100), (60, 10000)]);
+        let config = Config::new(None, None, defaults);
+        assert_eq!(config.get("save").unwrap(), DEFAULT_SAVE_RULES);
+        assert_eq!(config.to_file_path(), PathBuf::from("./dump.rdb"));
+        assert_eq!(config.to_aof_path(), PathBuf::from("./appendonly.aof"));
+    }
+
+    #[test]
+    fn empty_save_rules_disable_snapshots() {
+        for empty in ["", " "] {
+            assert!(Config::parse_save_rules(Some(empty)).unwrap().is_empty());
+        }
+        let config = Config::new(None, None, Vec::new());
+        assert_eq!(config.get("save").unwrap(), "");
+    }
+
+    #[test]
+    fn save_rules_are_pairs_of_numbers() {
+        let parsed = Config::parse_save_rules(Some(" 10  2 20 5")).unwrap();
+        assert_eq!(rules(&parsed), [(10, 2), (20, 5)]);
+        for invalid in ["10", "10 2 20", "10 x", "-1 2", "1.5 2"] {
+            assert!(
+                Config::parse_save_rules(Some(invalid)).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn files_live_in_the_configured_directory() {
+        let config = Config::new(
+            Some(PathBuf::from("/data")),
+            Some("cache.rdb".to_string()),
+            Vec::new(),
+        );
+        assert_eq!(config.to_file_path(), PathBuf::from("/data/cache.rdb"));
+        assert_eq!(config.to_aof_path(), PathBuf::from("/data/appendonly.aof"));
+        let config = Config::new(None, Some("cache.rdb".to_string()), Vec::new());
+        assert_eq!(config.to_file_path(), PathBuf::from("./cache.rdb"));
+    }
+}
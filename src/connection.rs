@@ -0,0 +1,145 @@
+use anyhow::{bail, Result};
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+};
+
+use crate::response::{Frame, RespParser, Value};
+
+/// A client connection that frames RESP values out of the byte stream.
+///
+/// Bytes read from the socket are accumulated in `buffer` until a complete
+/// frame is available, so requests split across reads and several requests
+/// pipelined into one read are both handled. Replies are buffered and flushed
+/// whenever the connection runs out of complete frames to process.
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+    parser: RespParser,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream: BufWriter::new(stream),
+            buffer: BytesMut::with_capacity(4096),
+            parser: RespParser::new(),
+        }
+    }
+
+    /// Reads the next complete value from the connection. Returns `None` once
+    /// the peer has closed the connection cleanly.
+    pub async fn read_value(&mut self) -> Result<Option<Value>> {
+        loop {
+            if let Some(value) = self.parse_frame().await? {
+                return Ok(Some(value));
+            }
+
+            self.stream.flush().await?;
+            if self.stream.get_mut().read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                bail!("connection reset by peer");
+            }
+        }
+    }
+
+    /// Waits until the peer closes the connection, e.g. while its client
+    /// is blocked. Whatever it sends in the meantime is kept for
+    /// `read_value`.
+    pub async fn closed(&mut self) -> Result<()> {
+        self.stream.flush().await?;
+        while self.stream.get_mut().read_buf(&mut self.buffer).await? != 0 {}
+        Ok(())
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.stream.write_all(buf).await?;
+        Ok(())
+    }
+
+    async fn parse_frame(&mut self) -> Result<Option<Value>> {
+        match self.parser.parse(&mut self.buffer) {
+            Frame::Complete(value) => Ok(Some(value)),
+            Frame::Incomplete => Ok(None),
+            Frame::Invalid(reason) => {
+                let error = format!("-ERR Protocol error: {}\r\n", reason);
+                self.write_all(error.as_bytes()).await?;
+                self.stream.flush().await?;
+                bail!("protocol error: {}", reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Sends `request` to a fresh connection and returns what the server
+    /// side read, along with everything the client received until the
+    /// connection closed.
+    async fn exchange(request: &[u8]) -> (Vec<Result<Option<Value>>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = request.to_vec();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::new(stream);
+        let mut read = Vec::new();
+        loop {
+            let value = connection.read_value().await;
+            let done = !matches!(value, Ok(Some(_)));
+            read.push(value);
+            if done {
+                break;
+            }
+        }
+        drop(connection);
+        (read, client.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests() {
+        let (read, received) = exchange(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPING\r\n").await;
+        assert_eq!(read.len(), 3);
+        assert!(matches!(read[2], Ok(None)));
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closes_on_a_malformed_frame() {
+        let (read, received) = exchange(b"*-1\r\n*1\r\n$4\r\nPING\r\n").await;
+        assert_eq!(read.len(), 1);
+        assert!(read[0].is_err());
+        assert_eq!(
+            received,
+            b"-ERR Protocol error: invalid multibulk length\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_on_an_oversized_frame() {
+        let (read, received) = exchange(b"*999999999999\r\n").await;
+        assert!(read[0].is_err());
+        assert!(received.starts_with(b"-ERR Protocol error"));
+    }
+
+    #[tokio::test]
+    async fn closes_on_nested_arrays() {
+        let (read, received) = exchange(&b"*1\r\n".repeat(1000)).await;
+        assert_eq!(read.len(), 1);
+        assert!(read[0].is_err());
+        assert_eq!(received, b"-ERR Protocol error: expected '$', got '*'\r\n");
+    }
+}
@@ -0,0 +1,54 @@
+/// CRC-64/Jones as used by Redis for the RDB checksum footer: polynomial
+/// `0xad93d23594c935a9` in normal form, reflected input and output, zero
+/// init, no final xor. `POLY` is the polynomial's reflected form, since the
+/// table is built least significant bit first.
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn incremental() {
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), crc64(0, b"123456789"));
+        assert_eq!(crc64(0, b""), 0);
+    }
+
+    #[test]
+    fn poly_is_the_reflected_polynomial() {
+        assert_eq!(POLY.reverse_bits(), 0xad93d23594c935a9);
+    }
+}
@@ -1,83 +1,875 @@
 use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime},
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    ops::{Index, IndexMut},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::{
+    quicklist::QuickList,
+    scan::{scan_hash, ScanMap},
 };
 
 #[derive(Debug)]
-pub enum GetValue<'a> {
-    Ok(&'a str),
+pub enum GetValue<T = Bytes> {
+    Ok(T),
     None,
-    Error(&'a str),
+    /// The key holds a value of another type than the command expects.
+    WrongType,
+}
+
+/// The key holds a value of another type than the command expects.
+#[derive(Error, Debug)]
+#[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+pub struct WrongTypeError;
+
+/// Why `INCRBY` and friends failed; the message is the whole error reply.
+#[derive(Error, Debug)]
+pub enum IncrError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR increment or decrement would overflow")]
+    Overflow,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR increment would produce NaN or Infinity")]
+    NanOrInfinity,
+}
+
+/// A value stored in the keyspace. Collections are shared with snapshots
+/// and copied on write, so cloning a value never copies its elements.
+#[derive(Debug, Clone)]
+pub enum RedisValue {
+    String(Bytes),
+    /// A string that is the canonical decimal form of a 64-bit integer,
+    /// kept as the integer itself like Redis' `int` encoding.
+    Integer(i64),
+    /// A string changed in place by `SETBIT` and friends, so a write does
+    /// not copy the whole value.
+    Buffer(Arc<Vec<u8>>),
+    List(Arc<QuickList>),
+    Set(Arc<ScanMap<()>>),
+    Hash(Arc<ScanMap<Bytes>>),
+    ZSet(Arc<ScanMap<f64>>),
+}
+
+impl RedisValue {
+    /// Wraps a string value, using the integer encoding when it looks like
+    /// one.
+    pub fn string(value: Bytes) -> Self {
+        match parse_i64(&value) {
+            Some(integer) => RedisValue::Integer(integer),
+            None => RedisValue::String(value),
+        }
+    }
+
+    /// The name `TYPE` reports for the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) | RedisValue::Integer(_) | RedisValue::Buffer(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Set(_) => "set",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::ZSet(_) => "zset",
+        }
+    }
+
+    /// The bytes of a string value, whichever its encoding.
+    pub fn as_string(&self) -> Option<Bytes> {
+        match self {
+            RedisValue::String(value) => Some(value.clone()),
+            RedisValue::Integer(integer) => Some(Bytes::from(integer.to_string())),
+            RedisValue::Buffer(buffer) => Some(Bytes::copy_from_slice(buffer)),
+            _ => None,
+        }
+    }
+
+    /// The bytes of a string value, borrowed unless it is an integer.
+    pub fn as_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            RedisValue::String(value) => Some(Cow::Borrowed(value)),
+            RedisValue::Integer(integer) => Some(Cow::Owned(integer.to_string().into_bytes())),
+            RedisValue::Buffer(buffer) => Some(Cow::Borrowed(buffer)),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a 64-bit integer the way Redis' `string2ll` does: only its
+/// canonical decimal form is accepted, so no sign on positive numbers, no
+/// leading zeros and no surrounding spaces.
+pub fn parse_i64(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 20 {
+        return None;
+    }
+    let integer = std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()?;
+    (integer.to_string().as_bytes() == bytes).then_some(integer)
+}
+
+/// Parses a float the way `INCRBYFLOAT` does: no surrounding spaces and no
+/// NaN.
+pub fn parse_f64(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|float| !float.is_nan())
+}
+
+/// Formats the result of `INCRBYFLOAT` like Redis: at most 15 decimals,
+/// without trailing zeros and never in exponent notation. Digits beyond the
+/// precision of a `f64` are dropped so `0.1 + 0.2` reads `0.3`.
+pub fn format_f64(float: f64) -> String {
+    let integer_digits = if float.abs() < 1.0 {
+        0
+    } else {
+        float.abs().log10().floor() as i32 + 1
+    };
+    let decimals = (f64::DIGITS as i32 - integer_digits).clamp(0, 17) as usize;
+    let mut formatted = format!("{:.*}", decimals, float);
+    if formatted.contains('.') {
+        let trimmed = formatted.trim_end_matches('0').trim_end_matches('.').len();
+        formatted.truncate(trimmed);
+    }
+    if formatted == "-0" {
+        formatted.remove(0);
+    }
+    formatted
 }
 
 pub trait Database {
-    fn get(&self, key: &str) -> GetValue;
-    fn set(&mut self, key: &str, value: &str, expires_at: Option<u64>);
-    fn delete(&mut self, key: &str) -> Option<String>;
+    /// Looks up a string value, deleting the key first if it has expired.
+    fn get(&mut self, key: &[u8]) -> GetValue;
+    /// Like `get`, but borrows the value rather than copying it.
+    fn get_ref(&mut self, key: &[u8]) -> GetValue<Cow<'_, [u8]>>;
+    /// Looks up the string at `key` to modify it in place, creating an
+    /// empty one if `key` does not exist, and counts a write. An existing
+    /// key keeps its expiry.
+    fn string_mut(&mut self, key: &[u8]) -> Result<&mut Vec<u8>, WrongTypeError>;
+    /// Stores a string value; `expires_at` is in absolute unix milliseconds.
+    fn set(&mut self, key: Bytes, value: Bytes, expires_at: Option<u64>);
+    /// Looks up an entry of any type, deleting the key first if it has
+    /// expired.
+    fn lookup(&mut self, key: &[u8]) -> Option<&DbValue>;
+    /// Looks up the list at `key` to modify it, deleting the key first if
+    /// it has expired. Report the changes with `list_changed`.
+    fn list_mut(&mut self, key: &[u8]) -> Result<Option<&mut QuickList>, WrongTypeError>;
+    /// Like `list_mut`, but creates an empty list if `key` does not exist,
+    /// which the caller must then push onto.
+    fn list_or_insert(&mut self, key: &[u8]) -> Result<&mut QuickList, WrongTypeError>;
+    /// Counts `changes` writes to the list at `key` and deletes the key if
+    /// they left the list empty, since Redis never keeps empty lists.
+    fn list_changed(&mut self, key: &[u8], changes: u64);
+    /// Removes and returns an entry along with its expiry, e.g. to move it
+    /// to another database.
+    fn take(&mut self, key: &[u8]) -> Option<DbValue>;
+    /// Deletes `key`. Returns whether it existed.
+    fn delete(&mut self, key: &[u8]) -> bool;
+    /// Stores an entry as is, replacing whatever `key` held, e.g. the
+    /// result of `RENAME` or `COPY`.
+    fn put(&mut self, key: Bytes, value: DbValue);
+    /// A random key that has not expired, or `None` if there is none.
+    fn random_key(&mut self) -> Option<Bytes>;
+    /// Adds `delta` to the integer stored at `key`, which counts as 0 when
+    /// missing, keeping its expiry. Returns the new value.
+    fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64, IncrError>;
+    /// Adds `delta` to the number stored at `key`, which counts as 0 when
+    /// missing, keeping its expiry. Returns the new value as stored.
+    fn incr_by_float(&mut self, key: &[u8], delta: f64) -> Result<Bytes, IncrError>;
+    /// Whether `key` holds a value that has not expired.
+    fn exists(&mut self, key: &[u8]) -> bool;
+    /// Returns `None` if `key` does not exist, otherwise its expiry in
+    /// absolute unix milliseconds, if any.
+    fn expiry(&mut self, key: &[u8]) -> Option<Option<u64>>;
+    /// Changes or removes the expiry of an existing key. Returns `false` if
+    /// the key does not exist.
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> bool;
+    /// Number of keys, including ones that expired but were not yet
+    /// reclaimed, like `DBSIZE`.
+    fn len(&self) -> usize;
+    /// Deletes every key.
+    fn clear(&mut self);
+    /// Number of keys with an expiry.
+    fn expires_len(&self) -> usize;
+    /// Checks up to `count` random keys with an expiry and deletes the
+    /// expired ones; one round of the active expiry cycle.
+    fn expire_sample(&mut self, count: usize) -> ExpireSample;
+    /// Number of keys deleted because they expired, lazily or actively.
+    fn expired_keys(&self) -> u64;
+    /// Inserts a value as is, e.g. one loaded from an RDB snapshot, without
+    /// counting it as a change.
+    fn insert(&mut self, key: Bytes, value: DbValue);
+    /// Makes room for `additional` more keys, e.g. ahead of loading them.
+    fn reserve(&mut self, additional: usize);
+    /// Returns every key that has not expired yet.
+    fn keys(&self) -> Vec<Bytes>;
+    /// Returns about `count` keys that have not expired, starting at
+    /// `cursor`, along with the cursor of the next call, 0 once every key
+    /// was visited. See the `scan` module for the guarantees.
+    fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>);
+    /// Returns a point-in-time copy of every live entry. Strings and
+    /// collections are shared rather than copied, and a collection is only
+    /// copied once it is written to while a snapshot still holds it, so
+    /// taking one costs a pass over the keys.
+    fn snapshot(&self) -> Vec<(Bytes, DbValue)>;
+    /// Number of writes since the last successful snapshot.
+    fn dirty(&self) -> u64;
+    /// Forgets `changes` writes once a snapshot containing them is on disk.
+    fn clear_dirty(&mut self, changes: u64);
 }
 
-#[derive(Debug)]
+/// Outcome of `Database::expire_sample`.
+#[derive(Debug, Default)]
+pub struct ExpireSample {
+    pub sampled: usize,
+    pub expired: usize,
+    /// Sum of the remaining time to live of the sampled keys that did not
+    /// expire, in milliseconds.
+    pub ttl_sum: u64,
+}
+
+/// A point-in-time copy of the live entries of every non-empty database,
+/// along with each database's index.
+pub type Snapshot = Vec<(usize, Vec<(Bytes, DbValue)>)>;
+
+/// The server's logical databases, addressed by index.
+pub struct Databases<T> {
+    dbs: Vec<T>,
+}
+
+impl<T: Database> Databases<T> {
+    pub fn new(count: usize, new_db: impl Fn() -> T) -> Self {
+        Self {
+            dbs: (0..count).map(|_| new_db()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.dbs.get_mut(index)
+    }
+
+    /// Exchanges the contents of two databases, like `SWAPDB`.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.dbs.swap(a, b);
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.dbs.iter_mut()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.dbs
+            .iter()
+            .enumerate()
+            .map(|(index, db)| (index, db.snapshot()))
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect()
+    }
+
+    /// Writes since the last successful snapshot, per database.
+    pub fn dirty(&self) -> Vec<u64> {
+        self.dbs.iter().map(Database::dirty).collect()
+    }
+
+    /// Forgets the writes counted by an earlier `dirty` once a snapshot
+    /// containing them is on disk.
+    pub fn clear_dirty(&mut self, changes: &[u64]) {
+        for (db, &changes) in self.dbs.iter_mut().zip(changes) {
+            db.clear_dirty(changes);
+        }
+    }
+}
+
+impl<T> Index<usize> for Databases<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.dbs[index]
+    }
+}
+
+impl<T> IndexMut<usize> for Databases<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut self.dbs[index]
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct DbValue {
-    value: String,
-    expires_at: Option<SystemTime>,
+    value: RedisValue,
+    /// Absolute unix time in milliseconds, the representation shared with
+    /// RDB files and the append-only file.
+    expires_at: Option<u64>,
 }
 
 impl DbValue {
-    pub fn new(value: String, expires_at: Option<SystemTime>) -> Self {
+    pub fn new(value: RedisValue, expires_at: Option<u64>) -> Self {
         Self { value, expires_at }
     }
+
+    pub fn value(&self) -> &RedisValue {
+        &self.value
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_time_millis())
+    }
+}
+
+/// Current unix time in milliseconds.
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
 #[derive(Debug)]
 pub struct RedisDatabase {
-    pub data: HashMap<String, DbValue>,
+    data: HashMap<Bytes, DbValue>,
+    /// Keys that have an expiry, in a vector so the active expiry cycle can
+    /// pick them at random, plus each key's position in it.
+    volatile: Vec<Bytes>,
+    volatile_positions: HashMap<Bytes, usize>,
+    /// Every key ordered by `scan_hash`, which `SCAN` cursors walk.
+    scan_index: BTreeSet<(u64, Bytes)>,
+    dirty: u64,
+    expired: u64,
+    /// State of the xorshift generator used to sample keys.
+    rng: u64,
 }
 
 impl RedisDatabase {
     pub fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
         Self {
             data: HashMap::new(),
+            volatile: Vec::new(),
+            volatile_positions: HashMap::new(),
+            scan_index: BTreeSet::new(),
+            dirty: 0,
+            expired: 0,
+            rng: seed | 1,
         }
     }
-}
 
-impl Database for RedisDatabase {
-    fn set(&mut self, key: &str, value: &str, expires_at: Option<u64>) {
-        if let Some(expires_at) = expires_at {
-            let now = SystemTime::now();
-            let expiry_duration = Duration::from_millis(expires_at);
-            let expires_at = now + expiry_duration;
-            self.data.insert(
-                key.to_owned(),
-                DbValue::new(value.to_owned(), Some(expires_at)),
-            );
-        } else {
-            self.data
-                .insert(key.to_owned(), DbValue::new(value.to_owned(), None));
-        }
-    }
-
-    fn get(&self, key: &str) -> GetValue {
-        match self.data.get(key) {
-            Some(DbValue {
-                value,
-                expires_at: Some(expires_at),
-            }) => {
-                if expires_at <= &SystemTime::now() {
-                    GetValue::Error(value)
-                } else {
-                    GetValue::Ok(value)
+    /// Deletes `key` if its expiry has passed, the lazy half of Redis'
+    /// expiration.
+    fn expire_if_needed(&mut self, key: &[u8]) {
+        if self.data.get(key).is_some_and(DbValue::is_expired) {
+            self.expire(key);
+        }
+    }
+
+    fn expire(&mut self, key: &[u8]) {
+        self.take(key);
+        self.expired += 1;
+    }
+
+    /// Keeps `volatile` in sync after the expiry of `key` changed.
+    fn track_expiry(&mut self, key: &Bytes, has_expiry: bool) {
+        match (has_expiry, self.volatile_positions.get(key)) {
+            (true, None) => {
+                self.volatile_positions
+                    .insert(key.clone(), self.volatile.len());
+                self.volatile.push(key.clone());
+            }
+            (false, Some(&position)) => {
+                self.volatile_positions.remove(key);
+                self.volatile.swap_remove(position);
+                if let Some(moved) = self.volatile.get(position) {
+                    self.volatile_positions.insert(moved.clone(), position);
                 }
             }
-            Some(DbValue {
-                value,
-                expires_at: None,
-            }) => GetValue::Ok(value),
+            _ => {}
+        }
+    }
+
+    /// Replaces the string at `key`, keeping its expiry, or creates it
+    /// without one.
+    fn store_string(&mut self, key: &[u8], value: RedisValue) {
+        self.dirty += 1;
+        match self.data.get_mut(key) {
+            Some(entry) => entry.value = value,
+            None => self.insert(Bytes::copy_from_slice(key), DbValue::new(value, None)),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl Database for RedisDatabase {
+    fn set(&mut self, key: Bytes, value: Bytes, expires_at: Option<u64>) {
+        self.dirty += 1;
+        self.insert(key, DbValue::new(RedisValue::string(value), expires_at));
+    }
+
+    fn get(&mut self, key: &[u8]) -> GetValue {
+        self.expire_if_needed(key);
+        match self.data.get(key).map(|entry| entry.value.as_string()) {
+            Some(Some(value)) => GetValue::Ok(value),
+            Some(None) => GetValue::WrongType,
+            None => GetValue::None,
+        }
+    }
+
+    fn get_ref(&mut self, key: &[u8]) -> GetValue<Cow<'_, [u8]>> {
+        self.expire_if_needed(key);
+        match self.data.get(key).map(|entry| entry.value.as_bytes()) {
+            Some(Some(value)) => GetValue::Ok(value),
+            Some(None) => GetValue::WrongType,
             None => GetValue::None,
         }
     }
 
-    fn delete(&mut self, key: &str) -> Option<String> {
-        self.data.remove(key).map(|v| v.value)
+    fn string_mut(&mut self, key: &[u8]) -> Result<&mut Vec<u8>, WrongTypeError> {
+        self.expire_if_needed(key);
+        if !self.data.contains_key(key) {
+            let buffer = DbValue::new(RedisValue::Buffer(Arc::default()), None);
+            self.insert(Bytes::copy_from_slice(key), buffer);
+        }
+        let entry = self.data.get_mut(key).expect("the key was just inserted");
+        // Strings are copied into a buffer on their first write only.
+        let copy = match &entry.value {
+            RedisValue::Buffer(_) => None,
+            value => Some(value.as_bytes().ok_or(WrongTypeError)?.into_owned()),
+        };
+        if let Some(copy) = copy {
+            entry.value = RedisValue::Buffer(Arc::new(copy));
+        }
+        self.dirty += 1;
+        match &mut entry.value {
+            // Copies the buffer first if a snapshot still shares it.
+            RedisValue::Buffer(buffer) => Ok(Arc::make_mut(buffer)),
+            _ => unreachable!("the value was just made a buffer"),
+        }
+    }
+
+    fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64, IncrError> {
+        self.expire_if_needed(key);
+        let current = match self.data.get(key).map(DbValue::value) {
+            None => 0,
+            Some(RedisValue::Integer(integer)) => *integer,
+            Some(RedisValue::String(value)) => parse_i64(value).ok_or(IncrError::NotInteger)?,
+            Some(RedisValue::Buffer(value)) => parse_i64(value).ok_or(IncrError::NotInteger)?,
+            Some(_) => return Err(IncrError::WrongType),
+        };
+        let value = current.checked_add(delta).ok_or(IncrError::Overflow)?;
+        self.store_string(key, RedisValue::Integer(value));
+        Ok(value)
+    }
+
+    fn incr_by_float(&mut self, key: &[u8], delta: f64) -> Result<Bytes, IncrError> {
+        self.expire_if_needed(key);
+        let current = match self.data.get(key).map(DbValue::value) {
+            None => 0.0,
+            Some(RedisValue::Integer(integer)) => *integer as f64,
+            Some(RedisValue::String(value)) => parse_f64(value).ok_or(IncrError::NotFloat)?,
+            Some(RedisValue::Buffer(value)) => parse_f64(value).ok_or(IncrError::NotFloat)?,
+            Some(_) => return Err(IncrError::WrongType),
+        };
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(IncrError::NanOrInfinity);
+        }
+        let value = Bytes::from(format_f64(value));
+        self.store_string(key, RedisValue::string(value.clone()));
+        Ok(value)
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<&DbValue> {
+        self.expire_if_needed(key);
+        self.data.get(key)
+    }
+
+    fn list_mut(&mut self, key: &[u8]) -> Result<Option<&mut QuickList>, WrongTypeError> {
+        self.expire_if_needed(key);
+        match self.data.get_mut(key).map(|entry| &mut entry.value) {
+            // Copies the list first if a snapshot still shares it.
+            Some(RedisValue::List(list)) => Ok(Some(Arc::make_mut(list))),
+            Some(_) => Err(WrongTypeError),
+            None => Ok(None),
+        }
+    }
+
+    fn list_or_insert(&mut self, key: &[u8]) -> Result<&mut QuickList, WrongTypeError> {
+        self.expire_if_needed(key);
+        if !self.data.contains_key(key) {
+            let list = DbValue::new(RedisValue::List(Arc::default()), None);
+            self.insert(Bytes::copy_from_slice(key), list);
+        }
+        self.list_mut(key)
+            .map(|list| list.expect("the list was just inserted"))
+    }
+
+    fn list_changed(&mut self, key: &[u8], changes: u64) {
+        self.dirty += changes;
+        let emptied = self
+            .data
+            .get(key)
+            .is_some_and(|entry| matches!(&entry.value, RedisValue::List(list) if list.is_empty()));
+        if emptied {
+            self.take(key);
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> bool {
+        self.take(key).is_some()
+    }
+
+    fn put(&mut self, key: Bytes, value: DbValue) {
+        self.dirty += 1;
+        self.insert(key, value);
+    }
+
+    fn random_key(&mut self) -> Option<Bytes> {
+        // Every expired pick is deleted, so this ends once a live key turns
+        // up or the database is empty.
+        while !self.data.is_empty() {
+            let start = (self.next_random(), Bytes::new());
+            let (_, key) = self
+                .scan_index
+                .range(start..)
+                .next()
+                .or_else(|| self.scan_index.first())?;
+            let key = key.clone();
+            if !self.data.get(&key).is_some_and(DbValue::is_expired) {
+                return Some(key);
+            }
+            self.expire(&key);
+        }
+        None
+    }
+
+    fn take(&mut self, key: &[u8]) -> Option<DbValue> {
+        let (key, value) = self.data.remove_entry(key)?;
+        self.dirty += 1;
+        self.track_expiry(&key, false);
+        self.scan_index.remove(&(scan_hash(&key), key));
+        Some(value).filter(|value| !value.is_expired())
+    }
+
+    fn exists(&mut self, key: &[u8]) -> bool {
+        self.expire_if_needed(key);
+        self.data.contains_key(key)
+    }
+
+    fn expiry(&mut self, key: &[u8]) -> Option<Option<u64>> {
+        self.expire_if_needed(key);
+        self.data.get(key).map(DbValue::expires_at)
+    }
+
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> bool {
+        self.expire_if_needed(key);
+        let Some((key, value)) = self.data.get_key_value(key) else {
+            return false;
+        };
+        let key = key.clone();
+        if value.expires_at == expires_at {
+            return true;
+        }
+        self.dirty += 1;
+        self.track_expiry(&key, expires_at.is_some());
+        if let Some(value) = self.data.get_mut(&key) {
+            value.expires_at = expires_at;
+        }
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn clear(&mut self) {
+        self.dirty += self.data.len() as u64;
+        self.data.clear();
+        self.scan_index.clear();
+        self.volatile.clear();
+        self.volatile_positions.clear();
+    }
+
+    fn expires_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    fn expire_sample(&mut self, count: usize) -> ExpireSample {
+        let mut sample = ExpireSample::default();
+        let now = unix_time_millis();
+        for _ in 0..count.min(self.volatile.len()) {
+            let position = (self.next_random() % self.volatile.len() as u64) as usize;
+            let key = self.volatile[position].clone();
+            let expires_at = self
+                .data
+                .get(&key)
+                .and_then(DbValue::expires_at)
+                .unwrap_or(0);
+            sample.sampled += 1;
+            if expires_at <= now {
+                self.expire(&key);
+                sample.expired += 1;
+            } else {
+                sample.ttl_sum += expires_at - now;
+            }
+        }
+        sample
+    }
+
+    fn expired_keys(&self) -> u64 {
+        self.expired
+    }
+
+    fn insert(&mut self, key: Bytes, value: DbValue) {
+        self.track_expiry(&key, value.expires_at().is_some());
+        if self.data.insert(key.clone(), value).is_none() {
+            self.scan_index.insert((scan_hash(&key), key));
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional);
+    }
+
+    fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let mut keys = Vec::with_capacity(count);
+        let mut last = None;
+        let entries = self.scan_index.range((cursor, Bytes::new())..);
+        for (visited, (hash, key)) in entries.enumerate() {
+            // Keys sharing a hash go in the same batch, since the cursor of
+            // the next call could not tell them apart.
+            if visited >= count && last != Some(*hash) {
+                return (*hash, keys);
+            }
+            last = Some(*hash);
+            if self.data.get(key).is_some_and(|value| !value.is_expired()) {
+                keys.push(key.clone());
+            }
+        }
+        (0, keys)
+    }
+
+    fn keys(&self) -> Vec<Bytes> {
+        self.data
+            .iter()
+            .filter(|(_, value)| !value.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn snapshot(&self) -> Vec<(Bytes, DbValue)> {
+        self.data
+            .iter()
+            .filter(|(_, value)| !value.is_expired())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    fn clear_dirty(&mut self, changes: u64) {
+        self.dirty = self.dirty.saturating_sub(changes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(value: &RedisValue) -> Vec<Bytes> {
+        match value {
+            RedisValue::List(list) => list.iter().cloned().collect(),
+            _ => panic!("not a list"),
+        }
+    }
+
+    #[test]
+    fn snapshot_shares_collections_until_written() {
+        let mut db = RedisDatabase::new();
+        db.list_or_insert(b"list")
+            .unwrap()
+            .push_back(Bytes::from("a"));
+        db.list_changed(b"list", 1);
+
+        let snapshot = db.snapshot();
+        let RedisValue::List(shared) = snapshot[0].1.value() else {
+            panic!("not a list");
+        };
+        let RedisValue::List(live) = db.lookup(b"list").unwrap().value() else {
+            panic!("not a list");
+        };
+        assert!(Arc::ptr_eq(shared, live));
+
+        db.list_mut(b"list")
+            .unwrap()
+            .unwrap()
+            .push_back(Bytes::from("b"));
+        db.list_changed(b"list", 1);
+        assert_eq!(list(snapshot[0].1.value()), vec![Bytes::from("a")]);
+        assert_eq!(
+            list(db.lookup(b"list").unwrap().value()),
+            vec![Bytes::from("a"), Bytes::from("b")]
+        );
+    }
+
+    #[test]
+    fn strings_are_written_in_place() {
+        let mut db = RedisDatabase::new();
+        db.set(Bytes::from("counter"), Bytes::from("12"), Some(u64::MAX));
+        db.string_mut(b"counter").unwrap().push(b'3');
+        assert!(matches!(db.get(b"counter"), GetValue::Ok(value) if value == "123"));
+        assert_eq!(db.expiry(b"counter"), Some(Some(u64::MAX)));
+
+        let buffer = db.string_mut(b"counter").unwrap();
+        buffer.reserve(64);
+        let address = buffer.as_ptr();
+        buffer.extend_from_slice(b"456");
+        assert_eq!(db.string_mut(b"counter").unwrap().as_ptr(), address);
+        assert_eq!(db.incr_by(b"counter", 1).unwrap(), 123457);
+
+        assert!(db.string_mut(b"missing").unwrap().is_empty());
+        assert!(db.exists(b"missing"));
+        db.list_or_insert(b"list")
+            .unwrap()
+            .push_back(Bytes::from("a"));
+        assert!(db.string_mut(b"list").is_err());
+    }
+
+    #[test]
+    fn snapshot_keeps_strings_written_after_it() {
+        let mut db = RedisDatabase::new();
+        db.string_mut(b"bitmap").unwrap().push(0x01);
+        let snapshot = db.snapshot();
+        db.string_mut(b"bitmap").unwrap()[0] = 0xff;
+        assert_eq!(snapshot[0].1.value().as_bytes().unwrap().as_ref(), [0x01]);
+        assert!(matches!(db.get_ref(b"bitmap"), GetValue::Ok(value) if value.as_ref() == [0xff]));
+    }
+
+    #[test]
+    fn integer_increments() {
+        let mut db = RedisDatabase::new();
+        assert_eq!(db.incr_by(b"counter", 5).unwrap(), 5);
+        assert_eq!(db.incr_by(b"counter", -7).unwrap(), -2);
+        db.set(Bytes::from("max"), Bytes::from(i64::MAX.to_string()), None);
+        assert!(matches!(db.incr_by(b"max", 1), Err(IncrError::Overflow)));
+        assert_eq!(db.incr_by(b"max", -1).unwrap(), i64::MAX - 1);
+        db.set(Bytes::from("min"), Bytes::from(i64::MIN.to_string()), None);
+        assert!(matches!(db.incr_by(b"min", -1), Err(IncrError::Overflow)));
+        assert!(matches!(
+            db.incr_by(b"min", i64::MIN),
+            Err(IncrError::Overflow)
+        ));
+        // A failed increment leaves the value alone.
+        assert!(matches!(db.get(b"min"), GetValue::Ok(value) if value == i64::MIN.to_string()));
+
+        for value in ["abc", "1.5", " 1", "01", "+1", "", "99999999999999999999"] {
+            db.set(Bytes::from("string"), Bytes::from(value), None);
+            assert!(
+                matches!(db.incr_by(b"string", 1), Err(IncrError::NotInteger)),
+                "{:?}",
+                value
+            );
+        }
+        db.list_or_insert(b"list")
+            .unwrap()
+            .push_back(Bytes::from("a"));
+        assert!(matches!(db.incr_by(b"list", 1), Err(IncrError::WrongType)));
+
+        // Strings written in place are incremented like any other.
+        db.string_mut(b"buffer").unwrap().extend_from_slice(b"41");
+        assert_eq!(db.incr_by(b"buffer", 1).unwrap(), 42);
+    }
+
+    #[test]
+    fn increments_keep_the_expiry() {
+        let mut db = RedisDatabase::new();
+        let expires_at = unix_time_millis() + 100_000;
+        db.set(Bytes::from("counter"), Bytes::from("1"), Some(expires_at));
+        db.incr_by(b"counter", 1).unwrap();
+        db.incr_by_float(b"counter", 0.5).unwrap();
+        assert_eq!(db.expiry(b"counter"), Some(Some(expires_at)));
+    }
+
+    #[test]
+    fn float_increments() {
+        let mut db = RedisDatabase::new();
+        assert_eq!(db.incr_by_float(b"float", 0.1).unwrap(), "0.1");
+        assert_eq!(db.incr_by_float(b"float", 0.2).unwrap(), "0.3");
+        assert_eq!(db.incr_by_float(b"float", -0.3).unwrap(), "0");
+        assert_eq!(db.incr_by_float(b"float", 1e3).unwrap(), "1000");
+        // The result is stored as an integer when it is one.
+        assert_eq!(db.incr_by(b"float", 1).unwrap(), 1001);
+
+        db.set(Bytes::from("max"), Bytes::from("1.7e308"), None);
+        assert!(matches!(
+            db.incr_by_float(b"max", 1.7e308),
+            Err(IncrError::NanOrInfinity)
+        ));
+        assert!(matches!(
+            db.incr_by_float(b"float", f64::INFINITY),
+            Err(IncrError::NanOrInfinity)
+        ));
+        db.set(Bytes::from("inf"), Bytes::from("inf"), None);
+        assert!(matches!(
+            db.incr_by_float(b"inf", f64::NEG_INFINITY),
+            Err(IncrError::NanOrInfinity)
+        ));
+        for value in ["abc", "nan", "1.5 ", ""] {
+            db.set(Bytes::from("string"), Bytes::from(value), None);
+            assert!(
+                matches!(db.incr_by_float(b"string", 1.0), Err(IncrError::NotFloat)),
+                "{:?}",
+                value
+            );
+        }
+        db.list_or_insert(b"list")
+            .unwrap()
+            .push_back(Bytes::from("a"));
+        assert!(matches!(
+            db.incr_by_float(b"list", 1.0),
+            Err(IncrError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn floats_are_formatted_without_exponents_or_trailing_zeros() {
+        assert_eq!(format_f64(0.0), "0");
+        assert_eq!(format_f64(-0.0), "0");
+        assert_eq!(format_f64(10.5), "10.5");
+        assert_eq!(format_f64(-10.5), "-10.5");
+        assert_eq!(format_f64(3.0), "3");
+        assert_eq!(format_f64(100.0), "100");
+        assert_eq!(format_f64(0.1 + 0.2), "0.3");
+        assert_eq!(format_f64(1.0 / 3.0), "0.333333333333333");
+        assert_eq!(format_f64(5.0e3), "5000");
+        assert_eq!(format_f64(1e20), "100000000000000000000");
+        assert_eq!(format_f64(1.5e-5), "0.000015");
+        assert_eq!(format_f64(1e-15), "0.000000000000001");
+        assert_eq!(format_f64(1e-16), "0");
+        assert_eq!(format_f64(-1e-16), "0");
+        assert_eq!(format_f64(1e6 / 3.0), "333333.333333333");
     }
 }
@@ -1,13 +1,37 @@
-pub fn to_bulk_string(s: &str) -> String {
-    let len = s.len();
-    format!("${}\r\n{}\r\n", len, s)
+pub fn to_bulk_string(s: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(s.len() + 16);
+    buffer.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+    buffer.extend_from_slice(s);
+    buffer.extend_from_slice(b"\r\n");
+    buffer
+}
+
+pub fn to_list_of_bulk_strings<T: AsRef<[u8]>>(list: &[T]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(format!("*{}\r\n", list.len()).as_bytes());
+    for item in list {
+        buffer.extend_from_slice(&to_bulk_string(item.as_ref()));
+    }
+    buffer
+}
+
+pub fn to_integer(integer: i64) -> Vec<u8> {
+    format!(":{}\r\n", integer).into_bytes()
+}
+
+pub fn to_array_header(len: usize) -> Vec<u8> {
+    format!("*{}\r\n", len).into_bytes()
 }
 
-pub fn to_list_of_bulk_strings(list: &[String]) -> String {
-    let mut buffer = String::new();
-    buffer.push_str(&format!("*{}\r\n", list.len()));
+/// Encodes an array of bulk strings where missing ones are nil, like the
+/// reply of `MGET`.
+pub fn to_list_of_optional_bulk_strings<T: AsRef<[u8]>>(list: &[Option<T>]) -> Vec<u8> {
+    let mut buffer = to_array_header(list.len());
     for item in list {
-        buffer.push_str(&to_bulk_string(item));
+        match item {
+            Some(item) => buffer.extend_from_slice(&to_bulk_string(item.as_ref())),
+            None => buffer.extend_from_slice(b"$-1\r\n"),
+        }
     }
     buffer
 }
@@ -0,0 +1,110 @@
+//! The active half of key expiration, modelled on Redis' slow
+//! `activeExpireCycle`: every cron tick samples random keys with an expiry in
+//! each database and deletes the expired ones, repeating while more than a
+//! tenth of a sample turned out to be expired, within a time budget.
+
+use std::time::{Duration, Instant};
+
+use crate::db::{Database, Databases};
+
+/// Keys sampled per round in each database.
+const KEYS_PER_LOOP: usize = 20;
+/// Percentage of expired keys in a sample above which another round runs.
+const ACCEPTABLE_STALE: usize = 10;
+/// Share of the cron period, in percent, a cycle may spend.
+const SLOW_TIME_PERC: u32 = 25;
+
+/// State kept between cycles, and the statistics reported by `INFO`.
+pub struct ActiveExpire {
+    /// Database to start the next cycle with, so a cycle that runs out of
+    /// time does not starve the databases after it.
+    next_db: usize,
+    /// Running estimate of the share of keys with an expiry that already
+    /// expired, between 0 and 1.
+    stale_perc: f64,
+    time_cap_reached_count: u64,
+    cycle_time: Duration,
+    /// Running estimate of the average time to live per database, in
+    /// milliseconds.
+    avg_ttls: Vec<u64>,
+}
+
+impl ActiveExpire {
+    pub fn new() -> Self {
+        Self {
+            next_db: 0,
+            stale_perc: 0.0,
+            time_cap_reached_count: 0,
+            cycle_time: Duration::ZERO,
+            avg_ttls: Vec::new(),
+        }
+    }
+
+    /// Runs one cycle over every database, spending at most a quarter of
+    /// `period`, the interval between cycles.
+    pub fn run<T: Database>(&mut self, dbs: &mut Databases<T>, period: Duration) {
+        let start = Instant::now();
+        let time_limit = period * SLOW_TIME_PERC / 100;
+        let count = dbs.len();
+        self.avg_ttls.resize(count, 0);
+        let (mut total_sampled, mut total_expired) = (0, 0);
+
+        'dbs: for _ in 0..count {
+            let index = self.next_db % count;
+            self.next_db = (index + 1) % count;
+            let db = &mut dbs[index];
+            loop {
+                let sample = db.expire_sample(KEYS_PER_LOOP);
+                total_sampled += sample.sampled;
+                total_expired += sample.expired;
+
+                let alive = (sample.sampled - sample.expired) as u64;
+                if let Some(avg_ttl) = sample.ttl_sum.checked_div(alive) {
+                    // Same smoothing as Redis: the new sample weighs 2%.
+                    let previous = self.avg_ttls[index];
+                    self.avg_ttls[index] = if previous == 0 {
+                        avg_ttl
+                    } else {
+                        previous / 50 * 49 + avg_ttl / 50
+                    };
+                }
+
+                if start.elapsed() >= time_limit {
+                    self.time_cap_reached_count += 1;
+                    break 'dbs;
+                }
+                if sample.sampled == 0 || sample.expired * 100 <= sample.sampled * ACCEPTABLE_STALE
+                {
+                    break;
+                }
+            }
+        }
+
+        self.cycle_time += start.elapsed();
+        let current_perc = if total_sampled > 0 {
+            total_expired as f64 / total_sampled as f64
+        } else {
+            0.0
+        };
+        self.stale_perc = current_perc * 0.05 + self.stale_perc * 0.95;
+    }
+
+    pub fn stale_perc(&self) -> f64 {
+        self.stale_perc
+    }
+
+    pub fn time_cap_reached_count(&self) -> u64 {
+        self.time_cap_reached_count
+    }
+
+    /// Total time spent in cycles, in milliseconds.
+    pub fn cycle_millis(&self) -> u128 {
+        self.cycle_time.as_millis()
+    }
+
+    /// Estimated average time to live of the keys with an expiry in database
+    /// `index`, in milliseconds.
+    pub fn avg_ttl(&self, index: usize) -> u64 {
+        self.avg_ttls.get(index).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,155 @@
+//! Glob-style pattern matching with the same rules as Redis'
+//! `stringmatchlen`, shared by every command that takes a pattern:
+//!
+//! - `?` matches any single byte and `*` any run of bytes, including none;
+//! - `[abc]` matches one of the listed bytes, `[a-z]` a range of them and
+//!   `[^...]` anything but them;
+//! - `\` makes the following byte match literally, inside brackets too.
+
+/// Whether `string` matches `pattern` as a whole.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match: the
+    // position right after the star and the byte of `string` it would
+    // swallow next.
+    let mut backtrack: Option<(usize, usize)> = None;
+    loop {
+        if p < pattern.len() && pattern[p] == b'*' {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            backtrack = Some((p, s));
+            continue;
+        }
+        if s == string.len() {
+            return p == pattern.len();
+        }
+        let step = match_byte(pattern, p, string[s], nocase);
+        match step {
+            Some(next) => {
+                p = next;
+                s += 1;
+            }
+            None => match backtrack {
+                // Let the star swallow one more byte and try again.
+                Some((star_p, star_s)) if star_s < string.len() => {
+                    backtrack = Some((star_p, star_s + 1));
+                    p = star_p;
+                    s = star_s + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+}
+
+/// Matches `byte` against the single-byte element of `pattern` starting at
+/// `p`, which is not `*`. Returns the position of the next element if it
+/// matches.
+fn match_byte(pattern: &[u8], p: usize, byte: u8, nocase: bool) -> Option<usize> {
+    let equal = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+    match pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            // An unterminated class ends with the pattern, like in Redis.
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= equal(pattern[i + 1], byte);
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+                    let (mut start, mut end) = (pattern[i], pattern[i + 2]);
+                    if start > end {
+                        std::mem::swap(&mut start, &mut end);
+                    }
+                    let byte = if nocase {
+                        byte.to_ascii_lowercase()
+                    } else {
+                        byte
+                    };
+                    if nocase {
+                        start = start.to_ascii_lowercase();
+                        end = end.to_ascii_lowercase();
+                    }
+                    matched |= (start..=end).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= equal(pattern[i], byte);
+                    i += 1;
+                }
+            }
+            // Skip the closing bracket, if any.
+            let next = (i + 1).min(pattern.len());
+            (matched != negate).then_some(next)
+        }
+        b'\\' if p + 1 < pattern.len() => equal(pattern[p + 1], byte).then_some(p + 2),
+        &literal => equal(literal, byte).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h*llo", "hllo"));
+        assert!(matches("h*llo", "heeeello"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(matches("**a**", "bab"));
+        assert!(!matches("", "a"));
+        assert!(!matches("abc", "abcd"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[abc", "c"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("\\?", "?"));
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn nocase() {
+        assert!(glob_match(b"HeLLo", b"hello", true));
+        assert!(glob_match(b"h[A-C]llo", b"hbllo", true));
+        assert!(glob_match(b"h[a-c]llo", b"HBLLO", true));
+        assert!(!glob_match(b"HeLLo", b"hello", false));
+    }
+}
@@ -0,0 +1,71 @@
+//! Frees deleted values on a background thread, like Redis' lazyfree, so
+//! `UNLINK` of a huge collection does not stall every other client while
+//! its memory is released.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::db::{DbValue, RedisValue};
+
+/// Values whose freeing costs less than this are dropped inline since
+/// handing them over would cost more, like Redis' `LAZYFREE_THRESHOLD`.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+pub struct LazyFree {
+    sender: Sender<Vec<DbValue>>,
+    /// Values freed by the background thread so far.
+    freed: Arc<AtomicU64>,
+}
+
+impl LazyFree {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel::<Vec<DbValue>>();
+        let freed = Arc::new(AtomicU64::new(0));
+        let thread_freed = Arc::clone(&freed);
+        thread::Builder::new()
+            .name("lazyfree".to_string())
+            .spawn(move || {
+                for values in receiver {
+                    let count = values.len() as u64;
+                    drop(values);
+                    thread_freed.fetch_add(count, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to spawn the lazyfree thread");
+        Self { sender, freed }
+    }
+
+    /// Frees `values`, on the background thread for the expensive ones.
+    pub fn free(&self, values: Vec<DbValue>) {
+        let (large, small): (Vec<_>, Vec<_>) = values
+            .into_iter()
+            .partition(|value| free_effort(value.value()) > LAZYFREE_THRESHOLD);
+        drop(small);
+        if !large.is_empty() {
+            // Should the thread be gone, the values are dropped right here
+            // along with the error.
+            let _ = self.sender.send(large);
+        }
+    }
+
+    pub fn freed_objects(&self) -> u64 {
+        self.freed.load(Ordering::Relaxed)
+    }
+}
+
+/// Rough number of allocations freeing `value` takes.
+fn free_effort(value: &RedisValue) -> usize {
+    match value {
+        RedisValue::String(_) | RedisValue::Integer(_) | RedisValue::Buffer(_) => 1,
+        RedisValue::List(list) => list.node_count(),
+        RedisValue::Set(set) => set.len(),
+        RedisValue::Hash(hash) => hash.len(),
+        RedisValue::ZSet(zset) => zset.len(),
+    }
+}
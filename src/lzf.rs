@@ -0,0 +1,88 @@
+/// Most bytes a single input byte can expand to: a three byte back reference
+/// produces at most 264 bytes.
+const MAX_EXPANSION: usize = 88;
+
+/// Decompresses an LZF block as produced by Redis' `lzf_compress`.
+///
+/// The input is a sequence of control bytes: values below 32 introduce a run
+/// of `ctrl + 1` literal bytes, anything else is a back reference of
+/// `(ctrl >> 5) + 2` bytes (extended by a further length byte when the 3-bit
+/// length is 7) located `((ctrl & 0x1f) << 8) + next + 1` bytes back in the
+/// output. Returns `None` if the data is corrupt or does not decompress to
+/// exactly `expected_len` bytes, including when `input` is too short to ever
+/// produce that many, so a corrupt length cannot force a huge allocation.
+pub fn decompress(input: &[u8], expected_len: usize) -> Option<Vec<u8>> {
+    if expected_len > input.len().saturating_mul(MAX_EXPANSION) {
+        return None;
+    }
+    let mut output = Vec::with_capacity(expected_len);
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+
+        if ctrl < 32 {
+            let run = ctrl + 1;
+            output.extend_from_slice(input.get(pos..pos + run)?);
+            pos += run;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(pos)? as usize;
+                pos += 1;
+            }
+            len += 2;
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(pos)? as usize + 1;
+            pos += 1;
+            let start = output.len().checked_sub(offset)?;
+            // The reference may overlap the bytes being produced, so copy one
+            // byte at a time.
+            for i in start..start + len {
+                let byte = output[i];
+                output.push(byte);
+            }
+        }
+
+        if output.len() > expected_len {
+            return None;
+        }
+    }
+
+    (output.len() == expected_len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "abc" as literals, then a long back reference repeating it 3 times.
+    const ABC_4: &[u8] = &[2, b'a', b'b', b'c', 0xE0, 0, 2];
+
+    #[test]
+    fn literals_and_overlapping_references() {
+        assert_eq!(decompress(ABC_4, 12).unwrap(), b"abcabcabcabc");
+    }
+
+    #[test]
+    fn wrong_length() {
+        assert!(decompress(ABC_4, 11).is_none());
+        assert!(decompress(ABC_4, 13).is_none());
+    }
+
+    #[test]
+    fn impossible_length_is_rejected_before_allocating() {
+        assert!(decompress(ABC_4, usize::MAX).is_none());
+        assert!(decompress(&[], 1).is_none());
+    }
+
+    #[test]
+    fn corrupt_input() {
+        // A literal run longer than the input.
+        assert!(decompress(&[5, b'a'], 6).is_none());
+        // A back reference before the start of the output.
+        assert!(decompress(&[0, b'a', 0x20, 5], 4).is_none());
+        // A long back reference missing its length byte.
+        assert!(decompress(&[0, b'a', 0xE0], 10).is_none());
+    }
+}
@@ -1,26 +1,61 @@
 use anyhow::Result;
 use clap::Parser;
-use std::{
-    fs::File,
-    io::{self, Read, Write},
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
     net::{TcpListener, TcpStream},
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    signal::unix::{signal, Signal, SignalKind},
 };
 
+mod aof;
+mod bitmap;
+mod blocking;
 mod command;
+mod compact;
 mod config;
+mod connection;
+mod crc64;
 mod db;
 mod encoding;
+mod expire;
+mod glob;
+mod lazyfree;
+mod lzf;
 mod parser;
+mod persistence;
+mod quicklist;
 mod response;
-use crate::config::Config;
-use command::{Command, SetCommand};
-use db::{Database, GetValue, RedisDatabase};
-use encoding::{encode_response_as_simple_string, to_bulk_string, to_list_of_bulk_strings};
-use parser::{RDBParser, Rdb};
-use response::{RespParser, Value};
+mod scan;
+mod server;
+mod strings;
+mod writer;
+use crate::config::{AppendFsync, Config};
+use aof::{read_aof_file, Aof};
+use bitmap::{
+    bit_op, bit_position, count_bits, fit_field, get_bit, read_field, set_bit, write_field,
+    Overflow,
+};
+use blocking::{BlockedWait, TIMEOUT_REPLY};
+use bytes::Bytes;
+use command::{
+    BitPosCommand, BitfieldOperation, BlockingCommand, BlockingOperation, Command, CommandError,
+    CopyCommand, ExpireCommand, GetExExpiry, LPosCommand, LcsCommand, ScanOptions, SetCommand,
+    SetCondition, ShutdownMode, TimeUnit,
+};
+use connection::Connection;
+use db::{
+    unix_time_millis, Database, Databases, DbValue, GetValue, RedisDatabase, RedisValue,
+    WrongTypeError,
+};
+use encoding::{
+    encode_response_as_simple_string, to_array_header, to_bulk_string, to_integer,
+    to_list_of_bulk_strings, to_list_of_optional_bulk_strings,
+};
+use glob::glob_match;
+use persistence::{insert_rdb, load_rdb, log_rdb_info};
+use quicklist::{resolve_index, resolve_range, ListEnd, QuickList};
+use response::Value;
+use server::{Client, Server};
+use strings::{fits_in_string, get_range, lcs, set_range, Lcs};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -29,167 +64,1384 @@ use response::{RespParser, Value};
     about
 )]
 pub struct Args {
-    /// The directory where RDB files are stored
-    #[arg(short, long)]
+    /// The directory where RDB files are stored; the working directory by
+    /// default
+    #[arg(long)]
     pub dir: Option<PathBuf>,
-    /// The name of the RDB files
-    #[arg(short, long)]
+    /// The name of the RDB files; dump.rdb by default
+    #[arg(long)]
     pub dbfilename: Option<String>,
+    /// Snapshot rules as "<seconds> <changes>" pairs, "3600 1 300 100 60
+    /// 10000" by default; "" disables snapshots
+    #[arg(long)]
+    pub save: Option<String>,
+    /// Whether write commands are logged to the append-only file (yes/no)
+    #[arg(long)]
+    pub appendonly: Option<String>,
+    /// The name of the append-only file
+    #[arg(long)]
+    pub appendfilename: Option<String>,
+    /// When the append-only file is fsynced: always, everysec or no
+    #[arg(long)]
+    pub appendfsync: Option<AppendFsync>,
+    /// Whether RDB checksums are verified on load (yes/no)
+    #[arg(long)]
+    pub rdbchecksum: Option<String>,
+    /// The number of logical databases
+    #[arg(long)]
+    pub databases: Option<usize>,
 }
 
-fn read_from_stream(stream: &mut TcpStream) -> Option<Vec<u8>> {
-    let mut buf = [0; 1024];
-    let size = stream.read(&mut buf);
-    match size {
-        Ok(size) => Some(buf[..size].to_vec()),
-        Err(_) => None,
-    }
-}
+/// How often the server checks whether a `save` rule requires a snapshot and
+/// runs the active expiry cycle.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+const WRONGTYPE_ERROR: &[u8] =
+    b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+const STRING_TOO_LONG_ERROR: &[u8] =
+    b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n";
 
-fn parse(data: &[u8]) -> Option<Value> {
-    let mut parser = RespParser::new(data);
-    let (value, _) = parser.parse();
-    value
+fn process_request(request: Value) -> Result<Command, CommandError> {
+    match request {
+        Value::Array(array) if !array.is_empty() => Command::handle_command(&array),
+        _ => Err(CommandError::Syntax),
+    }
 }
 
-fn process_request(request: &[u8]) -> Option<Command> {
-    let value = parse(request);
-    match value {
-        Some(Value::Array(array)) => Command::handle_command(&array),
-        _ => {
-            eprintln!("unable to parse request");
-            None
+/// Runs `SET` and its siblings: checks the `NX`/`XX` condition and writes the
+/// value. Returns whether the value was written, along with the previous
+/// value when `get` asked for it.
+fn set_string<T: Database>(db: &mut T, set: SetCommand) -> Result<(bool, Option<Bytes>), ()> {
+    let previous = if set.get {
+        match db.get(&set.key) {
+            GetValue::Ok(value) => Some(value),
+            GetValue::None => None,
+            GetValue::WrongType => return Err(()),
         }
+    } else {
+        None
+    };
+    let exists = db.exists(&set.key);
+    let allowed = match set.condition {
+        Some(SetCondition::Nx) => !exists,
+        Some(SetCondition::Xx) => exists,
+        None => true,
+    };
+    if !allowed {
+        return Ok((false, previous));
     }
+    let expires_at = if set.keep_ttl {
+        db.expiry(&set.key).flatten()
+    } else {
+        set.expires_at
+    };
+    db.set(set.key, set.value, expires_at);
+    Ok((true, previous))
 }
 
-fn read_rdb_file(path: PathBuf) -> Result<Rdb> {
-    let file = File::open(path).expect("Unable to open file");
-    let mut reader = io::BufReader::new(file);
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-    let mut parser = RDBParser::new(&buffer);
-    let rdb = parser.parse()?;
-    Ok(rdb)
-}
+fn handle_command<T: Database + Send + 'static>(
+    command: Result<Command, CommandError>,
+    client: &mut Client,
+    server: &Arc<Server<T>>,
+) -> Vec<u8> {
+    match command {
+        Ok(Command::Ping(response)) => encode_response_as_simple_string(response.as_bytes()),
 
-async fn handle_connection<T: Database>(
-    mut stream: TcpStream,
-    db: Arc<Mutex<T>>,
-    config: Arc<Mutex<Config>>,
-) -> Result<()> {
-    while let Some(request) = read_from_stream(&mut stream) {
-        if request.is_empty() {
-            break;
+        Ok(Command::Echo(response)) => to_bulk_string(&response),
+
+        Ok(Command::Set(set_command)) => {
+            let get = set_command.get;
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match set_string(db, set_command) {
+                Ok((_, Some(previous))) => to_bulk_string(&previous),
+                Ok((true, None)) if !get => encode_response_as_simple_string(b"OK"),
+                Ok(_) => b"$-1\r\n".to_vec(),
+                Err(()) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::SetNx(set_command)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match set_string(db, set_command) {
+                Ok((true, _)) => b":1\r\n".to_vec(),
+                _ => b":0\r\n".to_vec(),
+            }
+        }
+
+        Ok(Command::Get(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get(&key) {
+                GetValue::Ok(value) => to_bulk_string(&value),
+                GetValue::None => b"$-1\r\n".to_vec(),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::GetDel(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get(&key) {
+                GetValue::Ok(value) => {
+                    db.take(&key);
+                    to_bulk_string(&value)
+                }
+                GetValue::None => b"$-1\r\n".to_vec(),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::IncrBy(key, delta)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.incr_by(&key, delta) {
+                Ok(value) => format!(":{}\r\n", value).into_bytes(),
+                Err(e) => format!("-{}\r\n", e).into_bytes(),
+            }
+        }
+
+        Ok(Command::IncrByFloat(key, delta)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.incr_by_float(&key, delta) {
+                Ok(value) => to_bulk_string(&value),
+                Err(e) => format!("-{}\r\n", e).into_bytes(),
+            }
+        }
+
+        Ok(Command::Append(key, value)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let current_len = match db.get_ref(&key) {
+                GetValue::Ok(current) => current.len(),
+                GetValue::None => 0,
+                GetValue::WrongType => return WRONGTYPE_ERROR.to_vec(),
+            };
+            if !fits_in_string(current_len, value.len()) {
+                return STRING_TOO_LONG_ERROR.to_vec();
+            }
+            match db.string_mut(&key) {
+                Ok(current) => {
+                    current.extend_from_slice(&value);
+                    to_integer(current.len() as i64)
+                }
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::StrLen(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get_ref(&key) {
+                GetValue::Ok(value) => to_integer(value.len() as i64),
+                GetValue::None => to_integer(0),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::GetRange(key, start, end)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get_ref(&key) {
+                GetValue::Ok(value) => to_bulk_string(get_range(&value, start, end)),
+                GetValue::None => to_bulk_string(b""),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::SetRange(key, offset, value)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let current_len = match db.get_ref(&key) {
+                GetValue::Ok(current) => current.len(),
+                GetValue::None => 0,
+                GetValue::WrongType => return WRONGTYPE_ERROR.to_vec(),
+            };
+            // An empty patch changes nothing and does not create the key.
+            if value.is_empty() {
+                return to_integer(current_len as i64);
+            }
+            if !fits_in_string(offset, value.len()) {
+                return STRING_TOO_LONG_ERROR.to_vec();
+            }
+            match db.string_mut(&key) {
+                Ok(current) => {
+                    set_range(current, offset, &value);
+                    to_integer(current.len() as i64)
+                }
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::MGet(keys)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let values = keys
+                .iter()
+                .map(|key| match db.get(key) {
+                    GetValue::Ok(value) => Some(value),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            to_list_of_optional_bulk_strings(&values)
+        }
+
+        Ok(Command::MSet(pairs)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            for (key, value) in pairs {
+                db.set(key, value, None);
+            }
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::MSetNx(pairs)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            if pairs.iter().any(|(key, _)| db.exists(key)) {
+                return to_integer(0);
+            }
+            for (key, value) in pairs {
+                db.set(key, value, None);
+            }
+            to_integer(1)
+        }
+
+        Ok(Command::SetBit(key, offset, bit)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.string_mut(&key) {
+                Ok(value) => to_integer(set_bit(value, offset, bit) as i64),
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::GetBit(key, offset)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get_ref(&key) {
+                GetValue::Ok(value) => to_integer(get_bit(&value, offset) as i64),
+                GetValue::None => to_integer(0),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
+            }
         }
-        let response = process_request(&request);
-        match response {
-            Some(Command::Ping(response)) => {
-                stream
-                    .write_all(encode_response_as_simple_string(response.as_bytes()).as_slice())
-                    .unwrap();
+
+        Ok(Command::BitCount(key, range)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get_ref(&key) {
+                GetValue::Ok(value) => to_integer(count_bits(&value, range) as i64),
+                GetValue::None => to_integer(0),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
             }
+        }
 
-            Some(Command::Echo(response)) => {
-                stream
-                    .write_all(encode_response_as_simple_string(response.as_bytes()).as_slice())
-                    .unwrap();
+        Ok(Command::BitPos(BitPosCommand {
+            key,
+            bit,
+            start,
+            end,
+            unit,
+        })) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.get_ref(&key) {
+                GetValue::Ok(value) => to_integer(bit_position(&value, bit, start, end, unit)),
+                GetValue::None => to_integer(if bit { -1 } else { 0 }),
+                GetValue::WrongType => WRONGTYPE_ERROR.to_vec(),
             }
+        }
 
-            Some(Command::Set(set_command)) => {
-                let SetCommand { key, value, px } = set_command;
-                let mut db = db.lock().unwrap();
+        Ok(Command::BitOp(operation, destination, source_keys)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let mut sources = Vec::with_capacity(source_keys.len());
+            for key in &source_keys {
+                match db.get(key) {
+                    GetValue::Ok(value) => sources.push(value),
+                    GetValue::None => sources.push(Bytes::new()),
+                    GetValue::WrongType => return WRONGTYPE_ERROR.to_vec(),
+                }
+            }
+            let result = bit_op(operation, &sources);
+            let len = result.len();
+            if result.is_empty() {
+                db.take(&destination);
+            } else {
+                db.set(destination, Bytes::from(result), None);
+            }
+            to_integer(len as i64)
+        }
 
-                db.set(&key, &value, px);
-                stream.write_all(encode_response_as_simple_string(b"OK").as_slice())?;
+        Ok(Command::BitField(key, operations)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let replies = if operations.iter().any(BitfieldOperation::is_write) {
+                match db.string_mut(&key) {
+                    Ok(value) => run_bitfield(value, &operations),
+                    Err(WrongTypeError) => return WRONGTYPE_ERROR.to_vec(),
+                }
+            } else {
+                match db.get_ref(&key) {
+                    GetValue::Ok(value) => read_bitfield(&value, &operations),
+                    GetValue::None => read_bitfield(&[], &operations),
+                    GetValue::WrongType => return WRONGTYPE_ERROR.to_vec(),
+                }
+            };
+            let mut response = to_array_header(replies.len());
+            for reply in replies {
+                match reply {
+                    Some(integer) => response.extend_from_slice(&to_integer(integer)),
+                    None => response.extend_from_slice(b"$-1\r\n"),
+                }
             }
+            response
+        }
 
-            Some(Command::Get(key)) => {
-                let _db = db.lock().unwrap();
-                let value = _db.get(&key);
-                match value {
-                    GetValue::Error(_) => {
-                        stream.write_all(b"$-1\r\n").unwrap();
-                        let mut db = db.lock().unwrap();
-                        db.delete(&key);
+        Ok(Command::Lcs(lcs_command)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let mut strings = Vec::with_capacity(2);
+            for key in [&lcs_command.keys.0, &lcs_command.keys.1] {
+                match db.get(key) {
+                    GetValue::Ok(value) => strings.push(value),
+                    GetValue::None => strings.push(Bytes::new()),
+                    GetValue::WrongType => {
+                        return b"-ERR The specified keys must contain string values\r\n".to_vec()
                     }
-                    GetValue::Ok(value) => stream
-                        .write_all(encode_response_as_simple_string(value.as_bytes()).as_slice())?,
-                    GetValue::None => {
-                        let mut rdb = Rdb::new();
-                        if let Some(path) = config.lock().unwrap().to_file_path() {
-                            match read_rdb_file(path) {
-                                Ok(new_rdb) => rdb = new_rdb,
-                                Err(e) => {
-                                    panic!("Unable to read and parse rdb: {}", e)
-                                }
-                            }
-                        }
-                        let value = rdb.get(&key);
-                        match value {
-                            Some(value) => {
-                                if value.expiry.is_some() {
-                                    let start = SystemTime::now();
-                                    let since_the_epoch =
-                                        start.duration_since(UNIX_EPOCH).unwrap().as_millis();
-                                    if value.expiry.unwrap() as u128 <= since_the_epoch {
-                                        stream.write_all(b"$-1\r\n").unwrap();
-                                        rdb.delete(&key);
-                                        continue;
-                                    } else {
-                                        stream
-                                            .write_all(
-                                                to_bulk_string(&value.value.to_string()).as_bytes(),
-                                            )
-                                            .unwrap();
-                                    }
-                                } else {
-                                    stream
-                                        .write_all(
-                                            to_bulk_string(&value.value.to_string()).as_bytes(),
-                                        )
-                                        .unwrap();
-                                }
-                            }
-                            None => {
-                                stream.write_all(b"$-1\r\n").unwrap();
-                            }
-                        }
+                }
+            }
+            match lcs(&strings[0], &strings[1]) {
+                Some(result) => encode_lcs(&lcs_command, &result),
+                None => b"-ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len\r\n".to_vec(),
+            }
+        }
+
+        Ok(Command::GetEx(key, expiry)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let value = match db.get(&key) {
+                GetValue::Ok(value) => value,
+                GetValue::None => return b"$-1\r\n".to_vec(),
+                GetValue::WrongType => return WRONGTYPE_ERROR.to_vec(),
+            };
+            match expiry {
+                Some(GetExExpiry::At(expires_at)) if expires_at <= unix_time_millis() => {
+                    db.take(&key);
+                }
+                Some(GetExExpiry::At(expires_at)) => {
+                    db.set_expiry(&key, Some(expires_at));
+                }
+                Some(GetExExpiry::Persist) => {
+                    db.set_expiry(&key, None);
+                }
+                None => {}
+            }
+            to_bulk_string(&value)
+        }
+
+        Ok(Command::Push(end, key, elements)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match push_elements(db, &key, end, elements, false) {
+                Ok(len) => {
+                    server.blocked.lock().unwrap().signal(client.db, &key);
+                    to_integer(len as i64)
+                }
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::PushX(end, key, elements)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match push_elements(db, &key, end, elements, true) {
+                Ok(len) => {
+                    if len > 0 {
+                        server.blocked.lock().unwrap().signal(client.db, &key);
                     }
+                    to_integer(len as i64)
                 }
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
             }
+        }
 
-            Some(Command::Config(config_key)) => {
-                let config = config.lock().unwrap();
-                if let Some(config_value) = config.get(&config_key) {
-                    stream.write_all(
-                        to_list_of_bulk_strings(&[
-                            config_key.to_string(),
-                            config_value.to_string(),
-                        ])
-                        .as_bytes(),
-                    )?
-                } else {
-                    stream.write_all(b"$-1\r\n").unwrap();
+        Ok(Command::Pop(end, key, count)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match (pop_elements(db, &key, end, count.unwrap_or(1)), count) {
+                (Err(WrongTypeError), _) => WRONGTYPE_ERROR.to_vec(),
+                (Ok(None), None) => b"$-1\r\n".to_vec(),
+                (Ok(None), Some(_)) => b"*-1\r\n".to_vec(),
+                (Ok(Some(elements)), None) => to_bulk_string(&elements[0]),
+                (Ok(Some(elements)), Some(_)) => to_list_of_bulk_strings(&elements),
+            }
+        }
+
+        Ok(Command::LMPop(keys, end, count)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match pop_first_list(db, &keys, end, count) {
+                Ok(Some((key, elements))) => encode_key_and_elements(&key, &elements),
+                Ok(None) => b"*-1\r\n".to_vec(),
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::LLen(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::List(list)) => to_integer(list.len() as i64),
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => to_integer(0),
+            }
+        }
+
+        Ok(Command::LRange(key, start, end)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::List(list)) => {
+                    let elements = match resolve_range(start, end, list.len()) {
+                        Some((start, end)) => list
+                            .iter_from(start)
+                            .take(end - start + 1)
+                            .cloned()
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                    to_list_of_bulk_strings(&elements)
                 }
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => to_array_header(0),
             }
+        }
 
-            Some(Command::Keys(keys)) => {
-                if keys.as_str() == "*" {
-                    let mut rdb = Rdb::new();
-                    if let Some(path) = config.lock().unwrap().to_file_path() {
-                        match read_rdb_file(path) {
-                            Ok(new_rdb) => rdb = new_rdb,
-                            Err(e) => {
-                                panic!("Unable to read and parse rdb: {}", e)
-                            }
-                        }
+        Ok(Command::LIndex(key, index)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::List(list)) => {
+                    match resolve_index(index, list.len()).and_then(|index| list.get(index)) {
+                        Some(element) => to_bulk_string(element),
+                        None => b"$-1\r\n".to_vec(),
+                    }
+                }
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => b"$-1\r\n".to_vec(),
+            }
+        }
+
+        Ok(Command::LSet(key, index, element)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let list = match db.list_mut(&key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return b"-ERR no such key\r\n".to_vec(),
+                Err(WrongTypeError) => return WRONGTYPE_ERROR.to_vec(),
+            };
+            let Some(index) = resolve_index(index, list.len()) else {
+                return b"-ERR index out of range\r\n".to_vec();
+            };
+            list.set(index, element);
+            db.list_changed(&key, 1);
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::LRem(key, count, element)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let list = match db.list_mut(&key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return to_integer(0),
+                Err(WrongTypeError) => return WRONGTYPE_ERROR.to_vec(),
+            };
+            let removed = list.remove_matching(&element, count);
+            db.list_changed(&key, removed as u64);
+            to_integer(removed as i64)
+        }
+
+        Ok(Command::LTrim(key, start, end)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let list = match db.list_mut(&key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return encode_response_as_simple_string(b"OK"),
+                Err(WrongTypeError) => return WRONGTYPE_ERROR.to_vec(),
+            };
+            let len = list.len();
+            match resolve_range(start, end, len) {
+                Some((start, end)) => list.trim(start, end),
+                None => list.clear(),
+            }
+            let removed = len - list.len();
+            db.list_changed(&key, removed as u64);
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::LInsert(key, before, pivot, element)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let list = match db.list_mut(&key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return to_integer(0),
+                Err(WrongTypeError) => return WRONGTYPE_ERROR.to_vec(),
+            };
+            let Some(position) = list.iter().position(|candidate| *candidate == pivot) else {
+                return to_integer(-1);
+            };
+            list.insert(if before { position } else { position + 1 }, element);
+            let len = list.len();
+            db.list_changed(&key, 1);
+            to_integer(len as i64)
+        }
+
+        Ok(Command::LPos(lpos)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&lpos.key).map(DbValue::value) {
+                Some(RedisValue::List(list)) => encode_lpos(&lpos, &list_positions(list, &lpos)),
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => encode_lpos(&lpos, &[]),
+            }
+        }
+
+        Ok(Command::LMove(source, destination, from, to)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match move_element(db, &source, &destination, from, to) {
+                Ok(Some(element)) => {
+                    server
+                        .blocked
+                        .lock()
+                        .unwrap()
+                        .signal(client.db, &destination);
+                    to_bulk_string(&element)
+                }
+                Ok(None) => b"$-1\r\n".to_vec(),
+                Err(WrongTypeError) => WRONGTYPE_ERROR.to_vec(),
+            }
+        }
+
+        Ok(Command::Blocking(BlockingCommand {
+            keys,
+            operation,
+            timeout,
+        })) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            // Like the non-blocking variants, a key of the wrong type fails
+            // the command unless a list comes first.
+            let mut ready = None;
+            for key in &keys {
+                match db.lookup(key).map(DbValue::value) {
+                    Some(RedisValue::List(_)) => {
+                        ready = Some(key.clone());
+                        break;
                     }
-                    stream.write_all(to_list_of_bulk_strings(&rdb.get_keys()).as_bytes())?
+                    Some(_) => return WRONGTYPE_ERROR.to_vec(),
+                    None => {}
+                }
+            }
+            // The client is registered even if a list is there already and
+            // gets served along with any others once the command is done.
+            let mut blocked = server.blocked.lock().unwrap();
+            client.blocked = Some(blocked.block(client.db, keys, operation, timeout));
+            if let Some(key) = ready {
+                blocked.signal(client.db, &key);
+            }
+            Vec::new()
+        }
+
+        Ok(Command::Config(patterns)) => {
+            let config = server.config.lock().unwrap();
+            let reply = config
+                .matching(&patterns)
+                .into_iter()
+                .flat_map(|(name, value)| [name.to_string(), value])
+                .collect::<Vec<_>>();
+            to_list_of_bulk_strings(&reply)
+        }
+
+        Ok(Command::Keys(pattern)) => {
+            let db = &server.dbs.lock().unwrap()[client.db];
+            let mut keys = db.keys();
+            if pattern.as_ref() != b"*" {
+                keys.retain(|key| glob_match(&pattern, key, false));
+            }
+            to_list_of_bulk_strings(&keys)
+        }
+
+        Ok(Command::Save) => match server.save() {
+            Ok(()) => encode_response_as_simple_string(b"OK"),
+            Err(e) => {
+                eprintln!("Unable to save rdb: {}", e);
+                format!("-ERR {}\r\n", e).into_bytes()
+            }
+        },
+
+        Ok(Command::BgSave) => match server.bgsave() {
+            Ok(()) => encode_response_as_simple_string(b"Background saving started"),
+            Err(e) => format!("-ERR {}\r\n", e).into_bytes(),
+        },
+
+        Ok(Command::BgRewriteAof) => match server.bgrewriteaof() {
+            Ok(()) => {
+                encode_response_as_simple_string(b"Background append only file rewriting started")
+            }
+            Err(e) => format!("-ERR {}\r\n", e).into_bytes(),
+        },
+
+        Ok(Command::LastSave) => {
+            format!(":{}\r\n", server.persistence.lock().unwrap().last_save()).into_bytes()
+        }
+
+        Ok(Command::Shutdown(mode)) => {
+            let save = match mode {
+                ShutdownMode::Default => server.has_save_rules(),
+                ShutdownMode::Save => true,
+                ShutdownMode::NoSave => false,
+            };
+            if let Err(e) = server.aof.lock().unwrap().fsync() {
+                eprintln!("Unable to fsync the append only file: {}", e);
+            }
+            if save {
+                if let Err(e) = server.write_snapshot() {
+                    eprintln!("Error trying to save the DB, can't exit: {}", e);
+                    return b"-ERR Errors trying to SHUTDOWN. Check logs.\r\n".to_vec();
+                }
+            }
+            server.request_shutdown();
+            Vec::new()
+        }
+
+        Ok(Command::Select(index)) => {
+            if index >= server.dbs.lock().unwrap().len() {
+                return b"-ERR DB index is out of range\r\n".to_vec();
+            }
+            client.db = index;
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::Move(key, index)) => {
+            if index == client.db {
+                return b"-ERR source and destination objects are the same\r\n".to_vec();
+            }
+            let mut dbs = server.dbs.lock().unwrap();
+            if index >= dbs.len() {
+                return b"-ERR DB index is out of range\r\n".to_vec();
+            }
+            if !dbs[client.db].exists(&key) || dbs[index].exists(&key) {
+                return b":0\r\n".to_vec();
+            }
+            if let Some(value) = dbs[client.db].take(&key) {
+                server.blocked.lock().unwrap().signal(index, &key);
+                dbs[index].insert(key, value);
+            }
+            b":1\r\n".to_vec()
+        }
+
+        Ok(Command::SwapDb(first, second)) => {
+            let mut dbs = server.dbs.lock().unwrap();
+            if first >= dbs.len() {
+                return b"-ERR invalid first DB index\r\n".to_vec();
+            }
+            if second >= dbs.len() {
+                return b"-ERR invalid second DB index\r\n".to_vec();
+            }
+            dbs.swap(first, second);
+            let mut blocked = server.blocked.lock().unwrap();
+            blocked.signal_db(first);
+            blocked.signal_db(second);
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::FlushDb) => {
+            server.dbs.lock().unwrap()[client.db].clear();
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::FlushAll) => {
+            server
+                .dbs
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .for_each(Database::clear);
+            // Like Redis, persist the now empty dataset right away when
+            // snapshots are configured, unless the FLUSHALL is replayed.
+            if server.has_save_rules() && !server.is_loading() {
+                if let Err(e) = server.write_snapshot() {
+                    eprintln!("Unable to save rdb after FLUSHALL: {}", e);
                 }
             }
+            encode_response_as_simple_string(b"OK")
+        }
+
+        Ok(Command::DbSize) => {
+            format!(":{}\r\n", server.dbs.lock().unwrap()[client.db].len()).into_bytes()
+        }
+
+        Ok(Command::Del(keys)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let deleted = keys.iter().filter(|key| db.delete(key)).count();
+            to_integer(deleted as i64)
+        }
+
+        Ok(Command::Unlink(keys)) => {
+            let values = {
+                let db = &mut server.dbs.lock().unwrap()[client.db];
+                keys.iter()
+                    .filter_map(|key| db.take(key))
+                    .collect::<Vec<_>>()
+            };
+            let unlinked = values.len();
+            server.lazy_free.free(values);
+            to_integer(unlinked as i64)
+        }
+
+        // There is no LRU clock to update, so `TOUCH` only counts like
+        // `EXISTS`.
+        Ok(Command::Exists(keys)) | Ok(Command::Touch(keys)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let existing = keys.iter().filter(|key| db.exists(key)).count();
+            to_integer(existing as i64)
+        }
+
+        Ok(Command::Type(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let type_name = db
+                .lookup(&key)
+                .map_or("none", |entry| entry.value().type_name());
+            encode_response_as_simple_string(type_name.as_bytes())
+        }
+
+        Ok(Command::Rename(source, destination)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            server
+                .blocked
+                .lock()
+                .unwrap()
+                .signal(client.db, &destination);
+            rename_key(db, source, destination, false)
+        }
+
+        Ok(Command::RenameNx(source, destination)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            server
+                .blocked
+                .lock()
+                .unwrap()
+                .signal(client.db, &destination);
+            rename_key(db, source, destination, true)
+        }
+
+        Ok(Command::Copy(CopyCommand {
+            source,
+            destination,
+            db,
+            replace,
+        })) => {
+            let dbs = &mut server.dbs.lock().unwrap();
+            let target = db.unwrap_or(client.db);
+            if target >= dbs.len() {
+                return b"-ERR DB index is out of range\r\n".to_vec();
+            }
+            if target == client.db && source == destination {
+                return b"-ERR source and destination objects are the same\r\n".to_vec();
+            }
+            let Some(value) = dbs[client.db].lookup(&source).cloned() else {
+                return to_integer(0);
+            };
+            let target_db = target;
+            let target = &mut dbs[target_db];
+            if !replace && target.exists(&destination) {
+                return to_integer(0);
+            }
+            server
+                .blocked
+                .lock()
+                .unwrap()
+                .signal(target_db, &destination);
+            target.put(destination, value);
+            to_integer(1)
+        }
+
+        Ok(Command::Scan(scan, type_name)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let (cursor, mut keys) = db.scan(scan.cursor, scan.count);
+            if let Some(pattern) = &scan.pattern {
+                keys.retain(|key| glob_match(pattern, key, false));
+            }
+            if let Some(type_name) = type_name {
+                keys.retain(|key| {
+                    db.lookup(key)
+                        .is_some_and(|entry| entry.value().type_name() == type_name)
+                });
+            }
+            encode_scan(cursor, &keys)
+        }
 
-            None => stream.write_all(b"-ERR unknown command\r\n")?,
+        Ok(Command::HScan(key, scan)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::Hash(hash)) => {
+                    let (cursor, fields) = hash.scan(scan.cursor, scan.count);
+                    let reply = fields
+                        .into_iter()
+                        .filter(|(field, _)| scan_matches(&scan, field))
+                        .flat_map(|(field, value)| [field.clone(), value.clone()])
+                        .collect::<Vec<_>>();
+                    encode_scan(cursor, &reply)
+                }
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => encode_scan(0, &[]),
+            }
+        }
+
+        Ok(Command::SScan(key, scan)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::Set(set)) => {
+                    let (cursor, members) = set.scan(scan.cursor, scan.count);
+                    let reply = members
+                        .into_iter()
+                        .map(|(member, ())| member.clone())
+                        .filter(|member| scan_matches(&scan, member))
+                        .collect::<Vec<_>>();
+                    encode_scan(cursor, &reply)
+                }
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => encode_scan(0, &[]),
+            }
+        }
+
+        Ok(Command::ZScan(key, scan)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.lookup(&key).map(DbValue::value) {
+                Some(RedisValue::ZSet(zset)) => {
+                    let (cursor, members) = zset.scan(scan.cursor, scan.count);
+                    let reply = members
+                        .into_iter()
+                        .filter(|(member, _)| scan_matches(&scan, member))
+                        .flat_map(|(member, score)| {
+                            [member.clone(), Bytes::from(score.to_string())]
+                        })
+                        .collect::<Vec<_>>();
+                    encode_scan(cursor, &reply)
+                }
+                Some(_) => WRONGTYPE_ERROR.to_vec(),
+                None => encode_scan(0, &[]),
+            }
+        }
+
+        Ok(Command::RandomKey) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.random_key() {
+                Some(key) => to_bulk_string(&key),
+                None => b"$-1\r\n".to_vec(),
+            }
+        }
+
+        Ok(Command::Expire(ExpireCommand {
+            key,
+            expires_at,
+            flags,
+        })) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            let Some(current) = db.expiry(&key) else {
+                return b":0\r\n".to_vec();
+            };
+            let expires_at = u64::try_from(expires_at).unwrap_or(0);
+            if !flags.allow(current, expires_at) {
+                return b":0\r\n".to_vec();
+            }
+            if expires_at <= unix_time_millis() {
+                db.take(&key);
+            } else {
+                db.set_expiry(&key, Some(expires_at));
+            }
+            b":1\r\n".to_vec()
+        }
+
+        Ok(Command::Ttl(key, unit)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.expiry(&key) {
+                None => b":-2\r\n".to_vec(),
+                Some(None) => b":-1\r\n".to_vec(),
+                Some(Some(expires_at)) => {
+                    let ttl = expires_at.saturating_sub(unix_time_millis());
+                    let ttl = match unit {
+                        TimeUnit::Seconds => (ttl + 500) / 1000,
+                        TimeUnit::Milliseconds => ttl,
+                    };
+                    format!(":{}\r\n", ttl).into_bytes()
+                }
+            }
+        }
+
+        Ok(Command::ExpireTime(key, unit)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            match db.expiry(&key) {
+                None => b":-2\r\n".to_vec(),
+                Some(None) => b":-1\r\n".to_vec(),
+                Some(Some(expires_at)) => {
+                    let time = match unit {
+                        TimeUnit::Seconds => expires_at / 1000,
+                        TimeUnit::Milliseconds => expires_at,
+                    };
+                    format!(":{}\r\n", time).into_bytes()
+                }
+            }
+        }
+
+        Ok(Command::Persist(key)) => {
+            let db = &mut server.dbs.lock().unwrap()[client.db];
+            if let Some(Some(_)) = db.expiry(&key) {
+                db.set_expiry(&key, None);
+                b":1\r\n".to_vec()
+            } else {
+                b":0\r\n".to_vec()
+            }
+        }
+
+        Ok(Command::Info(sections)) => to_bulk_string(server.info(&sections).as_bytes()),
+
+        Err(e) => format!("-ERR {}\r\n", e).into_bytes(),
+    }
+}
+
+/// Whether `name` passes the `MATCH` pattern of a scan command, if any.
+fn scan_matches(scan: &ScanOptions, name: &[u8]) -> bool {
+    scan.pattern
+        .as_ref()
+        .is_none_or(|pattern| glob_match(pattern, name, false))
+}
+
+/// Encodes the reply of the scan commands: the cursor of the next call
+/// and a batch of elements.
+fn encode_scan(cursor: u64, elements: &[Bytes]) -> Vec<u8> {
+    let mut response = to_array_header(2);
+    response.extend_from_slice(&to_bulk_string(cursor.to_string().as_bytes()));
+    response.extend_from_slice(&to_list_of_bulk_strings(elements));
+    response
+}
+
+/// Runs `RENAME`, or `RENAMENX` when `nx` is set, keeping the expiry of
+/// the key.
+fn rename_key<T: Database>(db: &mut T, source: Bytes, destination: Bytes, nx: bool) -> Vec<u8> {
+    if !db.exists(&source) {
+        return b"-ERR no such key\r\n".to_vec();
+    }
+    if nx && (source == destination || db.exists(&destination)) {
+        return to_integer(0);
+    }
+    if source != destination {
+        if let Some(value) = db.take(&source) {
+            db.put(destination, value);
+        }
+    }
+    if nx {
+        to_integer(1)
+    } else {
+        encode_response_as_simple_string(b"OK")
+    }
+}
+
+/// Runs `LPUSH` and `RPUSH`, or `LPUSHX` and `RPUSHX` when
+/// `existing_only` is set. Returns the length of the list afterwards.
+fn push_elements<T: Database>(
+    db: &mut T,
+    key: &[u8],
+    end: ListEnd,
+    elements: Vec<Bytes>,
+    existing_only: bool,
+) -> Result<usize, WrongTypeError> {
+    let list = if existing_only {
+        match db.list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        }
+    } else {
+        db.list_or_insert(key)?
+    };
+    let changes = elements.len() as u64;
+    for element in elements {
+        list.push(end, element);
+    }
+    let len = list.len();
+    db.list_changed(key, changes);
+    Ok(len)
+}
+
+/// Pops up to `count` elements from `end` of the list at `key`, or returns
+/// `None` if there is no such list.
+fn pop_elements<T: Database>(
+    db: &mut T,
+    key: &[u8],
+    end: ListEnd,
+    count: usize,
+) -> Result<Option<Vec<Bytes>>, WrongTypeError> {
+    let Some(list) = db.list_mut(key)? else {
+        return Ok(None);
+    };
+    let elements = (0..count.min(list.len()))
+        .filter_map(|_| list.pop(end))
+        .collect::<Vec<_>>();
+    db.list_changed(key, elements.len() as u64);
+    Ok(Some(elements))
+}
+
+/// Pops up to `count` elements from the first of `keys` holding a list,
+/// like `LMPOP`. Returns that key along with the elements.
+fn pop_first_list<T: Database>(
+    db: &mut T,
+    keys: &[Bytes],
+    end: ListEnd,
+    count: usize,
+) -> Result<Option<(Bytes, Vec<Bytes>)>, WrongTypeError> {
+    for key in keys {
+        if let Some(elements) = pop_elements(db, key, end, count)? {
+            return Ok(Some((key.clone(), elements)));
+        }
+    }
+    Ok(None)
+}
+
+/// Runs `LMOVE`: pops an element from `from` of the list at `source` and
+/// pushes it onto `to` of the list at `destination`, which may be the same.
+/// Both types are checked before anything moves.
+fn move_element<T: Database>(
+    db: &mut T,
+    source: &[u8],
+    destination: &[u8],
+    from: ListEnd,
+    to: ListEnd,
+) -> Result<Option<Bytes>, WrongTypeError> {
+    match db.lookup(source).map(DbValue::value) {
+        Some(RedisValue::List(_)) => {}
+        Some(_) => return Err(WrongTypeError),
+        None => return Ok(None),
+    }
+    db.list_mut(destination)?;
+    let Some(element) = db.list_mut(source)?.and_then(|list| list.pop(from)) else {
+        return Ok(None);
+    };
+    db.list_or_insert(destination)?.push(to, element.clone());
+    db.list_changed(destination, 1);
+    // Deleting an emptied source waits for the push, in case it is also
+    // the destination.
+    db.list_changed(source, 1);
+    Ok(Some(element))
+}
+
+/// The positions `LPOS` replies with, in the order they were found.
+fn list_positions(list: &QuickList, lpos: &LPosCommand) -> Vec<usize> {
+    let len = list.len();
+    let elements: Box<dyn Iterator<Item = (usize, &Bytes)>> = if lpos.rank > 0 {
+        Box::new(list.iter().enumerate())
+    } else {
+        Box::new(
+            list.iter()
+                .rev()
+                .enumerate()
+                .map(move |(from_tail, element)| (len - 1 - from_tail, element)),
+        )
+    };
+    let max_len = if lpos.max_len == 0 { len } else { lpos.max_len };
+    let count = match lpos.count {
+        Some(0) => len,
+        Some(count) => count,
+        None => 1,
+    };
+    elements
+        .take(max_len)
+        .filter(|(_, element)| **element == lpos.element)
+        .map(|(position, _)| position)
+        .skip(lpos.rank.unsigned_abs() as usize - 1)
+        .take(count)
+        .collect()
+}
+
+/// Encodes the reply of `LPOS`: an array when `COUNT` was given, otherwise
+/// the first position or nil.
+fn encode_lpos(lpos: &LPosCommand, positions: &[usize]) -> Vec<u8> {
+    if lpos.count.is_none() {
+        return match positions.first() {
+            Some(&position) => to_integer(position as i64),
+            None => b"$-1\r\n".to_vec(),
+        };
+    }
+    let mut response = to_array_header(positions.len());
+    for &position in positions {
+        response.extend_from_slice(&to_integer(position as i64));
+    }
+    response
+}
+
+/// Encodes the reply of `LMPOP`: the key popped from and its elements.
+fn encode_key_and_elements(key: &[u8], elements: &[Bytes]) -> Vec<u8> {
+    let mut response = to_array_header(2);
+    response.extend_from_slice(&to_bulk_string(key));
+    response.extend_from_slice(&to_list_of_bulk_strings(elements));
+    response
+}
+
+/// Applies the subcommands of `BITFIELD` to `value` in place, returning
+/// one reply per `GET`, `SET` and `INCRBY`: `None` when an overflow made it
+/// fail.
+fn run_bitfield(value: &mut Vec<u8>, operations: &[BitfieldOperation]) -> Vec<Option<i64>> {
+    // Like Redis, a write grows the string to fit every write up front, even
+    // one that then fails.
+    let len = operations
+        .iter()
+        .filter_map(|operation| match operation {
+            BitfieldOperation::Set(field, offset, _)
+            | BitfieldOperation::IncrBy(field, offset, _) => {
+                Some((offset + field.bits as u64).div_ceil(8) as usize)
+            }
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    if value.len() < len {
+        value.resize(len, 0);
+    }
+
+    let mut overflow = Overflow::Wrap;
+    let mut replies = Vec::with_capacity(operations.len());
+    for operation in operations {
+        match *operation {
+            BitfieldOperation::Get(field, offset) => {
+                replies.push(Some(read_field(value, field, offset)));
+            }
+            BitfieldOperation::Set(field, offset, integer) => {
+                let previous = read_field(value, field, offset);
+                // Unsigned fields see negative values as huge, as in Redis.
+                let integer = if field.signed {
+                    integer as i128
+                } else {
+                    integer as u64 as i128
+                };
+                let reply = fit_field(field, integer, overflow).map(|integer| {
+                    write_field(value, field, offset, integer);
+                    previous
+                });
+                replies.push(reply);
+            }
+            BitfieldOperation::IncrBy(field, offset, increment) => {
+                let current = read_field(value, field, offset);
+                let integer = current as i128 + increment as i128;
+                let reply = fit_field(field, integer, overflow).inspect(|&integer| {
+                    write_field(value, field, offset, integer);
+                });
+                replies.push(reply);
+            }
+            BitfieldOperation::Overflow(mode) => overflow = mode,
+        }
+    }
+    replies
+}
+
+/// Runs a `BITFIELD` made of `GET` subcommands only, which leaves `value`
+/// as it is.
+fn read_bitfield(value: &[u8], operations: &[BitfieldOperation]) -> Vec<Option<i64>> {
+    operations
+        .iter()
+        .filter_map(|operation| match *operation {
+            BitfieldOperation::Get(field, offset) => Some(Some(read_field(value, field, offset))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Builds the reply of `LCS` for the options it was given.
+fn encode_lcs(command: &LcsCommand, result: &Lcs) -> Vec<u8> {
+    if command.len {
+        return to_integer(result.sequence.len() as i64);
+    }
+    if !command.idx {
+        return to_bulk_string(&result.sequence);
+    }
+    let matches = result
+        .matches
+        .iter()
+        .filter(|range| range.len() >= command.min_match_len)
+        .collect::<Vec<_>>();
+    let mut response = to_array_header(4);
+    response.extend_from_slice(&to_bulk_string(b"matches"));
+    response.extend_from_slice(&to_array_header(matches.len()));
+    for range in matches {
+        response.extend_from_slice(&to_array_header(if command.with_match_len { 3 } else { 2 }));
+        for (start, end) in [range.a, range.b] {
+            response.extend_from_slice(&to_array_header(2));
+            response.extend_from_slice(&to_integer(start as i64));
+            response.extend_from_slice(&to_integer(end as i64));
+        }
+        if command.with_match_len {
+            response.extend_from_slice(&to_integer(range.len() as i64));
+        }
+    }
+    response.extend_from_slice(&to_bulk_string(b"len"));
+    response.extend_from_slice(&to_integer(result.sequence.len() as i64));
+    response
+}
+
+/// Runs a command, logging it to the append-only file if it modified the
+/// keyspace. Clients blocked on lists the command created are served right
+/// after it, before any other command runs.
+fn execute<T: Database + Send + 'static>(
+    command: Result<Command, CommandError>,
+    client: &mut Client,
+    server: &Arc<Server<T>>,
+) -> Vec<u8> {
+    let args = command.as_ref().ok().and_then(Command::to_aof_args);
+    let blocking = matches!(command, Ok(Command::Blocking(_)));
+    if args.is_none() && !blocking {
+        return handle_command(command, client, server);
+    }
+    let mut aof = server.aof.lock().unwrap();
+    let db = client.db;
+    let response = handle_command(command, client, server);
+    let mut writes = Vec::new();
+    if let Some(args) = args.filter(|_| !response.starts_with(b"-")) {
+        writes.push((db, args));
+    }
+    writes.extend(serve_blocked_clients(server));
+    for (db, args) in writes {
+        if let Err(e) = aof.append(db, &args) {
+            eprintln!("Unable to write to the append only file: {}", e);
+        }
+    }
+    response
+}
+
+/// Serves the clients blocked on keys signaled as ready, the
+/// longest-waiting first for each key, for as long as its list lasts.
+/// Returns what they popped as the commands to log, along with their
+/// database.
+fn serve_blocked_clients<T: Database>(server: &Server<T>) -> Vec<(usize, Vec<Bytes>)> {
+    if !server.blocked.lock().unwrap().has_ready() {
+        return Vec::new();
+    }
+    let mut dbs = server.dbs.lock().unwrap();
+    let mut blocked = server.blocked.lock().unwrap();
+    let mut writes = Vec::new();
+    // Serving `BLMOVE` pushes onto another key, which may in turn be ready.
+    loop {
+        let ready = blocked.take_ready();
+        if ready.is_empty() {
+            break;
+        }
+        for (index, key) in ready {
+            let db = &mut dbs[index];
+            for id in blocked.blocked_on(index, &key) {
+                let Some(waiter) = blocked.waiter(id) else {
+                    continue;
+                };
+                if waiter.is_gone() {
+                    blocked.unblock(id);
+                    continue;
+                }
+                if !matches!(
+                    db.lookup(&key).map(DbValue::value),
+                    Some(RedisValue::List(_))
+                ) {
+                    break;
+                }
+                let operation = waiter.operation.clone();
+                let (reply, args) = serve_blocked_client(db, &key, &operation);
+                if let (Some(_), BlockingOperation::Move { destination, .. }) = (&args, &operation)
+                {
+                    blocked.signal(index, destination);
+                }
+                writes.extend(args.map(|args| (index, args)));
+                blocked.serve(id, reply);
+            }
+        }
+    }
+    writes
+}
+
+/// Pops for a client blocked on `key`, which holds a list. Returns the
+/// reply along with the non-blocking command to log for it, if anything
+/// changed.
+fn serve_blocked_client<T: Database>(
+    db: &mut T,
+    key: &Bytes,
+    operation: &BlockingOperation,
+) -> (Vec<u8>, Option<Vec<Bytes>>) {
+    match operation {
+        BlockingOperation::Pop(end) | BlockingOperation::MPop(end, _) => {
+            let count = match operation {
+                BlockingOperation::MPop(_, count) => *count,
+                _ => 1,
+            };
+            let elements = match pop_elements(db, key, *end, count) {
+                Ok(Some(elements)) => elements,
+                _ => return (WRONGTYPE_ERROR.to_vec(), None),
+            };
+            let reply = if matches!(operation, BlockingOperation::Pop(_)) {
+                to_list_of_bulk_strings(&[key.clone(), elements[0].clone()])
+            } else {
+                encode_key_and_elements(key, &elements)
+            };
+            let count = (count > 1).then_some(elements.len());
+            let args = Command::Pop(*end, key.clone(), count).to_aof_args();
+            (reply, args)
+        }
+        BlockingOperation::Move {
+            destination,
+            from,
+            to,
+        } => match move_element(db, key, destination, *from, *to) {
+            Ok(Some(element)) => {
+                let command = Command::LMove(key.clone(), destination.clone(), *from, *to);
+                (to_bulk_string(&element), command.to_aof_args())
+            }
+            _ => (WRONGTYPE_ERROR.to_vec(), None),
+        },
+    }
+}
+
+/// Waits until the blocked client is served, its timeout passes or it
+/// disconnects, without holding up the runtime. Returns `None` in the last
+/// case.
+async fn wait_until_served<T: Database>(
+    connection: &mut Connection,
+    server: &Server<T>,
+    mut wait: BlockedWait,
+) -> Result<Option<Vec<u8>>> {
+    let timeout = async {
+        match wait.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let disconnected = tokio::select! {
+        reply = &mut wait.receiver => {
+            return Ok(Some(reply.unwrap_or_else(|_| TIMEOUT_REPLY.to_vec())));
+        }
+        _ = timeout => false,
+        // A connection that fails is as good as closed.
+        _ = connection.closed() => true,
+    };
+    // The client may have been served just before it gave up, in which case
+    // the reply is waiting in the channel.
+    let reply = if server.blocked.lock().unwrap().unblock(wait.id) {
+        TIMEOUT_REPLY.to_vec()
+    } else {
+        wait.receiver
+            .try_recv()
+            .unwrap_or_else(|_| TIMEOUT_REPLY.to_vec())
+    };
+    Ok((!disconnected).then_some(reply))
+}
+
+/// Applies the commands read from the append-only file, before any client
+/// connects.
+fn replay_aof_commands<T: Database + Send + 'static>(
+    server: &Arc<Server<T>>,
+    commands: Vec<Value>,
+) {
+    server.start_loading();
+    let mut client = Client::default();
+    for command in commands {
+        let response = handle_command(process_request(command), &mut client, server);
+        if response.starts_with(b"-") {
+            eprintln!(
+                "Error replaying the append only file: {}",
+                String::from_utf8_lossy(&response).trim_end()
+            );
+        }
+    }
+    server.stop_loading();
+}
+
+async fn handle_connection<T: Database + Send + 'static>(
+    stream: TcpStream,
+    server: Arc<Server<T>>,
+) -> Result<()> {
+    let mut connection = Connection::new(stream);
+    let mut client = Client::default();
+    while let Some(request) = connection.read_value().await? {
+        let command = process_request(request);
+        let mut response = execute(command, &mut client, &server);
+        if let Some(wait) = client.blocked.take() {
+            match wait_until_served(&mut connection, &server, wait).await? {
+                Some(reply) => response = reply,
+                None => break,
+            }
+        }
+        if !response.is_empty() {
+            connection.write_all(&response).await?;
         }
     }
     Ok(())
@@ -197,33 +1449,337 @@ async fn handle_connection<T: Database>(
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:6379").unwrap_or_else(|e| {
-        panic!("failed to bind to socket: {}", e);
-    });
+    let listener = TcpListener::bind("127.0.0.1:6379")
+        .await
+        .unwrap_or_else(|e| {
+            panic!("failed to bind to socket: {}", e);
+        });
 
     let args = Args::parse();
 
-    let db = Arc::new(Mutex::new(RedisDatabase::new()));
-    let config = Arc::new(Mutex::new(Config::new(args.dir, args.dbfilename)));
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let db = Arc::clone(&db);
-                let config = Arc::clone(&config);
-                tokio::task::spawn(async move {
-                    match handle_connection(stream, db, config).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Failure while handling connection: {}", e);
+    let save_rules = Config::parse_save_rules(args.save.as_deref())?;
+    let mut config = Config::new(args.dir, args.dbfilename, save_rules);
+    if let Some(appendonly) = args.appendonly {
+        config.appendonly = Config::parse_yes_no(&appendonly)?;
+    }
+    if let Some(appendfilename) = args.appendfilename {
+        config.appendfilename = appendfilename;
+    }
+    if let Some(appendfsync) = args.appendfsync {
+        config.appendfsync = appendfsync;
+    }
+    if let Some(rdbchecksum) = args.rdbchecksum {
+        config.rdbchecksum = Config::parse_yes_no(&rdbchecksum)?;
+    }
+    if let Some(databases) = args.databases {
+        if databases == 0 {
+            anyhow::bail!("databases must be at least 1");
+        }
+        config.databases = databases;
+    }
+
+    let mut dbs = Databases::new(config.databases, RedisDatabase::new);
+    let aof_path = config.to_aof_path();
+    let replay_aof = config.appendonly && aof_path.exists();
+    let path = config.to_file_path();
+    // With the append-only file enabled it is the source of truth and is
+    // replayed instead of loading the RDB file.
+    let mut commands = Vec::new();
+    if replay_aof {
+        let aof_file = read_aof_file(&aof_path, config.rdbchecksum)
+            .map_err(|e| anyhow::anyhow!("Unable to read the append only file: {}", e))?;
+        if let Some(preamble) = aof_file.preamble {
+            log_rdb_info(&preamble);
+            insert_rdb(&mut dbs, preamble)
+                .map_err(|e| anyhow::anyhow!("Unable to load the append only file: {}", e))?;
+        }
+        commands = aof_file.commands;
+    } else if path.exists() {
+        load_rdb(&mut dbs, &path, config.rdbchecksum)
+            .map_err(|e| anyhow::anyhow!("Unable to read and parse rdb: {}", e))?;
+    }
+
+    let aof = Aof::disabled(aof_path.clone(), config.appendfsync);
+    let appendfsync = config.appendfsync;
+    let appendonly = config.appendonly;
+    let server = Arc::new(Server::new(dbs, config, aof));
+
+    // The log is only opened once replay is done so the replayed commands
+    // are not logged a second time.
+    replay_aof_commands(&server, commands);
+    if appendonly {
+        *server.aof.lock().unwrap() = Aof::open(aof_path, appendfsync)?;
+        if !replay_aof {
+            // Seed a fresh log with whatever the RDB file contained.
+            server.bgrewriteaof()?;
+        }
+    }
+
+    let cron_server = Arc::clone(&server);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CRON_INTERVAL);
+        loop {
+            interval.tick().await;
+            cron_server.active_expire_cycle(CRON_INTERVAL);
+            cron_server.save_if_needed();
+            cron_server.fsync_aof_if_needed();
+        }
+    });
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let server = Arc::clone(&server);
+                    tokio::task::spawn(async move {
+                        match handle_connection(stream, server).await {
+                            Ok(_) => {}
+                            Err(e) => {
+                                eprintln!("Failure while handling connection: {}", e);
+                            }
                         }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("failed to read stream:  {e}");
+                }
+            },
+            _ = server.shutdown_requested() => break,
+            _ = shutdown_signal(&mut sigterm) => {
+                if let Err(e) = server.aof.lock().unwrap().fsync() {
+                    eprintln!("Unable to fsync the append only file: {}", e);
+                }
+                if server.has_save_rules() {
+                    if let Err(e) = server.write_snapshot() {
+                        eprintln!("Error trying to save the DB, can't exit: {}", e);
+                        continue;
                     }
-                });
-            }
-            Err(e) => {
-                eprintln!("failed to read stream:  {e}");
+                }
+                break;
             }
         }
     }
     Ok(())
 }
+
+async fn shutdown_signal(sigterm: &mut Signal) {
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Starts a server without persistence, returning it and its address.
+    async fn start_server() -> (Arc<Server<RedisDatabase>>, std::net::SocketAddr) {
+        let dbs = Databases::new(16, RedisDatabase::new);
+        let aof = Aof::disabled(PathBuf::from("appendonly.aof"), AppendFsync::EverySec);
+        let server = Arc::new(Server::new(dbs, Config::new(None, None, Vec::new()), aof));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepting = Arc::clone(&server);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(stream, Arc::clone(&accepting)));
+            }
+        });
+        (server, address)
+    }
+
+    async fn send(stream: &mut TcpStream, args: &[&str]) {
+        let args = args
+            .iter()
+            .map(|arg| Bytes::from(arg.to_string()))
+            .collect::<Vec<_>>();
+        stream
+            .write_all(&to_list_of_bulk_strings(&args))
+            .await
+            .unwrap();
+    }
+
+    /// Reads exactly `expected.len()` bytes and checks they match.
+    async fn expect_reply(stream: &mut TcpStream, expected: &[u8]) {
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    async fn call(address: std::net::SocketAddr, args: &[&str], expected: &[u8]) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        send(&mut stream, args).await;
+        expect_reply(&mut stream, expected).await;
+    }
+
+    async fn until_blocked(server: &Server<RedisDatabase>, clients: usize) {
+        while server.blocked.lock().unwrap().len() != clients {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_commands_time_out_with_a_null_array() {
+        let (server, address) = start_server().await;
+        call(address, &["BLPOP", "list", "0.05"], b"*-1\r\n").await;
+        call(
+            address,
+            &["BLMOVE", "list", "other", "LEFT", "RIGHT", "0.05"],
+            b"*-1\r\n",
+        )
+        .await;
+        assert_eq!(server.blocked.lock().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn waiting_clients_are_served_in_order() {
+        let (server, address) = start_server().await;
+        let mut first = TcpStream::connect(address).await.unwrap();
+        send(&mut first, &["BLPOP", "list", "0"]).await;
+        until_blocked(&server, 1).await;
+        let mut second = TcpStream::connect(address).await.unwrap();
+        send(&mut second, &["BLPOP", "list", "0"]).await;
+        until_blocked(&server, 2).await;
+
+        call(address, &["RPUSH", "list", "a"], b":1\r\n").await;
+        expect_reply(&mut first, b"*2\r\n$4\r\nlist\r\n$1\r\na\r\n").await;
+        call(address, &["RPUSH", "list", "b"], b":1\r\n").await;
+        expect_reply(&mut second, b"*2\r\n$4\r\nlist\r\n$1\r\nb\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn one_push_serves_as_many_clients_as_it_has_elements() {
+        let (server, address) = start_server().await;
+        let mut first = TcpStream::connect(address).await.unwrap();
+        send(&mut first, &["BLPOP", "list", "0"]).await;
+        until_blocked(&server, 1).await;
+        let mut second = TcpStream::connect(address).await.unwrap();
+        send(&mut second, &["BLPOP", "list", "0"]).await;
+        until_blocked(&server, 2).await;
+
+        call(address, &["RPUSH", "list", "a", "b", "c"], b":3\r\n").await;
+        expect_reply(&mut first, b"*2\r\n$4\r\nlist\r\n$1\r\na\r\n").await;
+        expect_reply(&mut second, b"*2\r\n$4\r\nlist\r\n$1\r\nb\r\n").await;
+        call(
+            address,
+            &["LRANGE", "list", "0", "-1"],
+            b"*1\r\n$1\r\nc\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn multi_key_waits_are_served_from_any_key() {
+        let (server, address) = start_server().await;
+        let mut client = TcpStream::connect(address).await.unwrap();
+        send(&mut client, &["BLPOP", "first", "second", "0"]).await;
+        until_blocked(&server, 1).await;
+
+        call(address, &["LPUSH", "second", "a"], b":1\r\n").await;
+        expect_reply(&mut client, b"*2\r\n$6\r\nsecond\r\n$1\r\na\r\n").await;
+        assert_eq!(server.blocked.lock().unwrap().len(), 0);
+        // The client was forgotten for the other key too.
+        call(address, &["LPUSH", "first", "b"], b":1\r\n").await;
+        call(address, &["LLEN", "first"], b":1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn blocked_moves_are_served() {
+        let (server, address) = start_server().await;
+        let mut client = TcpStream::connect(address).await.unwrap();
+        send(&mut client, &["BLMOVE", "from", "to", "LEFT", "RIGHT", "0"]).await;
+        until_blocked(&server, 1).await;
+
+        call(address, &["RPUSH", "from", "a"], b":1\r\n").await;
+        expect_reply(&mut client, b"$1\r\na\r\n").await;
+        call(address, &["LRANGE", "to", "0", "-1"], b"*1\r\n$1\r\na\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn clients_disconnecting_while_blocked_are_removed() {
+        let (server, address) = start_server().await;
+        let mut client = TcpStream::connect(address).await.unwrap();
+        send(&mut client, &["BLPOP", "list", "0"]).await;
+        until_blocked(&server, 1).await;
+        drop(client);
+        until_blocked(&server, 0).await;
+
+        call(address, &["RPUSH", "list", "a"], b":1\r\n").await;
+        call(address, &["LLEN", "list"], b":1\r\n").await;
+    }
+
+    fn array(args: &[&str]) -> Value {
+        let args = args
+            .iter()
+            .map(|arg| Value::String(Bytes::from(arg.to_string())))
+            .collect();
+        Value::Array(args)
+    }
+
+    fn request(args: &[&str]) -> Result<Command, CommandError> {
+        process_request(array(args))
+    }
+
+    #[test]
+    fn pushx_wakes_blocked_clients() {
+        let dbs = Databases::new(16, RedisDatabase::new);
+        let aof = Aof::disabled(PathBuf::from("appendonly.aof"), AppendFsync::EverySec);
+        let server = Arc::new(Server::new(dbs, Config::new(None, None, Vec::new()), aof));
+        let mut client = Client::default();
+        // A client blocks on an empty key that a push then fills without
+        // serving it, the only way it can wait on an existing list.
+        let mut wait = server.blocked.lock().unwrap().block(
+            0,
+            vec![Bytes::from("list")],
+            BlockingOperation::Pop(ListEnd::Left),
+            None,
+        );
+        handle_command(request(&["RPUSH", "list", "a"]), &mut client, &server);
+        server.blocked.lock().unwrap().take_ready();
+
+        let reply = execute(request(&["RPUSHX", "list", "b"]), &mut client, &server);
+        assert_eq!(reply, b":2\r\n");
+        assert_eq!(
+            wait.receiver.try_recv().unwrap(),
+            b"*2\r\n$4\r\nlist\r\n$1\r\na\r\n"
+        );
+        assert_eq!(server.blocked.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn replaying_the_aof_neither_saves_nor_counts_writes() {
+        let dir = std::env::temp_dir().join(format!("redis-starter-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let save = Config::parse_save_rules(Some("1 1")).unwrap();
+        let config = Config::new(Some(dir.clone()), None, save);
+        let aof = Aof::disabled(dir.join("appendonly.aof"), AppendFsync::EverySec);
+        let dbs = Databases::new(16, RedisDatabase::new);
+        let server = Arc::new(Server::new(dbs, config, aof));
+
+        let commands = [&["SET", "a", "1"][..], &["FLUSHALL"], &["SET", "b", "2"]]
+            .iter()
+            .map(|args| array(args))
+            .collect();
+        replay_aof_commands(&server, commands);
+        let snapshot_written = dir.join("dump.rdb").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!snapshot_written);
+        assert!(!server.is_loading());
+        assert_eq!(server.dbs.lock().unwrap()[0].len(), 1);
+        assert!(server
+            .dbs
+            .lock()
+            .unwrap()
+            .dirty()
+            .iter()
+            .all(|&dirty| dirty == 0));
+    }
+}
@@ -1,35 +1,140 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
-use crate::response::Value;
+use crate::{
+    compact::{decode_intset, decode_listpack, decode_ziplist, decode_zipmap},
+    crc64::crc64,
+    db::RedisValue,
+    lzf,
+    quicklist::QuickList,
+    scan::ScanMap,
+};
+use bytes::Bytes;
 use thiserror::Error;
 
+/// Oldest and newest RDB format versions the parser understands; version 12
+/// is written by Redis 7.4.
+pub const RDB_MIN_VERSION: u32 = 1;
+pub const RDB_MAX_VERSION: u32 = 12;
+
+pub const OPCODE_SLOT_INFO: u8 = 0xF4;
+pub const OPCODE_FUNCTION2: u8 = 0xF5;
+pub const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+pub const OPCODE_MODULE_AUX: u8 = 0xF7;
+pub const OPCODE_IDLE: u8 = 0xF8;
+pub const OPCODE_FREQ: u8 = 0xF9;
+pub const OPCODE_AUX: u8 = 0xFA;
+pub const OPCODE_RESIZEDB: u8 = 0xFB;
+pub const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+pub const OPCODE_EXPIRETIME: u8 = 0xFD;
+pub const OPCODE_SELECTDB: u8 = 0xFE;
+pub const OPCODE_EOF: u8 = 0xFF;
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_HASH_ZIPMAP: u8 = 9;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+pub const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Upper bound on the capacity reserved from a `RESIZEDB` hint, so a corrupt
+/// file cannot make the loader allocate an absurd amount up front.
+const MAX_RESIZE_HINT: usize = 1 << 20;
+
+/// Node containers of a `TYPE_LIST_QUICKLIST_2` list.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Opcodes inside a serialized module value.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
 #[derive(Error, Debug)]
 pub enum RDBError {
     #[error("invalid magic number")]
     InvalidMagicNumber,
     #[error("invalid version")]
     InvalidVersion,
+    #[error("unsupported RDB version {0}; versions {min} to {max} are supported", min = RDB_MIN_VERSION, max = RDB_MAX_VERSION)]
+    UnsupportedVersion(u32),
+    #[error("opcode {opcode:#04x} is not valid in RDB version {version}")]
+    UnexpectedOpcode { opcode: u8, version: u32 },
+    #[error("unsupported opcode {0:#04x}")]
+    UnsupportedOpcode(u8),
+    #[error("invalid module value")]
+    InvalidModuleValue,
+    #[error("invalid {0} encoding")]
+    InvalidCompactEncoding(&'static str),
+    #[error("invalid double value")]
+    InvalidDouble,
     #[error("invalid length")]
     InvalidLength,
-    #[error("invalid string")]
-    InvalidString,
     #[error("invalid type")]
     InvalidType,
+    #[error("invalid string encoding {0}")]
+    InvalidEncoding(u8),
+    #[error("invalid LZF compressed string")]
+    InvalidCompressedString,
     #[error("unexpected EOF")]
     UnexpectedEOF,
+    #[error("checksum mismatch: file says {expected:#018x}, contents hash to {actual:#018x}")]
+    ChecksumMismatch { expected: u64, actual: u64 },
 }
 
 #[derive(Debug)]
 pub struct RdbValue {
-    pub value: Value,
+    pub value: RedisValue,
+    /// Absolute unix time in milliseconds.
     pub expiry: Option<u64>,
 }
 
+/// The keys stored under one `SELECTDB` index.
+#[derive(Debug, Default)]
+pub struct RdbDatabase {
+    data: HashMap<Bytes, RdbValue>,
+}
+
+impl RdbDatabase {
+    /// Number of keys in the database, e.g. to reserve room before loading.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn into_entries(self) -> impl Iterator<Item = (Bytes, RdbValue)> {
+        self.data.into_iter()
+    }
+}
+
 #[derive(Debug)]
 pub struct Rdb {
-    version: u8,
+    version: u32,
+    /// Index selected by the last `SELECTDB`; keys before any `SELECTDB`
+    /// belong to database 0.
     db: u32,
-    data: HashMap<String, RdbValue>,
+    databases: BTreeMap<u32, RdbDatabase>,
+    /// Metadata stored in `AUX` fields, e.g. the version of Redis that
+    /// wrote the file.
+    aux: HashMap<Bytes, Bytes>,
 }
 
 impl Rdb {
@@ -37,137 +142,274 @@ impl Rdb {
         Rdb {
             version: 0,
             db: 0,
-            data: HashMap::new(),
+            databases: BTreeMap::new(),
+            aux: HashMap::new(),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn set_db(&mut self, db: u32) {
-        self.db = db;
+    pub fn version(&self) -> u32 {
+        self.version
     }
 
-    #[allow(dead_code)]
-    pub fn current_db(&self) -> u32 {
-        self.db
-    }
-
-    pub fn add_object(&mut self, key: String, value: Value, expiry: Option<u64>) {
-        if let Some(expiry) = expiry {
-            self.data.insert(
-                key,
-                RdbValue {
-                    value,
-                    expiry: Some(expiry),
-                },
-            );
-        } else {
-            self.data.insert(
-                key,
-                RdbValue {
-                    value,
-                    expiry: None,
-                },
-            );
-        }
+    pub fn set_aux(&mut self, key: Bytes, value: Bytes) {
+        self.aux.insert(key, value);
     }
 
-    pub fn get_keys(&self) -> Vec<String> {
-        self.data.keys().cloned().collect()
+    fn aux_str(&self, key: &str) -> Option<&str> {
+        self.aux
+            .get(key.as_bytes())
+            .and_then(|value| std::str::from_utf8(value).ok())
     }
 
-    pub fn get(&self, key: &str) -> Option<&RdbValue> {
-        self.data.get(key)
+    /// Version of the Redis server that wrote the file.
+    pub fn redis_ver(&self) -> Option<&str> {
+        self.aux_str("redis-ver")
     }
 
-    pub fn delete(&mut self, key: &str) -> Option<RdbValue> {
-        self.data.remove(key)
+    /// Unix time in seconds at which the file was created.
+    pub fn ctime(&self) -> Option<u64> {
+        self.aux_str("ctime")?.parse().ok()
+    }
+
+    /// Memory used by the writing server, in bytes.
+    pub fn used_mem(&self) -> Option<u64> {
+        self.aux_str("used-mem")?.parse().ok()
+    }
+
+    /// Replication ID of the writing server.
+    pub fn repl_id(&self) -> Option<&str> {
+        self.aux_str("repl-id")
+    }
+
+    pub fn set_db(&mut self, db: u32) {
+        self.db = db;
+    }
+
+    /// Reserves room for the number of keys a `RESIZEDB` hint announces for
+    /// the current database.
+    pub fn resize_db(&mut self, db_size: u64) {
+        let database = self.databases.entry(self.db).or_default();
+        database
+            .data
+            .reserve(usize::try_from(db_size).unwrap_or(0).min(MAX_RESIZE_HINT));
+    }
+
+    pub fn add_object(&mut self, key: Bytes, value: RedisValue, expiry: Option<u64>) {
+        self.databases
+            .entry(self.db)
+            .or_default()
+            .data
+            .insert(key, RdbValue { value, expiry });
+    }
+
+    /// Consumes the file, yielding every non-empty database with its index.
+    pub fn into_databases(self) -> impl Iterator<Item = (u32, RdbDatabase)> {
+        self.databases
+            .into_iter()
+            .filter(|(_, database)| !database.is_empty())
     }
 }
 
 pub struct RDBParser<'a> {
     buf: &'a [u8],
     pos: usize,
+    version: u32,
+    verify_checksum: bool,
 }
 
 impl RDBParser<'_> {
-    pub fn new(buf: &[u8]) -> RDBParser {
-        RDBParser { buf, pos: 0 }
+    pub fn new(buf: &[u8]) -> RDBParser<'_> {
+        RDBParser {
+            buf,
+            pos: 0,
+            version: 0,
+            verify_checksum: true,
+        }
+    }
+
+    /// Controls whether the CRC64 footer is checked against the contents,
+    /// which costs a full pass over the file.
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
     }
 
     pub fn parse(&mut self) -> Result<Rdb, RDBError> {
         let mut rdb = Rdb::new();
         self.parse_header(&mut rdb)?;
         self.parse_body(&mut rdb)?;
+        self.parse_checksum()?;
         Ok(rdb)
     }
 
+    /// Reads the CRC64 footer that follows the EOF opcode since RDB version
+    /// 5. A checksum of zero means the writer had checksums disabled.
+    fn parse_checksum(&mut self) -> Result<(), RDBError> {
+        if self.version < 5 {
+            return Ok(());
+        }
+        let end = self.pos;
+        let mut buf = [0u8; 8];
+        self.read(&mut buf)?;
+        let expected = u64::from_le_bytes(buf);
+        if !self.verify_checksum || expected == 0 {
+            return Ok(());
+        }
+        let actual = crc64(0, &self.buf[..end]);
+        if actual != expected {
+            return Err(RDBError::ChecksumMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Number of bytes consumed so far; after `parse` this is the length of
+    /// the RDB payload, so data following it (an AOF tail) can be located.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
     fn parse_header(&mut self, rdb: &mut Rdb) -> Result<(), RDBError> {
         let mut buf = [0u8; 9];
         self.read(&mut buf)?;
         if &buf[0..5] != b"REDIS" {
             return Err(RDBError::InvalidMagicNumber);
         }
-        let version = &buf[5..];
-        if version != b"0003" {
-            return Err(RDBError::InvalidVersion);
+        let version = std::str::from_utf8(&buf[5..])
+            .ok()
+            .filter(|version| version.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or(RDBError::InvalidVersion)?;
+        if !(RDB_MIN_VERSION..=RDB_MAX_VERSION).contains(&version) {
+            return Err(RDBError::UnsupportedVersion(version));
+        }
+        self.version = version;
+        rdb.version = version;
+        Ok(())
+    }
+
+    /// Reads the expiry that follows `OPCODE_EXPIRETIME` (seconds, 4 bytes)
+    /// or `OPCODE_EXPIRETIME_MS` (milliseconds, 8 bytes) as absolute unix
+    /// milliseconds.
+    fn read_expiry(&mut self, opcode: u8) -> Result<u64, RDBError> {
+        if opcode == OPCODE_EXPIRETIME {
+            let mut buf = [0u8; 4];
+            self.read(&mut buf)?;
+            Ok(u64::from(u32::from_le_bytes(buf)) * 1000)
+        } else {
+            let mut buf = [0u8; 8];
+            self.read(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+    }
+
+    /// Rejects opcodes that did not exist yet in the file's RDB version.
+    fn require_version(&self, opcode: u8, since: u32) -> Result<(), RDBError> {
+        if self.version < since {
+            return Err(RDBError::UnexpectedOpcode {
+                opcode,
+                version: self.version,
+            });
         }
-        rdb.version = version[1] - b'0';
         Ok(())
     }
 
     fn parse_body(&mut self, rdb: &mut Rdb) -> Result<(), RDBError> {
+        let mut expiry = None;
         loop {
             let byte = self.read_byte()?;
-            if byte == 0xFF {
-                break;
-            }
-            if byte == 0xFA {
-                while let Ok(byte) = self.read_byte() {
-                    if byte == 0xFF || byte == 0xFA || byte == 0xFE {
-                        self.pos -= 1;
-                        break;
-                    }
+            match byte {
+                OPCODE_EOF => break,
+                OPCODE_AUX => {
+                    self.require_version(byte, 7)?;
+                    let key = self.read_string()?;
+                    let value = self.read_string()?;
+                    rdb.set_aux(key, value);
+                }
+                OPCODE_SELECTDB => {
+                    let db_number = self.read_length()?;
+                    rdb.set_db(db_number as u32);
+                }
+                OPCODE_RESIZEDB => {
+                    self.require_version(byte, 7)?;
+                    let db_size = self.read_length()?;
+                    // The number of keys with an expiry; only Redis' separate
+                    // expires dictionary needs it.
+                    self.read_length()?;
+                    rdb.resize_db(db_size);
+                }
+                OPCODE_EXPIRETIME => expiry = Some(self.read_expiry(byte)?),
+                OPCODE_EXPIRETIME_MS => {
+                    self.require_version(byte, 3)?;
+                    expiry = Some(self.read_expiry(byte)?);
+                }
+                // LRU idle time and LFU frequency of the next key; eviction
+                // metadata the server does not use.
+                OPCODE_IDLE => {
+                    self.require_version(byte, 9)?;
+                    self.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.require_version(byte, 9)?;
+                    self.read_byte()?;
+                }
+                OPCODE_MODULE_AUX => {
+                    self.require_version(byte, 9)?;
+                    self.skip_module_aux()?;
+                }
+                // Function libraries are not supported, so their code is
+                // skipped.
+                OPCODE_FUNCTION2 => {
+                    self.require_version(byte, 10)?;
+                    self.read_string()?;
+                }
+                OPCODE_FUNCTION_PRE_GA => return Err(RDBError::UnsupportedOpcode(byte)),
+                // Cluster slot sizing hints.
+                OPCODE_SLOT_INFO => {
+                    self.require_version(byte, 12)?;
+                    self.read_length()?;
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                _ => {
+                    let key = self.read_string()?;
+                    let value = self.read_object(byte)?;
+                    rdb.add_object(key, value, expiry.take());
                 }
-                continue;
-            }
-
-            if byte == 0xFE {
-                let db_number = self.read_length()?;
-                rdb.set_db(db_number);
-                continue;
             }
+        }
+        Ok(())
+    }
 
-            if byte == 0xFB {
-                let mut buf = [0u8; 2];
-                self.read(&mut buf)?;
-                continue;
-            }
-            if byte == 0xFD {
-                let mut buf = [0u8; 4];
-                self.read(&mut buf)?;
-                let expiry_in_ms = (u32::from_le_bytes(buf) * 1000) as u64;
-                let byte = self.read_byte()?;
-                let key = self.read_string()?;
-                let value = self.read_object(byte)?;
-                rdb.add_object(key, value, Some(expiry_in_ms));
-                continue;
-            }
+    /// Skips auxiliary data of a module that is not loaded: the module id,
+    /// the `when` marker and the module's serialized value.
+    fn skip_module_aux(&mut self) -> Result<(), RDBError> {
+        self.read_length()?;
+        if self.read_length()? != MODULE_OPCODE_UINT {
+            return Err(RDBError::InvalidModuleValue);
+        }
+        self.read_length()?;
+        self.skip_module_value()
+    }
 
-            if byte == 0xFC {
-                let mut buf = [0u8; 8];
-                self.read(&mut buf)?;
-                let expiry_in_ms = u64::from_le_bytes(buf);
-                let byte = self.read_byte()?;
-                let key = self.read_string()?;
-                let value = self.read_object(byte)?;
-                rdb.add_object(key, value, Some(expiry_in_ms));
-                continue;
+    fn skip_module_value(&mut self) -> Result<(), RDBError> {
+        loop {
+            match self.read_length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.read_length()?;
+                }
+                MODULE_OPCODE_FLOAT => {
+                    self.read_bytes(4)?;
+                }
+                MODULE_OPCODE_DOUBLE => {
+                    self.read_bytes(8)?;
+                }
+                MODULE_OPCODE_STRING => {
+                    self.read_string()?;
+                }
+                _ => return Err(RDBError::InvalidModuleValue),
             }
-            let key = self.read_string()?;
-            let value = self.read_object(byte)?;
-            rdb.add_object(key, value, None);
         }
-        Ok(())
     }
 
     #[allow(dead_code)]
@@ -198,31 +440,551 @@ impl RDBParser<'_> {
         Ok(buf[0])
     }
 
-    fn read_length(&mut self) -> Result<u32, RDBError> {
+    /// Reads a length prefix. The two most significant bits of the first
+    /// byte select the form: `00` is a 6-bit length, `01` a 14-bit length
+    /// spread over two bytes, `10` a 32-bit (`0x80`) or 64-bit (`0x81`)
+    /// big-endian length in the following bytes, and `11` marks a specially
+    /// encoded string whose format is in the remaining 6 bits. The flag in
+    /// the result tells whether the value is such an encoding.
+    fn read_length_or_encoding(&mut self) -> Result<(u64, bool), RDBError> {
         let byte = self.read_byte()?;
-        match byte {
-            0..=253 => Ok(byte as u32),
-            254 => {
+        match byte >> 6 {
+            0b00 => Ok(((byte & 0x3F) as u64, false)),
+            0b01 => {
+                let next = self.read_byte()?;
+                Ok(((((byte & 0x3F) as u64) << 8) | next as u64, false))
+            }
+            0b10 => match byte {
+                0x80 => {
+                    let mut buf = [0u8; 4];
+                    self.read(&mut buf)?;
+                    Ok((u32::from_be_bytes(buf) as u64, false))
+                }
+                0x81 => {
+                    let mut buf = [0u8; 8];
+                    self.read(&mut buf)?;
+                    Ok((u64::from_be_bytes(buf), false))
+                }
+                _ => Err(RDBError::InvalidLength),
+            },
+            _ => Ok(((byte & 0x3F) as u64, true)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64, RDBError> {
+        match self.read_length_or_encoding()? {
+            (length, false) => Ok(length),
+            (_, true) => Err(RDBError::InvalidLength),
+        }
+    }
+
+    fn read_bytes(&mut self, length: u64) -> Result<Vec<u8>, RDBError> {
+        let length = usize::try_from(length).map_err(|_| RDBError::InvalidLength)?;
+        if length > self.buf.len() - self.pos {
+            return Err(RDBError::UnexpectedEOF);
+        }
+        let mut buf = vec![0u8; length];
+        self.read(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads a string, which is either length-prefixed raw bytes or one of
+    /// the special encodings: 8, 16 or 32-bit little-endian integers stored
+    /// as their decimal representation, or an LZF compressed string.
+    fn read_string(&mut self) -> Result<Bytes, RDBError> {
+        match self.read_length_or_encoding()? {
+            (length, false) => Ok(Bytes::from(self.read_bytes(length)?)),
+            (0, true) => {
+                let value = self.read_byte()? as i8;
+                Ok(Bytes::from(value.to_string()))
+            }
+            (1, true) => {
+                let mut buf = [0u8; 2];
+                self.read(&mut buf)?;
+                Ok(Bytes::from(i16::from_le_bytes(buf).to_string()))
+            }
+            (2, true) => {
                 let mut buf = [0u8; 4];
                 self.read(&mut buf)?;
-                Ok(u32::from_le_bytes(buf))
+                Ok(Bytes::from(i32::from_le_bytes(buf).to_string()))
             }
-            _ => Err(RDBError::InvalidLength),
+            (3, true) => {
+                let compressed_length = self.read_length()?;
+                let length = self.read_length()?;
+                let compressed = self.read_bytes(compressed_length)?;
+                let length = usize::try_from(length).map_err(|_| RDBError::InvalidLength)?;
+                lzf::decompress(&compressed, length)
+                    .map(Bytes::from)
+                    .ok_or(RDBError::InvalidCompressedString)
+            }
+            (encoding, _) => Err(RDBError::InvalidEncoding(encoding as u8)),
         }
     }
 
-    fn read_string(&mut self) -> Result<String, RDBError> {
+    fn read_object(&mut self, object_type: u8) -> Result<RedisValue, RDBError> {
+        match object_type {
+            TYPE_STRING => Ok(RedisValue::string(self.read_string()?)),
+            TYPE_LIST => Ok(RedisValue::List(Arc::new(self.read_strings()?.into()))),
+            TYPE_SET => Ok(RedisValue::Set(Arc::new(
+                self.read_strings()?.into_iter().collect(),
+            ))),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let length = self.read_length()?;
+                let mut zset = ScanMap::new();
+                for _ in 0..length {
+                    let member = self.read_string()?;
+                    let score = if object_type == TYPE_ZSET_2 {
+                        let mut buf = [0u8; 8];
+                        self.read(&mut buf)?;
+                        f64::from_le_bytes(buf)
+                    } else {
+                        self.read_double_string()?
+                    };
+                    zset.insert(member, score);
+                }
+                Ok(RedisValue::ZSet(Arc::new(zset)))
+            }
+            TYPE_HASH => {
+                let length = self.read_length()?;
+                let mut hash = ScanMap::new();
+                for _ in 0..length {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    hash.insert(field, value);
+                }
+                Ok(RedisValue::Hash(Arc::new(hash)))
+            }
+            TYPE_HASH_ZIPMAP => Ok(to_hash(decode_zipmap(&self.read_string()?)?)),
+            TYPE_LIST_ZIPLIST => Ok(RedisValue::List(Arc::new(
+                decode_ziplist(&self.read_string()?)?.into(),
+            ))),
+            TYPE_SET_INTSET => Ok(RedisValue::Set(Arc::new(
+                decode_intset(&self.read_string()?)?.into_iter().collect(),
+            ))),
+            TYPE_SET_LISTPACK => Ok(RedisValue::Set(Arc::new(
+                decode_listpack(&self.read_string()?)?.into_iter().collect(),
+            ))),
+            TYPE_ZSET_ZIPLIST => to_zset(decode_ziplist(&self.read_string()?)?),
+            TYPE_ZSET_LISTPACK => to_zset(decode_listpack(&self.read_string()?)?),
+            TYPE_HASH_ZIPLIST => Ok(to_hash(decode_ziplist(&self.read_string()?)?)),
+            TYPE_HASH_LISTPACK => Ok(to_hash(decode_listpack(&self.read_string()?)?)),
+            TYPE_LIST_QUICKLIST => {
+                let length = self.read_length()?;
+                let mut list = QuickList::new();
+                for _ in 0..length {
+                    list.extend(decode_ziplist(&self.read_string()?)?);
+                }
+                Ok(RedisValue::List(Arc::new(list)))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let length = self.read_length()?;
+                let mut list = QuickList::new();
+                for _ in 0..length {
+                    match self.read_length()? {
+                        QUICKLIST_NODE_PLAIN => list.push_back(self.read_string()?),
+                        QUICKLIST_NODE_PACKED => {
+                            list.extend(decode_listpack(&self.read_string()?)?)
+                        }
+                        _ => return Err(RDBError::InvalidCompactEncoding("quicklist")),
+                    }
+                }
+                Ok(RedisValue::List(Arc::new(list)))
+            }
+            _ => Err(RDBError::InvalidType),
+        }
+    }
+
+    fn read_strings(&mut self) -> Result<Vec<Bytes>, RDBError> {
         let length = self.read_length()?;
-        let mut buf = vec![0u8; length as usize];
-        self.read(&mut buf)?;
-        let string = String::from_utf8(buf).map_err(|_| RDBError::InvalidString)?;
+        (0..length).map(|_| self.read_string()).collect()
+    }
+
+    /// Reads a score of the original `TYPE_ZSET` encoding: a length byte
+    /// followed by the number in ASCII, with 253, 254 and 255 standing for
+    /// NaN, positive and negative infinity.
+    fn read_double_string(&mut self) -> Result<f64, RDBError> {
+        match self.read_byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            length => parse_double(&self.read_bytes(length as u64)?),
+        }
+    }
+}
+
+fn parse_double(bytes: &[u8]) -> Result<f64, RDBError> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|string| string.parse::<f64>().ok())
+        .ok_or(RDBError::InvalidDouble)
+}
+
+/// Builds a hash from fields and values stored alternately.
+fn to_hash(entries: Vec<Bytes>) -> RedisValue {
+    let mut hash = ScanMap::new();
+    let mut entries = entries.into_iter();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        hash.insert(field, value);
+    }
+    RedisValue::Hash(Arc::new(hash))
+}
+
+/// Builds a sorted set from members and scores stored alternately.
+fn to_zset(entries: Vec<Bytes>) -> Result<RedisValue, RDBError> {
+    let mut zset = ScanMap::new();
+    let mut entries = entries.into_iter();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        zset.insert(member, parse_double(&score)?);
+    }
+    Ok(RedisValue::ZSet(Arc::new(zset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "abc" as literals, then a back reference repeating it 3 times.
+    const LZF_ABC_4: &[u8] = &[2, b'a', b'b', b'c', 0xE0, 0, 2];
+
+    fn read_length(buf: &[u8]) -> Result<(u64, bool), RDBError> {
+        let mut parser = RDBParser::new(buf);
+        let length = parser.read_length_or_encoding()?;
+        assert_eq!(parser.position(), buf.len());
+        Ok(length)
+    }
+
+    fn read_string(buf: &[u8]) -> Result<Bytes, RDBError> {
+        let mut parser = RDBParser::new(buf);
+        let string = parser.read_string()?;
+        assert_eq!(parser.position(), buf.len());
         Ok(string)
     }
 
-    fn read_object(&mut self, object_type: u8) -> Result<Value, RDBError> {
-        match object_type {
-            0 => Ok(Value::String(self.read_string()?)),
-            _ => Err(RDBError::InvalidType),
+    /// A complete file of the given version around `body`, ending with the
+    /// EOF opcode and, since version 5, a valid checksum.
+    fn rdb_file(version: u32, body: &[u8]) -> Vec<u8> {
+        let mut file = format!("REDIS{:04}", version).into_bytes();
+        file.extend_from_slice(body);
+        file.push(OPCODE_EOF);
+        if version >= 5 {
+            let checksum = crc64(0, &file);
+            file.extend_from_slice(&checksum.to_le_bytes());
+        }
+        file
+    }
+
+    fn aux(key: &str, value: &[u8]) -> Vec<u8> {
+        let mut field = vec![OPCODE_AUX, key.len() as u8];
+        field.extend_from_slice(key.as_bytes());
+        field.extend_from_slice(value);
+        field
+    }
+
+    /// A string key set to a raw string value.
+    fn string_entry(key: &str, value: &str) -> Vec<u8> {
+        let mut entry = vec![TYPE_STRING, key.len() as u8];
+        entry.extend_from_slice(key.as_bytes());
+        entry.push(value.len() as u8);
+        entry.extend_from_slice(value.as_bytes());
+        entry
+    }
+
+    fn parse(file: &[u8]) -> Result<Rdb, RDBError> {
+        RDBParser::new(file).parse()
+    }
+
+    #[test]
+    fn length_forms() {
+        assert_eq!(read_length(&[0x0A]).unwrap(), (10, false));
+        assert_eq!(read_length(&[0x3F]).unwrap(), (63, false));
+        assert_eq!(read_length(&[0x41, 0x2C]).unwrap(), (300, false));
+        assert_eq!(read_length(&[0x7F, 0xFF]).unwrap(), (0x3FFF, false));
+        assert_eq!(
+            read_length(&[0x80, 0x00, 0x01, 0x00, 0x00]).unwrap(),
+            (1 << 16, false)
+        );
+        assert_eq!(
+            read_length(&[0x81, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap(),
+            (1 << 32, false)
+        );
+        assert_eq!(read_length(&[0xC3]).unwrap(), (3, true));
+        assert!(matches!(read_length(&[0x82]), Err(RDBError::InvalidLength)));
+        assert!(matches!(
+            read_length(&[0x80, 0x00]),
+            Err(RDBError::UnexpectedEOF)
+        ));
+        assert!(matches!(
+            RDBParser::new(&[0xC0]).read_length(),
+            Err(RDBError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn string_encodings() {
+        assert_eq!(read_string(&[0x03, b'f', b'o', b'o']).unwrap(), "foo");
+        assert_eq!(read_string(&[0x00]).unwrap(), "");
+        assert_eq!(read_string(&[0xC0, 0xF6]).unwrap(), "-10");
+        assert_eq!(read_string(&[0xC0, 0x7F]).unwrap(), "127");
+        assert_eq!(read_string(&[0xC1, 0x39, 0x30]).unwrap(), "12345");
+        assert_eq!(read_string(&[0xC1, 0x00, 0x80]).unwrap(), "-32768");
+        let mut int32 = vec![0xC2];
+        int32.extend_from_slice(&(-100_000i32).to_le_bytes());
+        assert_eq!(read_string(&int32).unwrap(), "-100000");
+
+        let mut lzf = vec![0xC3, LZF_ABC_4.len() as u8, 12];
+        lzf.extend_from_slice(LZF_ABC_4);
+        assert_eq!(read_string(&lzf).unwrap(), "abcabcabcabc");
+
+        assert!(matches!(
+            read_string(&[0xC4]),
+            Err(RDBError::InvalidEncoding(4))
+        ));
+        assert!(matches!(
+            read_string(&[0x05, b'a']),
+            Err(RDBError::UnexpectedEOF)
+        ));
+    }
+
+    #[test]
+    fn lzf_lengths_are_checked_before_allocating() {
+        // An uncompressed length of 1 TiB from 7 compressed bytes.
+        let mut lzf = vec![0xC3, LZF_ABC_4.len() as u8, 0x81];
+        lzf.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        lzf.extend_from_slice(LZF_ABC_4);
+        assert!(matches!(
+            read_string(&lzf),
+            Err(RDBError::InvalidCompressedString)
+        ));
+
+        // A compressed length past the end of the file.
+        let mut lzf = vec![0xC3, 0x81];
+        lzf.extend_from_slice(&u64::MAX.to_be_bytes());
+        lzf.push(12);
+        lzf.extend_from_slice(LZF_ABC_4);
+        assert!(matches!(read_string(&lzf), Err(RDBError::UnexpectedEOF)));
+
+        // A length that does not match the decompressed data.
+        let mut lzf = vec![0xC3, LZF_ABC_4.len() as u8, 11];
+        lzf.extend_from_slice(LZF_ABC_4);
+        assert!(matches!(
+            read_string(&lzf),
+            Err(RDBError::InvalidCompressedString)
+        ));
+    }
+
+    #[test]
+    fn aux_fields() {
+        let mut body = aux("redis-ver", b"\x057.2.4");
+        // Integers are often stored with the integer string encodings.
+        let mut ctime = vec![0xC2];
+        ctime.extend_from_slice(&1_700_000_000i32.to_le_bytes());
+        body.extend(aux("ctime", &ctime));
+        body.extend(aux("used-mem", &[0xC1, 0x00, 0x10]));
+        body.extend(aux("repl-id", b"\x03abc"));
+        body.extend(aux("aof-base", &[0xC0, 0x00]));
+        body.extend(string_entry("key", "value"));
+
+        let rdb = parse(&rdb_file(11, &body)).unwrap();
+        assert_eq!(rdb.version(), 11);
+        assert_eq!(rdb.redis_ver(), Some("7.2.4"));
+        assert_eq!(rdb.ctime(), Some(1_700_000_000));
+        assert_eq!(rdb.used_mem(), Some(4096));
+        assert_eq!(rdb.repl_id(), Some("abc"));
+        assert_eq!(rdb.aux.get(b"aof-base".as_slice()).unwrap(), "0");
+        assert_eq!(rdb.databases[&0].len(), 1);
+    }
+
+    #[test]
+    fn checksums() {
+        let file = rdb_file(9, &string_entry("key", "value"));
+        assert!(parse(&file).is_ok());
+
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+        assert!(matches!(
+            parse(&corrupt),
+            Err(RDBError::ChecksumMismatch { .. })
+        ));
+        // A bit flipped in the contents rather than in the checksum.
+        let mut corrupt = file.clone();
+        let value = corrupt.len() - 10;
+        corrupt[value] ^= 0x01;
+        assert!(matches!(
+            parse(&corrupt),
+            Err(RDBError::ChecksumMismatch { .. })
+        ));
+        let rdb = RDBParser::new(&corrupt)
+            .verify_checksum(false)
+            .parse()
+            .unwrap();
+        assert_eq!(rdb.databases[&0].len(), 1);
+
+        // Written with `rdbchecksum no`.
+        let mut unchecked = file.clone();
+        let footer = unchecked.len() - 8;
+        unchecked[footer..].fill(0);
+        assert!(parse(&unchecked).is_ok());
+
+        let mut truncated = file;
+        truncated.pop();
+        assert!(matches!(parse(&truncated), Err(RDBError::UnexpectedEOF)));
+    }
+
+    #[test]
+    fn files_before_version_5_have_no_checksum() {
+        let mut file = rdb_file(4, &string_entry("key", "value"));
+        let mut parser = RDBParser::new(&file);
+        parser.parse().unwrap();
+        assert_eq!(parser.position(), file.len());
+        // Whatever follows the EOF opcode, e.g. an AOF tail, is left alone.
+        file.extend_from_slice(b"*1\r\n");
+        let mut parser = RDBParser::new(&file);
+        parser.parse().unwrap();
+        assert_eq!(parser.position(), file.len() - 4);
+    }
+
+    #[test]
+    fn versions() {
+        for version in [RDB_MIN_VERSION, 6, RDB_MAX_VERSION] {
+            assert_eq!(parse(&rdb_file(version, &[])).unwrap().version(), version);
         }
+        assert!(matches!(
+            parse(&rdb_file(0, &[])),
+            Err(RDBError::UnsupportedVersion(0))
+        ));
+        assert!(matches!(
+            parse(&rdb_file(13, &[])),
+            Err(RDBError::UnsupportedVersion(13))
+        ));
+        assert!(matches!(
+            parse(b"REDIS00x1\xFF"),
+            Err(RDBError::InvalidVersion)
+        ));
+        assert!(matches!(
+            parse(b"REDIS+012\xFF"),
+            Err(RDBError::InvalidVersion)
+        ));
+        assert!(matches!(
+            parse(b"RESID0009\xFF"),
+            Err(RDBError::InvalidMagicNumber)
+        ));
+    }
+
+    #[test]
+    fn opcodes_are_gated_on_the_version() {
+        let gated = [
+            (OPCODE_EXPIRETIME_MS, 3, vec![0; 8]),
+            (OPCODE_AUX, 7, vec![0x01, b'a', 0x01, b'b']),
+            (OPCODE_RESIZEDB, 7, vec![0x01, 0x00]),
+            (OPCODE_IDLE, 9, vec![0x01]),
+            (OPCODE_FREQ, 9, vec![0x01]),
+            (OPCODE_FUNCTION2, 10, vec![0x01, b'f']),
+            (OPCODE_SLOT_INFO, 12, vec![0x00, 0x01, 0x00]),
+        ];
+        for (opcode, since, operands) in gated {
+            let mut body = vec![opcode];
+            body.extend_from_slice(&operands);
+            if opcode == OPCODE_EXPIRETIME_MS {
+                body.extend(string_entry("key", "value"));
+            }
+            assert!(
+                parse(&rdb_file(since, &body)).is_ok(),
+                "{:#04x} in version {}",
+                opcode,
+                since
+            );
+            match parse(&rdb_file(since - 1, &body)) {
+                Err(RDBError::UnexpectedOpcode {
+                    opcode: got,
+                    version,
+                }) => {
+                    assert_eq!((got, version), (opcode, since - 1));
+                }
+                other => panic!("{:#04x} in version {}: {:?}", opcode, since - 1, other),
+            }
+        }
+        assert!(matches!(
+            parse(&rdb_file(10, &[OPCODE_FUNCTION_PRE_GA])),
+            Err(RDBError::UnsupportedOpcode(OPCODE_FUNCTION_PRE_GA))
+        ));
+    }
+
+    #[test]
+    fn keys_are_loaded_into_their_database() {
+        let mut body = string_entry("before", "select");
+        body.extend([OPCODE_SELECTDB, 0x03, OPCODE_RESIZEDB, 0x02, 0x00]);
+        body.extend(string_entry("key", "three"));
+        body.extend(string_entry("other", "three"));
+        // A 14-bit database index, and a resize hint too large to honor.
+        body.extend([OPCODE_SELECTDB, 0x41, 0x2C, OPCODE_RESIZEDB, 0x80]);
+        body.extend(u32::MAX.to_be_bytes());
+        body.push(0x00);
+        body.extend(string_entry("key", "three hundred"));
+        body.extend([OPCODE_SELECTDB, 0x00]);
+        body.extend(string_entry("key", "zero"));
+        // Selected but empty.
+        body.extend([OPCODE_SELECTDB, 0x05]);
+
+        let databases = parse(&rdb_file(9, &body))
+            .unwrap()
+            .into_databases()
+            .map(|(index, database)| {
+                let mut entries = database
+                    .into_entries()
+                    .map(|(key, entry)| (key, entry.value.as_string().unwrap()))
+                    .collect::<Vec<_>>();
+                entries.sort();
+                (index, entries)
+            })
+            .collect::<Vec<_>>();
+        let entry = |key: &'static str, value: &'static str| (Bytes::from(key), Bytes::from(value));
+        assert_eq!(
+            databases,
+            [
+                (0, vec![entry("before", "select"), entry("key", "zero")]),
+                (3, vec![entry("key", "three"), entry("other", "three")]),
+                (300, vec![entry("key", "three hundred")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn expiries_are_read_as_unix_milliseconds() {
+        let expiry_of = |opcode: u8, time: &[u8]| {
+            let mut body = vec![opcode];
+            body.extend_from_slice(time);
+            body.extend(string_entry("key", "value"));
+            body.extend(string_entry("persistent", "value"));
+            let mut database = parse(&rdb_file(9, &body))
+                .unwrap()
+                .into_databases()
+                .next()
+                .unwrap()
+                .1
+                .into_entries()
+                .collect::<HashMap<_, _>>();
+            // The expiry only applies to the key right after it.
+            assert_eq!(database[b"persistent".as_slice()].expiry, None);
+            database.remove(b"key".as_slice()).unwrap().expiry
+        };
+
+        // Seconds are widened before being scaled, so no timestamp overflows.
+        assert_eq!(
+            expiry_of(OPCODE_EXPIRETIME, &1_700_000_000u32.to_le_bytes()),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(
+            expiry_of(OPCODE_EXPIRETIME, &u32::MAX.to_le_bytes()),
+            Some(u64::from(u32::MAX) * 1000)
+        );
+        assert_eq!(
+            expiry_of(OPCODE_EXPIRETIME_MS, &1_700_000_000_123u64.to_le_bytes()),
+            Some(1_700_000_000_123)
+        );
+        assert_eq!(
+            expiry_of(OPCODE_EXPIRETIME_MS, &u64::MAX.to_le_bytes()),
+            Some(u64::MAX)
+        );
     }
 }
@@ -0,0 +1,195 @@
+use anyhow::{bail, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::SaveRule,
+    db::{unix_time_millis, Database, Databases, DbValue, Snapshot},
+    parser::{RDBParser, Rdb, RdbValue},
+    writer::RDBWriter,
+};
+
+pub const REDIS_VERSION: &str = "7.2.0";
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds to wait before retrying a failed automatic background save.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Bookkeeping for RDB snapshots shared by every connection.
+pub struct Persistence {
+    last_save: u64,
+    last_bgsave_try: u64,
+    last_bgsave_ok: bool,
+    bgsave_in_progress: bool,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Self {
+            last_save: unix_time_secs(),
+            last_bgsave_try: 0,
+            last_bgsave_ok: true,
+            bgsave_in_progress: false,
+        }
+    }
+
+    /// Unix time in seconds of the last successful snapshot.
+    pub fn last_save(&self) -> u64 {
+        self.last_save
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress
+    }
+
+    pub fn start_bgsave(&mut self) {
+        self.bgsave_in_progress = true;
+        self.last_bgsave_try = unix_time_secs();
+    }
+
+    pub fn finish_save(&mut self, succeeded: bool) {
+        if succeeded {
+            self.last_save = unix_time_secs();
+        }
+    }
+
+    pub fn finish_bgsave(&mut self, succeeded: bool) {
+        self.bgsave_in_progress = false;
+        self.last_bgsave_ok = succeeded;
+        self.finish_save(succeeded);
+    }
+
+    /// Checks the `save` rules against the number of unsaved changes, the way
+    /// Redis' cron does. A failed background save is only retried after a
+    /// short delay so a full disk does not turn into a busy loop.
+    pub fn should_save(&self, rules: &[SaveRule], dirty: u64) -> bool {
+        if self.bgsave_in_progress {
+            return false;
+        }
+        let now = unix_time_secs();
+        if !self.last_bgsave_ok && now.saturating_sub(self.last_bgsave_try) <= BGSAVE_RETRY_DELAY {
+            return false;
+        }
+        rules
+            .iter()
+            .any(|rule| dirty >= rule.changes && now.saturating_sub(self.last_save) >= rule.seconds)
+    }
+}
+
+pub fn read_rdb_file(path: &Path, verify_checksum: bool) -> Result<Rdb> {
+    let file = File::open(path)?;
+    let mut reader = io::BufReader::new(file);
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
+    let mut parser = RDBParser::new(&buffer).verify_checksum(verify_checksum);
+    let rdb = parser.parse()?;
+    Ok(rdb)
+}
+
+pub fn load_rdb<T: Database>(
+    dbs: &mut Databases<T>,
+    path: &Path,
+    verify_checksum: bool,
+) -> Result<()> {
+    let rdb = read_rdb_file(path, verify_checksum)?;
+    println!(
+        "Loading RDB file {} (RDB version {})",
+        path.display(),
+        rdb.version()
+    );
+    log_rdb_info(&rdb);
+    insert_rdb(dbs, rdb)
+}
+
+/// Reports the metadata the writer stored in the file, like Redis does.
+pub fn log_rdb_info(rdb: &Rdb) {
+    if let Some(redis_ver) = rdb.redis_ver() {
+        println!("Loading RDB produced by version {}", redis_ver);
+    }
+    if let Some(ctime) = rdb.ctime() {
+        println!("RDB age {} seconds", unix_time_secs().saturating_sub(ctime));
+    }
+    if let Some(used_mem) = rdb.used_mem() {
+        println!(
+            "RDB memory usage when created {:.2} Mb",
+            used_mem as f64 / (1024.0 * 1024.0)
+        );
+    }
+    if let Some(repl_id) = rdb.repl_id() {
+        println!("RDB replication ID {}", repl_id);
+    }
+}
+
+/// Copies every key of a parsed RDB file into the database with the same
+/// index, skipping keys that already expired like a Redis master does.
+/// Fails if the file uses more databases than are configured.
+pub fn insert_rdb<T: Database>(dbs: &mut Databases<T>, rdb: Rdb) -> Result<()> {
+    let count = dbs.len();
+    for (index, database) in rdb.into_databases() {
+        let Some(db) = dbs.get_mut(index as usize) else {
+            bail!(
+                "the file contains database {} but the server is configured for {} databases",
+                index,
+                count
+            );
+        };
+        db.reserve(database.len());
+        let now = unix_time_millis();
+        for (key, RdbValue { value, expiry }) in database.into_entries() {
+            if expiry.is_some_and(|expiry| expiry <= now) {
+                continue;
+            }
+            db.insert(key, DbValue::new(value, expiry));
+        }
+    }
+    Ok(())
+}
+
+/// Serializes `snapshot` and atomically replaces the RDB file at `path`.
+pub fn save_rdb(snapshot: &Snapshot, path: &Path) -> Result<()> {
+    let temp_path = path.with_file_name(format!(
+        "temp-{}-{}.rdb",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&dump_rdb(snapshot))?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Serializes `snapshot` in the RDB format.
+pub fn dump_rdb(snapshot: &Snapshot) -> Vec<u8> {
+    let mut writer = RDBWriter::new();
+    writer.write_header();
+    writer.write_aux(b"redis-ver", REDIS_VERSION.as_bytes());
+    writer.write_aux(b"redis-bits", b"64");
+    writer.write_aux(b"ctime", unix_time_secs().to_string().as_bytes());
+
+    for (index, entries) in snapshot {
+        let expires = entries
+            .iter()
+            .filter(|(_, value)| value.expires_at().is_some())
+            .count();
+        writer.write_select_db(*index as u32);
+        writer.write_resize_db(entries.len(), expires);
+        for (key, value) in entries {
+            writer.write_entry(key, value.value(), value.expires_at());
+        }
+    }
+    writer.finish()
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,535 @@
+//! The list value, a deque of small chunks like Redis' quicklist: pushes and
+//! pops at either end are O(1), and reaching an index walks chunks rather
+//! than elements.
+
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+/// Most elements kept in one chunk. A full chunk is split in two when an
+/// element is inserted in its middle, and neighbouring chunks that fit in
+/// one after removals are merged.
+const NODE_CAPACITY: usize = 128;
+
+/// An end of a list, the `LEFT|RIGHT` argument of `LMOVE` and friends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl ListEnd {
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        if name.eq_ignore_ascii_case(b"LEFT") {
+            Some(ListEnd::Left)
+        } else if name.eq_ignore_ascii_case(b"RIGHT") {
+            Some(ListEnd::Right)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ListEnd::Left => "LEFT",
+            ListEnd::Right => "RIGHT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuickList {
+    /// Chunks of elements, none of them empty.
+    nodes: VecDeque<VecDeque<Bytes>>,
+    len: usize,
+}
+
+impl QuickList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of chunks, which is what freeing the list costs.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn push_front(&mut self, element: Bytes) {
+        match self.nodes.front_mut() {
+            Some(node) if node.len() < NODE_CAPACITY => node.push_front(element),
+            _ => self.nodes.push_front(VecDeque::from([element])),
+        }
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, element: Bytes) {
+        match self.nodes.back_mut() {
+            Some(node) if node.len() < NODE_CAPACITY => node.push_back(element),
+            _ => self.nodes.push_back(VecDeque::from([element])),
+        }
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        let node = self.nodes.front_mut()?;
+        let element = node.pop_front();
+        if node.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.len -= 1;
+        element
+    }
+
+    pub fn pop_back(&mut self) -> Option<Bytes> {
+        let node = self.nodes.back_mut()?;
+        let element = node.pop_back();
+        if node.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.len -= 1;
+        element
+    }
+
+    pub fn push(&mut self, end: ListEnd, element: Bytes) {
+        match end {
+            ListEnd::Left => self.push_front(element),
+            ListEnd::Right => self.push_back(element),
+        }
+    }
+
+    pub fn pop(&mut self, end: ListEnd) -> Option<Bytes> {
+        match end {
+            ListEnd::Left => self.pop_front(),
+            ListEnd::Right => self.pop_back(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Bytes> {
+        let (node, offset) = self.locate(index)?;
+        self.nodes[node].get(offset)
+    }
+
+    /// Replaces the element at `index`. Returns `false` if it is out of
+    /// range.
+    pub fn set(&mut self, index: usize, element: Bytes) -> bool {
+        match self.locate(index) {
+            Some((node, offset)) => {
+                self.nodes[node][offset] = element;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts `element` so it ends up at `index`, which may be `len()`.
+    pub fn insert(&mut self, index: usize, element: Bytes) {
+        if index == 0 {
+            return self.push_front(element);
+        }
+        if index >= self.len {
+            return self.push_back(element);
+        }
+        let (mut node, mut offset) = self.locate(index).expect("index is in range");
+        if self.nodes[node].len() >= NODE_CAPACITY {
+            let half = self.nodes[node].len() / 2;
+            let tail = self.nodes[node].split_off(half);
+            self.nodes.insert(node + 1, tail);
+            if offset >= half {
+                node += 1;
+                offset -= half;
+            }
+        }
+        self.nodes[node].insert(offset, element);
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.len = 0;
+    }
+
+    /// Iterates from head to tail; `.rev()` goes from tail to head.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.nodes.iter().flatten()
+    }
+
+    /// Iterates over the elements from `start` on, skipping whole chunks to
+    /// get there.
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = &Bytes> {
+        let (node, offset) = self.locate(start).unwrap_or((self.nodes.len(), 0));
+        self.nodes
+            .range(node..)
+            .enumerate()
+            .flat_map(move |(i, elements)| elements.range(if i == 0 { offset } else { 0 }..))
+    }
+
+    /// Keeps only the elements from `start` to `end`, both inclusive and in
+    /// range.
+    pub fn trim(&mut self, start: usize, end: usize) {
+        let mut front = start;
+        while let Some(node) = self.nodes.front_mut() {
+            if front < node.len() {
+                node.drain(..front);
+                break;
+            }
+            front -= node.len();
+            self.nodes.pop_front();
+        }
+        let mut back = self.len - 1 - end;
+        while let Some(node) = self.nodes.back_mut() {
+            if back < node.len() {
+                node.truncate(node.len() - back);
+                break;
+            }
+            back -= node.len();
+            self.nodes.pop_back();
+        }
+        self.nodes.retain(|node| !node.is_empty());
+        self.len = end - start + 1;
+        // Only the chunks at either end lost elements.
+        if self.nodes.len() >= 2 {
+            self.merge_with_next(self.nodes.len() - 2);
+        }
+        self.merge_with_next(0);
+    }
+
+    /// Removes elements equal to `element`, like `LREM`: the first `count`
+    /// from the head when positive, the last `-count` from the tail when
+    /// negative and all of them when 0. Returns how many were removed.
+    pub fn remove_matching(&mut self, element: &[u8], count: i64) -> usize {
+        let limit = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+        let mut removed = 0;
+        let nodes: Box<dyn Iterator<Item = &mut VecDeque<Bytes>>> = if count < 0 {
+            Box::new(self.nodes.iter_mut().rev())
+        } else {
+            Box::new(self.nodes.iter_mut())
+        };
+        for node in nodes {
+            if removed == limit {
+                break;
+            }
+            let mut positions = node
+                .iter()
+                .enumerate()
+                .filter(|(_, candidate)| candidate.as_ref() == element)
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>();
+            if count < 0 {
+                positions.reverse();
+            }
+            positions.truncate(limit - removed);
+            removed += positions.len();
+            // Remove from the back of the chunk so positions stay valid.
+            positions.sort_unstable();
+            for position in positions.into_iter().rev() {
+                node.remove(position);
+            }
+        }
+        self.len -= removed;
+        if removed > 0 {
+            self.merge_small_nodes();
+        }
+        removed
+    }
+
+    /// Drops empty chunks and merges neighbouring ones that fit in one, so
+    /// removals scattered over the list do not leave it in slivers.
+    fn merge_small_nodes(&mut self) {
+        for node in std::mem::take(&mut self.nodes) {
+            match self.nodes.back_mut() {
+                _ if node.is_empty() => {}
+                Some(last) if last.len() + node.len() <= NODE_CAPACITY => last.extend(node),
+                _ => self.nodes.push_back(node),
+            }
+        }
+    }
+
+    /// Merges chunk `node` with the one after it if they fit in one.
+    fn merge_with_next(&mut self, node: usize) {
+        if node + 1 < self.nodes.len()
+            && self.nodes[node].len() + self.nodes[node + 1].len() <= NODE_CAPACITY
+        {
+            let next = self.nodes.remove(node + 1).expect("checked above");
+            self.nodes[node].extend(next);
+        }
+    }
+
+    /// Chunk and offset of the element at `index`, walking from whichever
+    /// end is closer.
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.len / 2 {
+            let mut offset = index;
+            for (node, elements) in self.nodes.iter().enumerate() {
+                if offset < elements.len() {
+                    return Some((node, offset));
+                }
+                offset -= elements.len();
+            }
+        } else {
+            let mut from_back = self.len - 1 - index;
+            for (node, elements) in self.nodes.iter().enumerate().rev() {
+                if from_back < elements.len() {
+                    return Some((node, elements.len() - 1 - from_back));
+                }
+                from_back -= elements.len();
+            }
+        }
+        None
+    }
+}
+
+/// Resolves an index that counts from the tail when negative, like the one
+/// of `LINDEX`, to a position in a list of `len` elements.
+pub fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        index.checked_add(len as i64)?
+    } else {
+        index
+    };
+    usize::try_from(index).ok().filter(|&index| index < len)
+}
+
+/// Resolves the inclusive `start` and `end` of `LRANGE` and `LTRIM`, which
+/// count from the tail when negative, to positions in a list of `len`
+/// elements. Out of range ends are clamped; `None` if nothing is left.
+pub fn resolve_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { end + len } else { end.min(len - 1) };
+    (start <= end && start < len).then_some((start as usize, end as usize))
+}
+
+impl Extend<Bytes> for QuickList {
+    fn extend<I: IntoIterator<Item = Bytes>>(&mut self, elements: I) {
+        for element in elements {
+            self.push_back(element);
+        }
+    }
+}
+
+impl FromIterator<Bytes> for QuickList {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(elements: I) -> Self {
+        let mut list = QuickList::new();
+        list.extend(elements);
+        list
+    }
+}
+
+impl From<Vec<Bytes>> for QuickList {
+    fn from(elements: Vec<Bytes>) -> Self {
+        elements.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(range: std::ops::Range<usize>) -> QuickList {
+        range.map(|i| Bytes::from(i.to_string())).collect()
+    }
+
+    fn contents(list: &QuickList) -> Vec<usize> {
+        let contents = list
+            .iter()
+            .map(|element| std::str::from_utf8(element).unwrap().parse().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents.len(), list.len());
+        assert!(list
+            .nodes
+            .iter()
+            .all(|node| (1..=NODE_CAPACITY).contains(&node.len())));
+        contents
+    }
+
+    /// Whether no two neighbouring chunks would fit in one.
+    fn is_compact(list: &QuickList) -> bool {
+        list.nodes
+            .iter()
+            .zip(list.nodes.iter().skip(1))
+            .all(|(node, next)| node.len() + next.len() > NODE_CAPACITY)
+    }
+
+    #[test]
+    fn pushes_and_pops_at_both_ends() {
+        let mut list = QuickList::new();
+        for i in 0..300 {
+            list.push(ListEnd::Right, Bytes::from((i + 300).to_string()));
+            list.push(ListEnd::Left, Bytes::from((299 - i).to_string()));
+        }
+        assert_eq!(contents(&list), (0..600).collect::<Vec<_>>());
+        assert_eq!(list.pop(ListEnd::Left).unwrap(), "0");
+        assert_eq!(list.pop(ListEnd::Right).unwrap(), "599");
+        assert_eq!(list.get(0).unwrap(), "1");
+        assert_eq!(list.get(597).unwrap(), "598");
+        assert_eq!(list.get(598), None);
+        assert_eq!(list.iter_from(590).count(), 8);
+        assert_eq!(list.iter_from(598).count(), 0);
+        while list.pop_front().is_some() {}
+        assert!(list.is_empty());
+        assert_eq!(list.node_count(), 0);
+    }
+
+    #[test]
+    fn inserting_into_a_full_chunk_splits_it() {
+        let mut list = numbers(0..NODE_CAPACITY);
+        assert_eq!(list.node_count(), 1);
+        list.insert(100, Bytes::from("1000"));
+        assert_eq!(list.node_count(), 2);
+        list.insert(10, Bytes::from("1001"));
+        list.insert(list.len(), Bytes::from("1002"));
+        list.insert(0, Bytes::from("1003"));
+
+        let mut expected = (0..NODE_CAPACITY).collect::<Vec<_>>();
+        expected.insert(100, 1000);
+        expected.insert(10, 1001);
+        expected.push(1002);
+        expected.insert(0, 1003);
+        assert_eq!(contents(&list), expected);
+        for (index, element) in expected.iter().enumerate() {
+            assert_eq!(list.get(index).unwrap(), element.to_string().as_str());
+        }
+        assert!(list.set(101, Bytes::from("2000")));
+        assert_eq!(list.get(101).unwrap(), "2000");
+        assert!(!list.set(list.len(), Bytes::from("2001")));
+    }
+
+    #[test]
+    fn trimming_keeps_the_range_and_merges_the_ends() {
+        for (start, end) in [(0, 999), (5, 5), (0, 0), (999, 999), (127, 128), (100, 400)] {
+            let mut list = numbers(0..1000);
+            list.trim(start, end);
+            assert_eq!(contents(&list), (start..=end).collect::<Vec<_>>());
+        }
+
+        // Two half-full chunks, split by an insert.
+        let half_full = || {
+            let mut list = numbers(0..NODE_CAPACITY);
+            list.insert(NODE_CAPACITY / 2, Bytes::from("1000"));
+            assert_eq!(list.node_count(), 2);
+            list
+        };
+        let mut expected = (0..NODE_CAPACITY).collect::<Vec<_>>();
+        expected.insert(NODE_CAPACITY / 2, 1000);
+        let mut list = half_full();
+        list.trim(10, NODE_CAPACITY);
+        assert_eq!(list.node_count(), 1);
+        assert_eq!(contents(&list), expected[10..]);
+        let mut list = half_full();
+        list.trim(0, NODE_CAPACITY - 10);
+        assert_eq!(list.node_count(), 1);
+        assert_eq!(contents(&list), expected[..=NODE_CAPACITY - 10]);
+    }
+
+    #[test]
+    fn removing_matches_from_either_end() {
+        let positions_of_x = |list: &QuickList| {
+            list.iter()
+                .enumerate()
+                .filter(|(_, element)| element.as_ref() == b"x")
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>()
+        };
+        let with_matches = || {
+            (0..30)
+                .map(|i| {
+                    Bytes::from(if i % 3 == 0 {
+                        "x".to_string()
+                    } else {
+                        i.to_string()
+                    })
+                })
+                .collect::<QuickList>()
+        };
+
+        let mut list = with_matches();
+        assert_eq!(list.remove_matching(b"x", 2), 2);
+        assert_eq!(positions_of_x(&list), [4, 7, 10, 13, 16, 19, 22, 25]);
+        let mut list = with_matches();
+        assert_eq!(list.remove_matching(b"x", -2), 2);
+        assert_eq!(positions_of_x(&list), [0, 3, 6, 9, 12, 15, 18, 21]);
+        let mut list = with_matches();
+        assert_eq!(list.remove_matching(b"x", 0), 10);
+        assert_eq!(list.len(), 20);
+        assert!(positions_of_x(&list).is_empty());
+        assert_eq!(list.remove_matching(b"x", 0), 0);
+        assert_eq!(list.remove_matching(b"1", 100), 1);
+        assert_eq!(list.len(), 19);
+    }
+
+    #[test]
+    fn removals_merge_undersized_chunks() {
+        // Every chunk ends up with a single element left.
+        let mut list = (0..NODE_CAPACITY * 8)
+            .map(|i| {
+                Bytes::from(if i % NODE_CAPACITY == 0 {
+                    "keep"
+                } else {
+                    "drop"
+                })
+            })
+            .collect::<QuickList>();
+        assert_eq!(list.node_count(), 8);
+        assert_eq!(list.remove_matching(b"drop", 0), (NODE_CAPACITY - 1) * 8);
+        assert_eq!(list.len(), 8);
+        assert_eq!(list.node_count(), 1);
+        assert!(list.iter().all(|element| element == "keep"));
+
+        let mut list = numbers(0..NODE_CAPACITY * 8);
+        for i in (0..NODE_CAPACITY * 8).filter(|i| i % 4 != 0) {
+            list.remove_matching(i.to_string().as_bytes(), 1);
+        }
+        assert!(is_compact(&list));
+        assert_eq!(
+            contents(&list),
+            (0..NODE_CAPACITY * 8).step_by(4).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn indices_count_from_the_tail_when_negative() {
+        assert_eq!(resolve_index(0, 3), Some(0));
+        assert_eq!(resolve_index(2, 3), Some(2));
+        assert_eq!(resolve_index(3, 3), None);
+        assert_eq!(resolve_index(-1, 3), Some(2));
+        assert_eq!(resolve_index(-3, 3), Some(0));
+        assert_eq!(resolve_index(-4, 3), None);
+        assert_eq!(resolve_index(0, 0), None);
+        assert_eq!(resolve_index(i64::MIN, 3), None);
+        assert_eq!(resolve_index(i64::MAX, 3), None);
+    }
+
+    #[test]
+    fn ranges_are_clamped() {
+        assert_eq!(resolve_range(0, -1, 5), Some((0, 4)));
+        assert_eq!(resolve_range(1, 2, 5), Some((1, 2)));
+        assert_eq!(resolve_range(-2, -1, 5), Some((3, 4)));
+        assert_eq!(resolve_range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(resolve_range(3, 3, 5), Some((3, 3)));
+        assert_eq!(resolve_range(3, 2, 5), None);
+        assert_eq!(resolve_range(5, 10, 5), None);
+        assert_eq!(resolve_range(0, -6, 5), None);
+        assert_eq!(resolve_range(-1, -2, 5), None);
+        assert_eq!(resolve_range(0, -1, 0), None);
+        assert_eq!(resolve_range(i64::MIN, i64::MAX, 5), Some((0, 4)));
+    }
+}
@@ -1,15 +1,17 @@
 use std::fmt::Display;
 
+use bytes::{Buf, Bytes};
+
 #[derive(Debug)]
 pub enum Value {
-    String(String),
+    String(Bytes),
     Array(Vec<Value>),
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Value::String(string) => write!(f, "{}", string),
+            Value::String(string) => write!(f, "{}", String::from_utf8_lossy(string)),
             Value::Array(array) => {
                 let mut result = String::new();
                 result.push('[');
@@ -26,93 +28,300 @@ impl Display for Value {
     }
 }
 
-pub struct RespParser<'a> {
-    buf: &'a [u8],
-    pos: usize,
+/// Most elements a request array may have, like Redis' limit on
+/// multibulk lengths.
+pub const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+/// Largest bulk string a request may carry, like Redis'
+/// `proto-max-bulk-len`.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Longest a simple string request may get, like Redis' limit on inline
+/// requests.
+pub const MAX_INLINE_LEN: usize = 64 * 1024;
+/// Longest a length line may get without its CRLF before the frame is
+/// considered malformed rather than incomplete.
+const MAX_LENGTH_LINE: usize = 32;
+
+/// Outcome of parsing one frame.
+#[derive(Debug)]
+pub enum Frame {
+    Complete(Value),
+    /// The buffer does not hold a whole frame yet.
+    Incomplete,
+    /// The bytes can never form a valid frame, for the given reason.
+    Invalid(String),
+}
+
+/// Why `RespParser` could not return a value.
+enum ParseError {
+    Incomplete,
+    Invalid(String),
 }
 
-impl<'a> RespParser<'a> {
-    pub fn new(buf: &'a [u8]) -> Self {
-        RespParser { buf, pos: 0 }
+impl ParseError {
+    fn invalid(reason: &str) -> Self {
+        ParseError::Invalid(reason.to_string())
     }
+}
 
-    pub fn parse(&mut self) -> (Option<Value>, usize) {
-        let value = self.parse_value();
+type ParseResult<T> = Result<T, ParseError>;
 
-        (value, self.pos)
+/// Parses requests, which like in Redis are arrays of bulk strings, or a
+/// bare simple or bulk string.
+///
+/// Bytes are consumed from the buffer as soon as they form a whole element,
+/// and the elements of an array that has not fully arrived yet are kept
+/// until the next call, so a large request arriving in many reads is only
+/// parsed once.
+#[derive(Debug, Default)]
+pub struct RespParser {
+    /// The array being received: its length and the elements so far.
+    pending: Option<(usize, Vec<Value>)>,
+}
+
+impl RespParser {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn parse_value(&mut self) -> Option<Value> {
-        if self.pos < self.buf.len() {
-            let byte = self.buf[self.pos];
-            self.pos += 1;
+    /// Parses the next request from `buf`, advancing it past whatever was
+    /// used, including the elements of an incomplete array.
+    pub fn parse(&mut self, buf: &mut impl Buf) -> Frame {
+        match self.parse_request(buf) {
+            Ok(value) => Frame::Complete(value),
+            Err(ParseError::Incomplete) => Frame::Incomplete,
+            Err(ParseError::Invalid(reason)) => {
+                self.pending = None;
+                Frame::Invalid(reason)
+            }
+        }
+    }
+
+    fn parse_request(&mut self, buf: &mut impl Buf) -> ParseResult<Value> {
+        if self.pending.is_none() {
+            let Some(&byte) = buf.chunk().first() else {
+                return Err(ParseError::Incomplete);
+            };
             match byte {
-                b'+' => self.parse_simple_string(),
-                b'*' => self.parse_array(),
-                b'$' => self.parse_bulk_string(),
-                _ => None,
+                b'+' => return Self::parse_simple_string(buf),
+                b'$' => return Self::parse_bulk_string(buf),
+                b'*' => {
+                    let (length, used) = Self::parse_length(
+                        buf.chunk(),
+                        MAX_MULTIBULK_LEN,
+                        "invalid multibulk length",
+                    )?;
+                    buf.advance(used);
+                    // The length is only trusted as far as the elements
+                    // actually arrive.
+                    self.pending = Some((length, Vec::with_capacity(length.min(1024))));
+                }
+                _ => return Err(ParseError::invalid("invalid request")),
+            }
+        }
+
+        let (length, elements) = self.pending.as_mut().expect("an array is pending");
+        while elements.len() < *length {
+            match buf.chunk().first() {
+                None => return Err(ParseError::Incomplete),
+                Some(b'$') => elements.push(Self::parse_bulk_string(buf)?),
+                Some(&byte) => {
+                    return Err(ParseError::Invalid(format!(
+                        "expected '$', got '{}'",
+                        byte as char
+                    )))
+                }
             }
-        } else {
-            None
         }
+        let (_, elements) = self.pending.take().expect("an array is pending");
+        Ok(Value::Array(elements))
     }
 
-    fn parse_simple_string(&mut self) -> Option<Value> {
-        let string_raw = self.until_crlf();
-        let string = Self::as_string(string_raw)?;
-        Some(Value::String(string))
+    fn parse_simple_string(buf: &mut impl Buf) -> ParseResult<Value> {
+        let chunk = buf.chunk();
+        // The prefix byte, the string and CRLF.
+        let limit = MAX_INLINE_LEN + 3;
+        let Some(end) = find_crlf(&chunk[..chunk.len().min(limit)]) else {
+            return if chunk.len() >= limit {
+                Err(ParseError::invalid("too big inline request"))
+            } else {
+                Err(ParseError::Incomplete)
+            };
+        };
+        let string = Bytes::copy_from_slice(&chunk[1..end]);
+        buf.advance(end + 2);
+        Ok(Value::String(string))
     }
 
-    fn parse_bulk_string(&mut self) -> Option<Value> {
-        let length_raw = self.until_crlf();
-        let length = Self::as_usize(length_raw)?;
-        let string_raw = &self.buf[self.pos..self.pos + length];
-        self.pos += length + 2;
-        let string = Self::as_string(string_raw)?;
-        Some(Value::String(string))
+    /// Parses a whole bulk string, consuming nothing unless it is complete.
+    fn parse_bulk_string(buf: &mut impl Buf) -> ParseResult<Value> {
+        let chunk = buf.chunk();
+        let (length, start) = Self::parse_length(chunk, MAX_BULK_LEN, "invalid bulk length")?;
+        if chunk.len() < start + length + 2 {
+            return Err(ParseError::Incomplete);
+        }
+        if &chunk[start + length..start + length + 2] != b"\r\n" {
+            return Err(ParseError::invalid("bulk string not terminated by CRLF"));
+        }
+        let string = Bytes::copy_from_slice(&chunk[start..start + length]);
+        buf.advance(start + length + 2);
+        Ok(Value::String(string))
     }
 
-    fn parse_array(&mut self) -> Option<Value> {
-        let length_raw = self.until_crlf();
-        let length = Self::as_usize(length_raw)?;
-        let mut array: Vec<Value> = Vec::with_capacity(length);
-        for _ in 0..length {
-            let value = self.parse_value();
+    /// Parses the length line of an array or bulk string after its prefix
+    /// byte, which must be a non-negative integer no larger than `max`.
+    /// Returns the length and the bytes the line takes.
+    fn parse_length(chunk: &[u8], max: usize, invalid: &str) -> ParseResult<(usize, usize)> {
+        let limit = MAX_LENGTH_LINE + 3;
+        let Some(end) = find_crlf(&chunk[..chunk.len().min(limit)]) else {
+            return if chunk.len() >= limit {
+                Err(ParseError::invalid(invalid))
+            } else {
+                Err(ParseError::Incomplete)
+            };
+        };
+        std::str::from_utf8(&chunk[1..end])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|&length| length <= max)
+            .map(|length| (length, end + 2))
+            .ok_or_else(|| ParseError::invalid(invalid))
+    }
+}
 
-            if let Some(value) = value {
-                array.push(value);
-            }
-        }
-        Some(Value::Array(array))
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|window| window == b"\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+
+    fn parse(mut buf: &[u8]) -> Frame {
+        RespParser::new().parse(&mut buf)
     }
 
-    fn as_usize(buf: &[u8]) -> Option<usize> {
-        if let Ok(str) = std::str::from_utf8(buf) {
-            str.parse::<usize>().ok()
-        } else {
-            None
+    fn strings(value: Value) -> Vec<Bytes> {
+        match value {
+            Value::Array(array) => array
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(string) => string,
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected {:?}", other),
         }
     }
 
-    fn as_string(buf: &[u8]) -> Option<String> {
-        if let Ok(str) = std::str::from_utf8(buf) {
-            Some(str.to_string())
-        } else {
-            None
+    fn is_invalid(frame: Frame, expected: &str) -> bool {
+        matches!(frame, Frame::Invalid(reason) if reason == expected)
+    }
+
+    #[test]
+    fn parses_a_complete_request() {
+        let mut request = &b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n"[..];
+        match RespParser::new().parse(&mut request) {
+            Frame::Complete(value) => assert_eq!(strings(value), ["ECHO", "hi"]),
+            other => panic!("unexpected {:?}", other),
         }
+        assert!(request.is_empty());
     }
 
-    fn until_crlf(&mut self) -> &[u8] {
-        let begin = self.pos;
+    #[test]
+    fn every_prefix_of_a_request_is_incomplete() {
+        let request = b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n";
+        for end in 0..request.len() {
+            assert!(
+                matches!(parse(&request[..end]), Frame::Incomplete),
+                "prefix of {} bytes",
+                end
+            );
+        }
+    }
 
-        while self.pos < self.buf.len() {
-            let byte = self.buf[self.pos];
-            self.pos += 1;
-            if byte == b'\r' && self.pos < self.buf.len() && self.buf[self.pos] == b'\n' {
-                self.pos += 1;
-                break;
+    #[test]
+    fn a_request_arriving_byte_by_byte_is_parsed_once() {
+        let request = b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n";
+        let mut parser = RespParser::new();
+        let mut buffer = BytesMut::new();
+        for (i, &byte) in request.iter().enumerate() {
+            buffer.extend_from_slice(&[byte]);
+            match parser.parse(&mut buffer) {
+                Frame::Incomplete => assert!(i < request.len() - 1),
+                Frame::Complete(value) => {
+                    assert_eq!(i, request.len() - 1);
+                    assert_eq!(strings(value), ["ECHO", "hi"]);
+                }
+                Frame::Invalid(reason) => panic!("invalid: {}", reason),
             }
+            // Whole elements are consumed as soon as they arrive.
+            assert!(buffer.len() <= 9, "{} bytes buffered", buffer.len());
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn pipelined_requests_are_parsed_one_at_a_time() {
+        let mut requests = &b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPING\r\n"[..];
+        let mut parser = RespParser::new();
+        assert!(matches!(parser.parse(&mut requests), Frame::Complete(_)));
+        assert_eq!(requests, b"*1\r\n$4\r\nPING\r\n");
+        assert!(matches!(parser.parse(&mut requests), Frame::Complete(_)));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn nested_arrays_are_rejected() {
+        assert!(is_invalid(
+            parse(b"*1\r\n*1\r\n$4\r\nPING\r\n"),
+            "expected '$', got '*'"
+        ));
+        let nested = b"*1\r\n".repeat(200_000);
+        assert!(is_invalid(parse(&nested), "expected '$', got '*'"));
+    }
+
+    #[test]
+    fn malformed_lengths_are_invalid() {
+        for request in [
+            &b"*-1\r\n*1\r\n$4\r\nPING\r\n"[..],
+            b"$abc\r\n",
+            b"*1\r\n$-5\r\n",
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$2\r\nhiXX",
+            b"*11111111111111111111111111111111111111",
+        ] {
+            assert!(
+                matches!(parse(request), Frame::Invalid(_)),
+                "{:?}",
+                String::from_utf8_lossy(request)
+            );
         }
-        &self.buf[begin..self.pos - 2]
+    }
+
+    #[test]
+    fn oversized_lengths_are_invalid_without_allocating() {
+        assert!(is_invalid(
+            parse(b"*999999999999\r\n"),
+            "invalid multibulk length"
+        ));
+        assert!(matches!(
+            parse(format!("*{}\r\n", MAX_MULTIBULK_LEN + 1).as_bytes()),
+            Frame::Invalid(_)
+        ));
+        assert!(is_invalid(
+            parse(format!("*1\r\n${}\r\n", MAX_BULK_LEN + 1).as_bytes()),
+            "invalid bulk length"
+        ));
+        assert!(is_invalid(
+            parse(&vec![b'+'; MAX_INLINE_LEN + 3]),
+            "too big inline request"
+        ));
+        // At the limit the frame is merely waiting for its elements.
+        assert!(matches!(
+            parse(format!("*{}\r\n", MAX_MULTIBULK_LEN).as_bytes()),
+            Frame::Incomplete
+        ));
     }
 }
@@ -0,0 +1,207 @@
+//! Cursors for `SCAN` and its per-collection variants.
+//!
+//! Elements are visited in the order of a fixed hash of their name and the
+//! cursor is the hash to resume from. Unlike a position in a hash table, it
+//! stays meaningful however the collection grows or shrinks in between
+//! calls, so every element present for the whole iteration is returned,
+//! and exactly once. The keyspace and every `ScanMap` keep their names
+//! ordered by that hash, so a call only visits what it returns.
+//!
+//! Like Redis with its compact encodings, collections of at most
+//! `SMALL_COLLECTION_LEN` elements are returned whole in a single call.
+
+use std::{
+    collections::{hash_map, BTreeSet, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use bytes::Bytes;
+
+/// Largest collection `HSCAN`, `SSCAN` and `ZSCAN` return in one call,
+/// Redis' default `*-max-listpack-entries`.
+pub const SMALL_COLLECTION_LEN: usize = 128;
+
+/// Hash deciding the iteration order. `DefaultHasher::new` always uses the
+/// same keys, so cursors stay valid for the lifetime of the process.
+pub fn scan_hash(name: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The value of a set, hash or sorted set: a map from member or field name
+/// to its value, `()` for sets, along with the hash-ordered index its scan
+/// cursors walk.
+#[derive(Debug, Clone)]
+pub struct ScanMap<V> {
+    entries: HashMap<Bytes, V>,
+    index: BTreeSet<(u64, Bytes)>,
+}
+
+impl<V> ScanMap<V> {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            index: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Stores `value` under `name`, returning the value it replaced.
+    pub fn insert(&mut self, name: Bytes, value: V) -> Option<V> {
+        let replaced = self.entries.insert(name.clone(), value);
+        if replaced.is_none() {
+            self.index.insert((scan_hash(&name), name));
+        }
+        replaced
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, Bytes, V> {
+        self.entries.iter()
+    }
+
+    /// One call of `HSCAN`, `SSCAN` or `ZSCAN`: about `count` entries from
+    /// `cursor` on, in hash order, and the cursor to continue from, 0 once
+    /// the iteration is complete. Small collections are returned whole.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&Bytes, &V)>) {
+        if self.len() <= SMALL_COLLECTION_LEN {
+            return (0, self.iter().collect());
+        }
+        let mut entries = Vec::with_capacity(count);
+        let mut last = None;
+        for (hash, name) in self.index.range((cursor, Bytes::new())..) {
+            // Entries sharing a hash go in the same batch, since the cursor
+            // of the next call could not tell them apart.
+            if entries.len() >= count && last != Some(*hash) {
+                return (*hash, entries);
+            }
+            last = Some(*hash);
+            let (name, value) = self
+                .entries
+                .get_key_value(name)
+                .expect("indexed names are in the map");
+            entries.push((name, value));
+        }
+        (0, entries)
+    }
+}
+
+impl<V> Default for ScanMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> FromIterator<(Bytes, V)> for ScanMap<V> {
+    fn from_iter<I: IntoIterator<Item = (Bytes, V)>>(entries: I) -> Self {
+        let mut map = ScanMap::new();
+        for (name, value) in entries {
+            map.insert(name, value);
+        }
+        map
+    }
+}
+
+impl FromIterator<Bytes> for ScanMap<()> {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(members: I) -> Self {
+        members.into_iter().map(|member| (member, ())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn names(prefix: &str, range: std::ops::Range<usize>) -> Vec<Bytes> {
+        range
+            .map(|i| Bytes::from(format!("{}:{}", prefix, i)))
+            .collect()
+    }
+
+    /// Scans `map` to completion with `count`, calling `between` before
+    /// every call, and returns the names seen.
+    fn scan_all(
+        map: &mut ScanMap<()>,
+        count: usize,
+        mut between: impl FnMut(&mut ScanMap<()>, usize),
+    ) -> Vec<Bytes> {
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        for round in 0.. {
+            between(map, round);
+            let (next, page) = map.scan(cursor, count);
+            seen.extend(page.into_iter().map(|(name, ())| name.clone()));
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn large_collections_are_paged_in_hash_order() {
+        let mut map = names("member", 0..1000)
+            .into_iter()
+            .collect::<ScanMap<()>>();
+        let (cursor, page) = map.scan(0, 10);
+        assert_ne!(cursor, 0);
+        assert_eq!(page.len(), 10);
+        let hashes = page
+            .iter()
+            .map(|(name, ())| scan_hash(name))
+            .collect::<Vec<_>>();
+        assert!(hashes.is_sorted());
+        assert!(hashes.iter().all(|&hash| hash < cursor));
+
+        let seen = scan_all(&mut map, 10, |_, _| {});
+        assert_eq!(seen.len(), 1000);
+        assert_eq!(seen.iter().collect::<HashSet<_>>().len(), 1000);
+    }
+
+    #[test]
+    fn every_element_present_throughout_is_returned_once() {
+        let stable = names("stable", 0..300);
+        let mut map = stable.iter().cloned().collect::<ScanMap<()>>();
+        let seen = scan_all(&mut map, 10, |map, round| {
+            // Grow and shrink the collection between calls.
+            for name in names("added", round * 10..round * 10 + 10) {
+                map.insert(name, ());
+            }
+            if round % 2 == 1 {
+                *map = map
+                    .iter()
+                    .filter(|(name, ())| !name.starts_with(b"added:"))
+                    .map(|(name, ())| name.clone())
+                    .collect();
+            }
+        });
+        for name in &stable {
+            assert_eq!(seen.iter().filter(|seen| *seen == name).count(), 1);
+        }
+    }
+
+    #[test]
+    fn small_collections_are_returned_whole() {
+        let map = names("member", 0..SMALL_COLLECTION_LEN)
+            .into_iter()
+            .collect::<ScanMap<()>>();
+        let (cursor, page) = map.scan(0, 10);
+        assert_eq!(cursor, 0);
+        assert_eq!(page.len(), SMALL_COLLECTION_LEN);
+    }
+
+    #[test]
+    fn replacing_a_value_keeps_one_index_entry() {
+        let mut map = ScanMap::new();
+        assert_eq!(map.insert(Bytes::from("a"), 1), None);
+        assert_eq!(map.insert(Bytes::from("a"), 2), Some(1));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.index.len(), 1);
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{bail, Result};
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+use crate::{
+    aof::{rewrite_aof, Aof},
+    blocking::{BlockedClients, BlockedWait},
+    config::Config,
+    db::{Database, Databases},
+    expire::ActiveExpire,
+    lazyfree::LazyFree,
+    persistence::save_rdb,
+    persistence::{Persistence, REDIS_VERSION},
+};
+
+/// Sections reported by `INFO` without arguments, in order.
+const INFO_SECTIONS: [&str; 4] = ["server", "clients", "stats", "keyspace"];
+
+/// State of one client connection.
+#[derive(Debug, Default)]
+pub struct Client {
+    /// Index of the database selected with `SELECT`.
+    pub db: usize,
+    /// Set by a blocking command that found nothing to pop yet.
+    pub blocked: Option<BlockedWait>,
+}
+
+/// State shared by every connection and by the server's background tasks.
+pub struct Server<T> {
+    pub dbs: Mutex<Databases<T>>,
+    pub config: Mutex<Config>,
+    pub persistence: Mutex<Persistence>,
+    /// Held while a write command executes so commands are logged in the
+    /// order they were applied.
+    pub aof: Mutex<Aof>,
+    /// Serializes writers of the RDB file so a synchronous save can never be
+    /// overwritten by an older background snapshot finishing after it.
+    rdb_lock: Mutex<()>,
+    /// Taken before `dbs` while a cycle runs.
+    active_expire: Mutex<ActiveExpire>,
+    pub lazy_free: LazyFree,
+    /// Clients blocked on lists. Taken after `dbs`.
+    pub blocked: Mutex<BlockedClients>,
+    /// Set while the append-only file is replayed on startup.
+    loading: AtomicBool,
+    started: Instant,
+    shutdown: Notify,
+}
+
+impl<T: Database + Send + 'static> Server<T> {
+    pub fn new(dbs: Databases<T>, config: Config, aof: Aof) -> Self {
+        Self {
+            dbs: Mutex::new(dbs),
+            config: Mutex::new(config),
+            persistence: Mutex::new(Persistence::new()),
+            aof: Mutex::new(aof),
+            rdb_lock: Mutex::new(()),
+            active_expire: Mutex::new(ActiveExpire::new()),
+            lazy_free: LazyFree::new(),
+            blocked: Mutex::new(BlockedClients::new()),
+            loading: AtomicBool::new(false),
+            started: Instant::now(),
+            shutdown: Notify::new(),
+        }
+    }
+
+    /// Writes a snapshot while blocking every client, like `SAVE`.
+    pub fn save(&self) -> Result<()> {
+        if self.persistence.lock().unwrap().bgsave_in_progress() {
+            bail!("Background save already in progress");
+        }
+        self.write_snapshot()
+    }
+
+    /// Starts writing a snapshot on the blocking thread pool, like `BGSAVE`.
+    pub fn bgsave(self: &Arc<Self>) -> Result<()> {
+        {
+            let mut persistence = self.persistence.lock().unwrap();
+            if persistence.bgsave_in_progress() {
+                bail!("Background save already in progress");
+            }
+            persistence.start_bgsave();
+        }
+        let (snapshot, dirty) = {
+            let dbs = self.dbs.lock().unwrap();
+            (dbs.snapshot(), dbs.dirty())
+        };
+        let path = self.config.lock().unwrap().to_file_path();
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let result = {
+                let _rdb_lock = server.rdb_lock.lock().unwrap();
+                save_rdb(&snapshot, &path)
+            };
+            match &result {
+                Ok(()) => server.dbs.lock().unwrap().clear_dirty(&dirty),
+                Err(e) => eprintln!("Background saving failed: {}", e),
+            }
+            server
+                .persistence
+                .lock()
+                .unwrap()
+                .finish_bgsave(result.is_ok());
+        });
+        Ok(())
+    }
+
+    /// Starts a background save when one of the configured `save` rules is
+    /// satisfied. Meant to be called periodically.
+    pub fn save_if_needed(self: &Arc<Self>) {
+        let rules = self.config.lock().unwrap().save.clone();
+        let dirty = self.dbs.lock().unwrap().dirty().iter().sum();
+        if self.persistence.lock().unwrap().should_save(&rules, dirty) {
+            if let Err(e) = self.bgsave() {
+                eprintln!("Unable to start background save: {}", e);
+            }
+        }
+    }
+
+    /// Compacts the append-only file from the current keyspace on the
+    /// blocking thread pool, like `BGREWRITEAOF`.
+    pub fn bgrewriteaof(self: &Arc<Self>) -> Result<()> {
+        let (snapshot, temp_path) = {
+            let mut aof = self.aof.lock().unwrap();
+            if aof.rewrite_in_progress() {
+                bail!("Background append only file rewriting already in progress");
+            }
+            let snapshot = self.dbs.lock().unwrap().snapshot();
+            (snapshot, aof.start_rewrite())
+        };
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let result = rewrite_aof(&snapshot, &temp_path);
+            let mut aof = server.aof.lock().unwrap();
+            if let Err(e) = aof.finish_rewrite(&temp_path, result) {
+                eprintln!("Background AOF rewrite failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Flushes the append-only file to disk once a second under
+    /// `appendfsync everysec`, without holding the lock during the `fsync`.
+    pub fn fsync_aof_if_needed(&self) {
+        if let Some(file) = self.aof.lock().unwrap().fsync_due() {
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = file.sync_data() {
+                    eprintln!("Unable to fsync the append only file: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Deletes expired keys nobody reads. Meant to be called every `period`.
+    pub fn active_expire_cycle(&self, period: Duration) {
+        let mut active_expire = self.active_expire.lock().unwrap();
+        active_expire.run(&mut self.dbs.lock().unwrap(), period);
+    }
+
+    /// Renders the `INFO` report for `sections`, or the default sections
+    /// when none are given.
+    pub fn info(&self, sections: &[String]) -> String {
+        let all = sections.is_empty()
+            || sections
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+        let mut info = String::new();
+        for section in INFO_SECTIONS {
+            if !all && !sections.iter().any(|requested| requested == section) {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            match section {
+                "server" => {
+                    info.push_str("# Server\r\n");
+                    let _ = write!(info, "redis_version:{}\r\n", REDIS_VERSION);
+                    let _ = write!(info, "process_id:{}\r\n", std::process::id());
+                    let uptime = self.started.elapsed().as_secs();
+                    let _ = write!(info, "uptime_in_seconds:{}\r\n", uptime);
+                    let _ = write!(info, "uptime_in_days:{}\r\n", uptime / 86400);
+                }
+                "clients" => {
+                    info.push_str("# Clients\r\n");
+                    let blocked_clients = self.blocked.lock().unwrap().len();
+                    let _ = write!(info, "blocked_clients:{}\r\n", blocked_clients);
+                }
+                "stats" => {
+                    let active_expire = self.active_expire.lock().unwrap();
+                    let expired_keys: u64 = {
+                        let dbs = self.dbs.lock().unwrap();
+                        (0..dbs.len()).map(|index| dbs[index].expired_keys()).sum()
+                    };
+                    info.push_str("# Stats\r\n");
+                    let _ = write!(info, "expired_keys:{}\r\n", expired_keys);
+                    let _ = write!(
+                        info,
+                        "expired_stale_perc:{:.2}\r\n",
+                        active_expire.stale_perc() * 100.0
+                    );
+                    let _ = write!(
+                        info,
+                        "expired_time_cap_reached_count:{}\r\n",
+                        active_expire.time_cap_reached_count()
+                    );
+                    let _ = write!(
+                        info,
+                        "expire_cycle_cpu_milliseconds:{}\r\n",
+                        active_expire.cycle_millis()
+                    );
+                    let _ = write!(
+                        info,
+                        "lazyfreed_objects:{}\r\n",
+                        self.lazy_free.freed_objects()
+                    );
+                }
+                _ => {
+                    let active_expire = self.active_expire.lock().unwrap();
+                    let dbs = self.dbs.lock().unwrap();
+                    info.push_str("# Keyspace\r\n");
+                    for index in 0..dbs.len() {
+                        let db = &dbs[index];
+                        if db.len() == 0 {
+                            continue;
+                        }
+                        let avg_ttl = if db.expires_len() > 0 {
+                            active_expire.avg_ttl(index)
+                        } else {
+                            0
+                        };
+                        let _ = write!(
+                            info,
+                            "db{}:keys={},expires={},avg_ttl={}\r\n",
+                            index,
+                            db.len(),
+                            db.expires_len(),
+                            avg_ttl
+                        );
+                    }
+                }
+            }
+        }
+        info
+    }
+
+    /// Marks the append-only file as being replayed. Until `stop_loading`,
+    /// replayed commands only change the keyspace: they trigger no snapshot.
+    pub fn start_loading(&self) {
+        self.loading.store(true, Ordering::Relaxed);
+    }
+
+    /// Ends the replay. The writes it made are already on disk, so they do
+    /// not count toward the `save` rules.
+    pub fn stop_loading(&self) {
+        let mut dbs = self.dbs.lock().unwrap();
+        let dirty = dbs.dirty();
+        dbs.clear_dirty(&dirty);
+        self.loading.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed)
+    }
+
+    pub fn has_save_rules(&self) -> bool {
+        !self.config.lock().unwrap().save.is_empty()
+    }
+
+    /// Asks the accept loop to stop; used by `SHUTDOWN` once it has done its
+    /// final save.
+    pub fn request_shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await;
+    }
+
+    /// Writes a snapshot regardless of a running background save, waiting
+    /// for it to finish first. Used for the final save before exiting.
+    pub fn write_snapshot(&self) -> Result<()> {
+        let _rdb_lock = self.rdb_lock.lock().unwrap();
+        let (snapshot, dirty) = {
+            let dbs = self.dbs.lock().unwrap();
+            (dbs.snapshot(), dbs.dirty())
+        };
+        let path = self.config.lock().unwrap().to_file_path();
+        save_rdb(&snapshot, &path)?;
+        self.dbs.lock().unwrap().clear_dirty(&dirty);
+        self.persistence.lock().unwrap().finish_save(true);
+        Ok(())
+    }
+}
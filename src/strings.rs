@@ -0,0 +1,181 @@
+//! Algorithms behind the string commands that work on parts of a value:
+//! `GETRANGE`, `SETRANGE` and `LCS`.
+
+/// Largest string a command may create, Redis' default
+/// `proto-max-bulk-len` of 512MB.
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Whether writing `len` bytes at `offset` keeps a string within
+/// `MAX_STRING_LEN`, which `APPEND` and `SETRANGE` check before writing.
+pub fn fits_in_string(offset: usize, len: usize) -> bool {
+    offset
+        .checked_add(len)
+        .is_some_and(|end| end <= MAX_STRING_LEN)
+}
+
+/// The bytes between `start` and `end`, both inclusive, where negative
+/// offsets count from the end of the value, like `GETRANGE`.
+pub fn get_range(value: &[u8], start: i64, end: i64) -> &[u8] {
+    let len = value.len() as i64;
+    if start < 0 && end < 0 && start > end {
+        return &[];
+    }
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+    if start > end || len == 0 {
+        return &[];
+    }
+    &value[start as usize..=end as usize]
+}
+
+/// Overwrites `value` with `patch` at `offset`, padding with zero bytes when
+/// `offset` is past its end, like `SETRANGE`.
+pub fn set_range(value: &mut Vec<u8>, offset: usize, patch: &[u8]) {
+    let end = offset + patch.len();
+    if value.len() < end {
+        value.resize(end, 0);
+    }
+    value[offset..end].copy_from_slice(patch);
+}
+
+/// A run of consecutive bytes that is part of the longest common
+/// subsequence, as inclusive ranges of both strings.
+pub struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+
+impl LcsMatch {
+    pub fn len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+pub struct Lcs {
+    pub sequence: Vec<u8>,
+    /// The runs making up `sequence`, last one first like Redis reports
+    /// them.
+    pub matches: Vec<LcsMatch>,
+}
+
+/// Computes the longest common subsequence of `a` and `b` with the classic
+/// dynamic programming table. Returns `None` if that table would be larger
+/// than `MAX_STRING_LEN`, which Redis refuses as well.
+pub fn lcs(a: &[u8], b: &[u8]) -> Option<Lcs> {
+    let width = b.len() + 1;
+    let cells = (a.len() + 1).checked_mul(width)?;
+    if cells.checked_mul(size_of::<u32>())? > MAX_STRING_LEN {
+        return None;
+    }
+    let mut table = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    // Walk back from the end, turning consecutive matching positions into
+    // runs. Ties move along `b` first, as Redis does, so the reported
+    // ranges are the same.
+    let mut sequence = Vec::with_capacity(table[cells - 1] as usize);
+    let mut matches: Vec<LcsMatch> = Vec::new();
+    let (mut i, mut j) = (a.len(), b.len());
+    let mut extending = false;
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            sequence.push(a[i - 1]);
+            match matches.last_mut() {
+                Some(run) if extending => {
+                    run.a.0 -= 1;
+                    run.b.0 -= 1;
+                }
+                _ => matches.push(LcsMatch {
+                    a: (i - 1, i - 1),
+                    b: (j - 1, j - 1),
+                }),
+            }
+            extending = true;
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            extending = false;
+        }
+    }
+    sequence.reverse();
+    Some(Lcs { sequence, matches })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_range_offsets() {
+        let value = b"This is a string";
+        assert_eq!(get_range(value, 0, 3), b"This");
+        assert_eq!(get_range(value, -3, -1), b"ing");
+        assert_eq!(get_range(value, 0, -1), value);
+        assert_eq!(get_range(value, 10, 100), b"string");
+        assert_eq!(get_range(value, -100, 3), b"This");
+        assert_eq!(get_range(value, 5, 3), b"");
+        assert_eq!(get_range(value, -1, -5), b"");
+        assert_eq!(get_range(value, 100, 200), b"");
+        assert_eq!(get_range(b"", 0, -1), b"");
+    }
+
+    #[test]
+    fn set_range_overwrites_and_pads() {
+        let mut value = b"Hello World".to_vec();
+        set_range(&mut value, 6, b"Redis");
+        assert_eq!(value, b"Hello Redis");
+        set_range(&mut value, 10, b"!!");
+        assert_eq!(value, b"Hello Redi!!");
+
+        let mut value = Vec::new();
+        set_range(&mut value, 3, b"ab");
+        assert_eq!(value, b"\0\0\0ab");
+    }
+
+    #[test]
+    fn string_length_limit() {
+        assert!(fits_in_string(0, MAX_STRING_LEN));
+        assert!(fits_in_string(MAX_STRING_LEN - 1, 1));
+        assert!(!fits_in_string(MAX_STRING_LEN, 1));
+        assert!(!fits_in_string(usize::MAX, 1));
+        assert!(fits_in_string(MAX_STRING_LEN, 0));
+    }
+
+    #[test]
+    fn lcs_reports_runs_last_first() {
+        let result = lcs(b"ohmytext", b"mynewtext").unwrap();
+        assert_eq!(result.sequence, b"mytext");
+        let runs = result
+            .matches
+            .iter()
+            .map(|run| (run.a, run.b, run.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(runs, [((4, 7), (5, 8), 4), ((2, 3), (0, 1), 2)]);
+    }
+
+    #[test]
+    fn lcs_of_unrelated_or_empty_strings() {
+        let result = lcs(b"abc", b"xyz").unwrap();
+        assert!(result.sequence.is_empty() && result.matches.is_empty());
+        assert!(lcs(b"", b"abc").unwrap().sequence.is_empty());
+    }
+
+    #[test]
+    fn lcs_refuses_tables_over_the_limit() {
+        let long = vec![b'a'; 20_000];
+        assert!(lcs(&long, &long).is_none());
+    }
+}